@@ -1,13 +1,95 @@
+use crate::agents::dsl::{self, Diagnostic};
+use crate::agents::run;
+use crate::agents::sandbox::{self, SandboxError, SandboxLimits};
+use crate::agents::tools::{self, Tool, ToolCallRecord};
 use crate::icore::context::Context;
-use sentience::{
-    context::AgentContext, eval::eval, lexer::Lexer, parser::Parser, types::Statement,
-};
-use std::collections::HashMap;
+use crate::memory::value;
+use sentience::{context::AgentContext, types::Statement};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+const MAX_VERSIONS: usize = 20;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentVersion {
+    pub version: u32,
+    pub source: String,
+    pub loaded_at: i64,
+}
+
+/// Short- and long-term memory an agent holds for one namespace.
+#[derive(Debug, Clone, Default)]
+struct AgentMemory {
+    short: HashMap<String, String>,
+    long: HashMap<String, String>,
+    /// Keys written since the last flush to global memory.
+    dirty: HashSet<(MemScope, String)>,
+}
+
+impl AgentMemory {
+    fn scope(&self, scope: MemScope) -> &HashMap<String, String> {
+        match scope {
+            MemScope::Short => &self.short,
+            MemScope::Long => &self.long,
+        }
+    }
+
+    fn scope_mut(&mut self, scope: MemScope) -> &mut HashMap<String, String> {
+        match scope {
+            MemScope::Short => &mut self.short,
+            MemScope::Long => &mut self.long,
+        }
+    }
+
+    fn set(&mut self, scope: MemScope, key: String, value: String) {
+        if self.scope(scope).get(&key) != Some(&value) {
+            self.dirty.insert((scope, key.clone()));
+        }
+        self.scope_mut(scope).insert(key, value);
+    }
+
+    /// Replaces a scope, marking the keys whose value changed.
+    fn replace(&mut self, scope: MemScope, entries: HashMap<String, String>) {
+        for (key, value) in &entries {
+            if self.scope(scope).get(key) != Some(value) {
+                self.dirty.insert((scope, key.clone()));
+            }
+        }
+        *self.scope_mut(scope) = entries;
+    }
+
+    /// Takes the entries of `scope` written since the last call.
+    fn take_dirty(&mut self, scope: MemScope) -> Vec<(String, String)> {
+        let mut entries = Vec::new();
+        self.dirty.retain(|(s, key)| {
+            if *s != scope {
+                return true;
+            }
+            let map = match scope {
+                MemScope::Short => &self.short,
+                MemScope::Long => &self.long,
+            };
+            if let Some(value) = map.get(key) {
+                entries.push((key.clone(), value.clone()));
+            }
+            false
+        });
+        entries
+    }
+}
 
 pub struct BaseAgent {
     pub name: String,
     pub goal: String,
+    /// The compiled program; its memory maps are unused, see `memory`.
     ctx: AgentContext,
+    /// Memory per namespace, so tenants sharing an agent never see each
+    /// other's keys.
+    memory: HashMap<String, AgentMemory>,
+    versions: Vec<AgentVersion>,
+    pub limits: SandboxLimits,
+    pub tools: HashSet<Tool>,
+    tool_log: Vec<ToolCallRecord>,
 }
 
 impl BaseAgent {
@@ -16,72 +98,411 @@ impl BaseAgent {
             name,
             goal,
             ctx: AgentContext::new(),
+            memory: HashMap::new(),
+            versions: Vec::new(),
+            limits: SandboxLimits::default(),
+            tools: HashSet::new(),
+            tool_log: Vec::new(),
         }
     }
 
-    pub async fn load(&mut self, code: &str) -> Result<(), String> {
-        let mut lexer = Lexer::new(code.trim());
-        let mut parser = Parser::new(&mut lexer);
-        let program = parser.parse_program();
-        let mut output = Vec::new();
-        for stmt in program.statements {
-            eval(&stmt, "", "", &mut self.ctx, &mut output);
+    /// Lints, parses and evaluates `code` into a fresh context without
+    /// touching any loaded agent, so a broken program never replaces a
+    /// working one. Evaluation runs in the sandbox under `limits`. Errors
+    /// are rendered as `line:col: error: message`.
+    pub async fn compile(code: &str, limits: SandboxLimits) -> Result<AgentContext, String> {
+        let errors = dsl::lint(code)
+            .into_iter()
+            .filter(Diagnostic::is_error)
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            return Err(dsl::render(&errors));
+        }
+        Self::evaluate(code, limits)
+            .await
+            .map_err(|d| d.to_string())
+    }
+
+    /// All diagnostics for `code`: lint warnings and errors, plus parser
+    /// failures once the source is structurally sound.
+    pub async fn validate(code: &str, limits: SandboxLimits) -> Vec<Diagnostic> {
+        let mut diagnostics = dsl::lint(code);
+        if !diagnostics.iter().any(Diagnostic::is_error)
+            && let Err(e) = Self::evaluate(code, limits).await
+        {
+            diagnostics.push(e);
         }
+        diagnostics
+    }
 
-        dbg!(&self.ctx.current_agent);
+    async fn evaluate(code: &str, limits: SandboxLimits) -> Result<AgentContext, Diagnostic> {
+        let program = run::parse(code).await?;
+        let starts = dsl::statement_starts(code);
+        let first = starts.first().copied().unwrap_or((1, 1));
+        let ctx = match sandbox::run(AgentContext::new(), program.statements, None, limits).await {
+            Ok(run) => run.ctx,
+            // Without input every step is a top-level statement.
+            Err(SandboxError::Panic {
+                message,
+                step: Some(step),
+            }) => {
+                let (line, column) = starts.get(step - 1).copied().unwrap_or(first);
+                return Err(Diagnostic::error(
+                    line,
+                    column,
+                    format!("eval failed: {}", message),
+                ));
+            }
+            Err(e) => {
+                return Err(Diagnostic::error(
+                    first.0,
+                    first.1,
+                    format!("eval failed: {}", e),
+                ));
+            }
+        };
+
+        match ctx.current_agent {
+            Some(Statement::AgentDeclaration { .. }) => Ok(ctx),
+            _ => Err(Diagnostic::error(
+                first.0,
+                first.1,
+                "source did not evaluate to an agent declaration",
+            )),
+        }
+    }
 
+    pub async fn load(&mut self, code: &str) -> Result<(), String> {
+        let ctx = Self::compile(code, self.limits).await?;
+        self.install(ctx, code);
+        tracing::debug!("agent '{}' loaded version {}", self.name, self.version());
         Ok(())
     }
 
-    pub async fn handle_core(&mut self, input: &str) -> Option<String> {
-        self.ctx.set_mem("short", "msg", input);
+    /// Swaps in a compiled context. The agent's memory in every namespace
+    /// carries over to the new version.
+    pub fn install(&mut self, mut ctx: AgentContext, code: &str) {
+        ctx.mem_short.clear();
+        ctx.mem_long.clear();
+        self.ctx = ctx;
 
-        if let Some(Statement::AgentDeclaration { body, .. }) = self.ctx.current_agent.clone() {
-            let mut output = Vec::new();
-            for stmt in body {
-                if let Statement::OnInput { body, .. } = stmt {
-                    for inner in body {
-                        eval(&inner, "", input, &mut self.ctx, &mut output);
-                    }
-                    return self.ctx.output.clone();
-                }
-            }
+        let version = self.version() + 1;
+        self.versions.push(AgentVersion {
+            version,
+            source: code.to_string(),
+            loaded_at: chrono::Utc::now().timestamp_millis(),
+        });
+        if self.versions.len() > MAX_VERSIONS {
+            self.versions.remove(0);
         }
-        None
     }
 
-    pub async fn handle(&mut self, input: &str, ctx: &mut Context) -> Option<String> {
-        let output = self.handle_core(input).await;
+    pub fn version(&self) -> u32 {
+        self.versions.last().map(|v| v.version).unwrap_or(0)
+    }
+
+    pub fn versions(&self) -> &[AgentVersion] {
+        &self.versions
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.versions.last().map(|v| v.source.as_str())
+    }
+
+    /// A copy of the agent's program and its memory in `namespace` to run
+    /// code against without touching the live context.
+    pub fn scratch_context(&self, namespace: &str) -> AgentContext {
+        let mut scratch = sandbox::scratch_copy(&self.ctx);
+        if let Some(memory) = self.memory.get(namespace) {
+            scratch.mem_short = memory.short.clone();
+            scratch.mem_long = memory.long.clone();
+        }
+        scratch
+    }
+
+    /// Adopts the memory of a scratch context as the agent's memory in
+    /// `namespace` after a committed run.
+    pub fn adopt_memory(&mut self, namespace: &str, scratch: AgentContext) {
+        let memory = self.memory.entry(namespace.to_string()).or_default();
+        memory.replace(MemScope::Short, scratch.mem_short);
+        memory.replace(MemScope::Long, scratch.mem_long);
+    }
+
+    /// Runs the agent's `on input` block in the sandbox against its memory
+    /// in `namespace`. Memory written by the block is kept only if the run
+    /// stays within the agent's limits.
+    pub async fn handle_core(
+        &mut self,
+        input: &str,
+        namespace: &str,
+    ) -> Result<Option<String>, SandboxError> {
+        if self.ctx.current_agent.is_none() {
+            self.set_mem(
+                namespace,
+                MemScope::Short,
+                "msg".to_string(),
+                input.to_string(),
+            );
+            return Ok(None);
+        }
+
+        let run = sandbox::run(
+            self.scratch_context(namespace),
+            Vec::new(),
+            Some(input.to_string()),
+            self.limits,
+        )
+        .await
+        .inspect_err(|e| tracing::warn!("agent '{}' aborted: {}", self.name, e))?;
+        let output = run.ctx.output.clone();
+        self.adopt_memory(namespace, run.ctx);
+        Ok(output)
+    }
+
+    /// Runs `on input`, then serves any tool calls it left in short-term
+    /// memory and runs it again so it can use the results, for up to
+    /// [`tools::MAX_TOOL_ROUNDS`] rounds. A call is served once per input:
+    /// when a re-run repeats it with the same argument, the earlier result
+    /// stands, and a round with no new calls ends the loop.
+    pub async fn handle(
+        &mut self,
+        input: &str,
+        ctx: &mut Context,
+    ) -> Result<Option<String>, SandboxError> {
+        let namespace = ctx.namespace.clone();
+        let mut output = self.handle_core(input, &namespace).await?;
+        let mut served = HashSet::new();
+        for round in 0..=tools::MAX_TOOL_ROUNDS {
+            let memory = self.memory.entry(namespace.clone()).or_default();
+            let calls = tools::take_calls(&mut memory.short)
+                .into_iter()
+                .filter(|call| !served.contains(&(call.result_key(), call.arg.clone())))
+                .collect::<Vec<_>>();
+            if calls.is_empty() {
+                break;
+            }
+            if round == tools::MAX_TOOL_ROUNDS {
+                tracing::warn!(
+                    "agent '{}' still calling tools after {} rounds; dropped {} calls",
+                    self.name,
+                    round,
+                    calls.len()
+                );
+                break;
+            }
+            for call in calls {
+                let record = tools::invoke(&self.name, &self.tools, &call, ctx).await;
+                self.set_mem(
+                    &namespace,
+                    MemScope::Short,
+                    call.result_key(),
+                    record.result.clone(),
+                );
+                tools::log_call(&mut self.tool_log, record);
+                served.insert((call.result_key(), call.arg));
+            }
+            output = self.handle_core(input, &namespace).await?;
+        }
         self.flush_to_global_short(ctx);
         self.flush_to_global_long(ctx).await;
-        output
+        Ok(output)
     }
 
-    pub fn flush_to_global_short(&self, ctx: &mut Context) {
-        for (k, v) in self.ctx.mem_short.iter() {
-            ctx.mem_short.set(k.clone(), v.clone());
+    pub fn allowed_tools(&self) -> Vec<Tool> {
+        let mut tools = self.tools.iter().copied().collect::<Vec<_>>();
+        tools.sort_by_key(|t| t.name());
+        tools
+    }
+
+    /// Tool calls made in `namespace`, oldest first.
+    pub fn tool_log(&self, namespace: &str) -> Vec<ToolCallRecord> {
+        self.tool_log
+            .iter()
+            .filter(|r| r.namespace == namespace)
+            .cloned()
+            .collect()
+    }
+
+    /// Writes the short-term keys changed since the last flush.
+    ///
+    /// Agent memory holds strings; JSON objects and arrays in it are stored
+    /// globally as structured values.
+    pub fn flush_to_global_short(&mut self, ctx: &mut Context) {
+        let Some(memory) = self.memory.get_mut(&ctx.namespace) else {
+            return;
+        };
+        for (k, v) in memory.take_dirty(MemScope::Short) {
+            ctx.mem_short.set_value(k, value::parse_structured(&v));
+        }
+    }
+
+    /// Writes the long-term keys changed since the last flush, queueing
+    /// their vectors with them. If the write fails the keys stay marked
+    /// for the next flush.
+    pub async fn flush_to_global_long(&mut self, ctx: &Context) {
+        let Some(memory) = self.memory.get_mut(&ctx.namespace) else {
+            return;
+        };
+        let changed = memory.take_dirty(MemScope::Long);
+        if changed.is_empty() {
+            return;
+        }
+        let entries = changed
+            .iter()
+            .map(|(k, v)| (k.clone(), value::parse_structured(v)))
+            .collect::<Vec<_>>();
+        if let Err(e) = ctx.write_long(&entries).await {
+            tracing::warn!("agent '{}' long-term flush failed: {}", self.name, e);
+            memory
+                .dirty
+                .extend(changed.into_iter().map(|(k, _)| (MemScope::Long, k)));
         }
     }
 
-    pub async fn flush_to_global_long(&self, ctx: &Context) {
-        for (k, v) in self.ctx.mem_long.iter() {
-            ctx.mem_long.set(k, v).await;
+    fn mem(&self, namespace: &str, scope: MemScope) -> Option<&HashMap<String, String>> {
+        self.memory.get(namespace).map(|m| m.scope(scope))
+    }
+
+    pub fn get_mem(&self, namespace: &str, scope: MemScope, key: &str) -> Option<String> {
+        self.mem(namespace, scope)?.get(key).cloned()
+    }
+
+    pub fn all_mem(&self, namespace: &str, scope: MemScope) -> HashMap<String, String> {
+        self.mem(namespace, scope).cloned().unwrap_or_default()
+    }
+
+    pub fn set_mem(&mut self, namespace: &str, scope: MemScope, key: String, value: String) {
+        self.memory
+            .entry(namespace.to_string())
+            .or_default()
+            .set(scope, key, value);
+    }
+
+    /// Returns whether the key existed.
+    pub fn delete_mem(&mut self, namespace: &str, scope: MemScope, key: &str) -> bool {
+        let Some(memory) = self.memory.get_mut(namespace) else {
+            return false;
+        };
+        memory.dirty.remove(&(scope, key.to_string()));
+        memory.scope_mut(scope).remove(key).is_some()
+    }
+
+    pub fn clear_mem(&mut self, namespace: &str, scope: MemScope) {
+        if let Some(memory) = self.memory.get_mut(namespace) {
+            memory.scope_mut(scope).clear();
         }
     }
 
-    pub fn get_short(&self, key: &str) -> Option<String> {
-        Some(self.ctx.get_mem("short", key))
+    /// Drops everything the agent holds for `namespace`: its memory there
+    /// and the tool calls made from it.
+    pub fn forget_namespace(&mut self, namespace: &str) {
+        self.memory.remove(namespace);
+        self.tool_log.retain(|r| r.namespace != namespace);
     }
 
-    pub fn get_long(&self, key: &str) -> Option<String> {
-        Some(self.ctx.get_mem("long", key))
+    /// Copies the namespace's global short- and long-term memory into the
+    /// agent's memory for that namespace, the reverse of
+    /// `flush_to_global_*`. Keys the agent already holds are overwritten
+    /// and, matching global memory again, are not flushed back. Returns the
+    /// number of short and long entries copied.
+    pub async fn seed_from_global(&mut self, ctx: &Context) -> (usize, usize) {
+        let short = ctx.mem_short.all().unwrap_or_default();
+        let long = ctx.mem_long.all().await;
+        let counts = (short.len(), long.len());
+        let memory = self.memory.entry(ctx.namespace.clone()).or_default();
+        for (key, value) in short {
+            memory.dirty.remove(&(MemScope::Short, key.clone()));
+            memory.short.insert(key, value);
+        }
+        for (key, value) in long {
+            memory.dirty.remove(&(MemScope::Long, key.clone()));
+            memory.long.insert(key, value);
+        }
+        counts
     }
+}
 
-    pub fn all_short(&self) -> Option<HashMap<String, String>> {
-        Some(self.ctx.mem_short.clone())
+/// One of an agent's two memory maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemScope {
+    Short,
+    Long,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_is_kept_per_namespace() {
+        let mut agent = BaseAgent::new("Tenant".to_string(), String::new());
+        agent.set_mem("a", MemScope::Long, "secret".into(), "42".into());
+        let mut scratch = agent.scratch_context("a");
+        scratch.mem_short.insert("seen".into(), "yes".into());
+        agent.adopt_memory("a", scratch);
+
+        assert_eq!(
+            agent.get_mem("a", MemScope::Long, "secret").as_deref(),
+            Some("42")
+        );
+        assert_eq!(agent.get_mem("b", MemScope::Long, "secret"), None);
+        assert!(agent.all_mem("b", MemScope::Short).is_empty());
+        let other = agent.scratch_context("b");
+        assert!(other.mem_short.is_empty() && other.mem_long.is_empty());
+
+        agent.install(AgentContext::new(), "agent Tenant {}");
+        agent.clear_mem("b", MemScope::Long);
+        assert_eq!(
+            agent.get_mem("a", MemScope::Short, "seen").as_deref(),
+            Some("yes")
+        );
     }
 
-    pub fn all_long(&self) -> Option<HashMap<String, String>> {
-        Some(self.ctx.mem_long.clone())
+    #[test]
+    fn forgetting_a_namespace_keeps_the_others() {
+        let mut agent = BaseAgent::new("Tenant".to_string(), String::new());
+        for namespace in ["a", "b"] {
+            agent.set_mem(namespace, MemScope::Long, "k".into(), "v".into());
+            agent.tool_log.push(ToolCallRecord {
+                namespace: namespace.to_string(),
+                tool: "recall".to_string(),
+                arg: String::new(),
+                ok: true,
+                result: String::new(),
+                elapsed_ms: 0,
+                timestamp: 0,
+            });
+        }
+
+        agent.forget_namespace("a");
+        assert_eq!(agent.get_mem("a", MemScope::Long, "k"), None);
+        assert!(agent.tool_log("a").is_empty());
+        assert_eq!(
+            agent.get_mem("b", MemScope::Long, "k").as_deref(),
+            Some("v")
+        );
+        assert_eq!(agent.tool_log("b").len(), 1);
+    }
+
+    #[test]
+    fn only_changed_keys_are_flushed() {
+        let mut memory = AgentMemory::default();
+        memory.set(MemScope::Long, "name".into(), "Ada".into());
+        memory.set(MemScope::Long, "city".into(), "Paris".into());
+        assert_eq!(memory.take_dirty(MemScope::Long).len(), 2);
+        assert!(memory.take_dirty(MemScope::Long).is_empty());
+
+        // A run that rewrites the same values changes nothing.
+        let mut long = memory.long.clone();
+        memory.replace(MemScope::Long, long.clone());
+        long.insert("city".into(), "Rome".into());
+        memory.replace(MemScope::Long, long);
+        memory.set(MemScope::Short, "msg".into(), "hi".into());
+        assert_eq!(
+            memory.take_dirty(MemScope::Long),
+            vec![("city".to_string(), "Rome".to_string())]
+        );
+        assert_eq!(memory.take_dirty(MemScope::Short).len(), 1);
     }
 }
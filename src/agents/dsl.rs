@@ -0,0 +1,439 @@
+use serde::Serialize;
+use std::fmt;
+
+/// Declarations read from the head of a Sentience source file, without
+/// evaluating it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AgentHeader {
+    pub name: Option<String>,
+    pub goal: Option<String>,
+}
+
+impl AgentHeader {
+    pub fn parse(code: &str) -> Self {
+        let mut header = AgentHeader::default();
+        for line in code.lines().map(|l| strip_comment(l).trim()) {
+            if header.name.is_none()
+                && let Some(rest) = line.strip_prefix("agent ")
+            {
+                let name = rest.trim_end_matches('{').trim();
+                if !name.is_empty() {
+                    header.name = Some(name.to_string());
+                }
+                continue;
+            }
+            if header.goal.is_none()
+                && let Some(rest) = line.strip_prefix("goal:")
+            {
+                header.goal = Some(rest.trim().trim_matches('"').to_string());
+            }
+        }
+        header
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find("//") {
+        Some(pos) if !line[..pos].contains('"') => &line[..pos],
+        _ => line,
+    }
+}
+
+/// Memory scopes backed by the runtime. Writes to any other scope are
+/// accepted by `eval` but never reach short- or long-term memory.
+pub const MEMORY_SCOPES: &[&str] = &["short", "long"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in Sentience source. Lines and columns are 1-based.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(line: usize, column: usize, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(line: usize, column: usize, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.line, self.column, severity, self.message
+        )
+    }
+}
+
+/// Renders diagnostics one per line, as `line:col: severity: message`.
+pub fn render(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An opening bracket with its line and column.
+type Bracket = (char, usize, usize);
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize,
+}
+
+impl Token<'_> {
+    fn is_ident(&self) -> bool {
+        self.text
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+    }
+}
+
+/// Checks source text for problems the Sentience parser does not report:
+/// unbalanced brackets, unterminated strings, a missing agent declaration or
+/// `on input` block, and unknown memory scopes.
+pub fn lint(code: &str) -> Vec<Diagnostic> {
+    let (tokens, mut diagnostics, _) = tokenize(code);
+    check_memory_scopes(&tokens, &mut diagnostics);
+
+    let declarations = tokens
+        .windows(3)
+        .filter(|w| w[0].text == "agent" && w[1].is_ident() && w[2].text == "{")
+        .map(|w| &w[0])
+        .collect::<Vec<_>>();
+    match declarations.as_slice() {
+        [] => diagnostics.push(Diagnostic::error(
+            1,
+            1,
+            "missing `agent <Name> { ... }` declaration",
+        )),
+        [first, rest @ ..] => {
+            for extra in rest {
+                diagnostics.push(Diagnostic::warning(
+                    extra.line,
+                    extra.column,
+                    "only the first agent declaration in a file is loaded",
+                ));
+            }
+            if !tokens
+                .windows(2)
+                .any(|w| w[0].text == "on" && w[1].text == "input")
+            {
+                diagnostics.push(Diagnostic::warning(
+                    first.line,
+                    first.column,
+                    "agent has no `on input(...)` block and will never answer from the DSL",
+                ));
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+/// Like [`lint`], but for a program that is run directly rather than
+/// loaded as an agent, so no declaration is required.
+pub fn lint_snippet(code: &str) -> Vec<Diagnostic> {
+    let (tokens, mut diagnostics, _) = tokenize(code);
+    check_memory_scopes(&tokens, &mut diagnostics);
+    diagnostics.sort_by_key(|d| (d.line, d.column));
+    diagnostics
+}
+
+/// Line and column of each top-level statement of `code`, in order. A
+/// statement starts at the first token of a line outside any bracket.
+pub fn statement_starts(code: &str) -> Vec<(usize, usize)> {
+    let (tokens, _, _) = tokenize(code);
+    let mut starts = Vec::new();
+    let mut depth = 0usize;
+    let mut line = 0;
+    for token in &tokens {
+        if token.line != line {
+            line = token.line;
+            if depth == 0 && !matches!(token.text, "}" | ")" | "]") {
+                starts.push((token.line, token.column));
+            }
+        }
+        match token.text {
+            "{" | "(" | "[" => depth += 1,
+            "}" | ")" | "]" => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    starts
+}
+
+/// Finds the line a parser rejects, for parsers that only say that the
+/// source failed. Returns the position of the first line whose prefix of
+/// `code`, with its open brackets closed, does not pass `parses`. A prefix
+/// past a failing line fails too, so the prefixes are bisected and
+/// `parses` runs a logarithmic number of times.
+pub fn locate_failure(code: &str, parses: impl Fn(&str) -> bool) -> Option<(usize, usize)> {
+    let lines = code.lines().collect::<Vec<_>>();
+    let ends = (1..=lines.len())
+        .filter(|&n| !lines[n - 1].trim().is_empty())
+        .collect::<Vec<_>>();
+    let passes = |n: usize| {
+        let mut prefix = lines[..n].join("\n");
+        let (_, _, open) = tokenize(&prefix);
+        for (opener, _, _) in open.into_iter().rev() {
+            prefix.push(closer(opener));
+        }
+        parses(&prefix)
+    };
+    let n = *ends.get(ends.partition_point(|&n| passes(n)))?;
+    let column = lines[n - 1]
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .count()
+        + 1;
+    Some((n, column))
+}
+
+fn check_memory_scopes(tokens: &[Token<'_>], diagnostics: &mut Vec<Diagnostic>) {
+    for (i, token) in tokens.iter().enumerate() {
+        if token.text != "mem" {
+            continue;
+        }
+        let scope = match tokens.get(i + 1) {
+            Some(next) if next.text == "." => tokens.get(i + 2),
+            Some(next) if next.is_ident() && next.line == token.line => Some(next),
+            _ => None,
+        };
+        if let Some(scope) = scope.filter(|s| s.is_ident())
+            && !MEMORY_SCOPES.contains(&scope.text)
+        {
+            diagnostics.push(Diagnostic::error(
+                scope.line,
+                scope.column,
+                format!(
+                    "unknown memory scope `{}` (expected one of: {})",
+                    scope.text,
+                    MEMORY_SCOPES.join(", ")
+                ),
+            ));
+        }
+    }
+}
+
+/// Splits `code` into tokens. Also returns the problems found on the way
+/// and the brackets still open at the end, innermost last.
+fn tokenize(code: &str) -> (Vec<Token<'_>>, Vec<Diagnostic>, Vec<Bracket>) {
+    let mut tokens = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut open: Vec<Bracket> = Vec::new();
+
+    for (line_no, line) in code.lines().enumerate() {
+        let line_no = line_no + 1;
+        let mut chars = line.char_indices().enumerate().peekable();
+        while let Some((col, (start, c))) = chars.next() {
+            let column = col + 1;
+            match c {
+                '/' if line[start..].starts_with("//") => break,
+                '"' => {
+                    let mut end = None;
+                    let mut escaped = false;
+                    for (_, (i, c)) in chars.by_ref() {
+                        match c {
+                            '\\' if !escaped => escaped = true,
+                            '"' if !escaped => {
+                                end = Some(i + 1);
+                                break;
+                            }
+                            _ => escaped = false,
+                        }
+                    }
+                    match end {
+                        Some(end) => tokens.push(Token {
+                            text: &line[start..end],
+                            line: line_no,
+                            column,
+                        }),
+                        None => diagnostics.push(Diagnostic::error(
+                            line_no,
+                            column,
+                            "unterminated string literal",
+                        )),
+                    }
+                }
+                c if c.is_alphanumeric() || c == '_' => {
+                    let mut end = start + c.len_utf8();
+                    while let Some((_, (i, c))) = chars.peek() {
+                        if !(c.is_alphanumeric() || *c == '_') {
+                            break;
+                        }
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    tokens.push(Token {
+                        text: &line[start..end],
+                        line: line_no,
+                        column,
+                    });
+                }
+                c if c.is_whitespace() => {}
+                c => {
+                    match c {
+                        '{' | '(' | '[' => open.push((c, line_no, column)),
+                        '}' | ')' | ']' => match open.pop() {
+                            Some((opener, _, _)) if closer(opener) == c => {}
+                            Some((opener, l, col)) => diagnostics.push(Diagnostic::error(
+                                line_no,
+                                column,
+                                format!(
+                                    "expected `{}` to close `{}` from {}:{}, found `{}`",
+                                    closer(opener),
+                                    opener,
+                                    l,
+                                    col,
+                                    c
+                                ),
+                            )),
+                            None => diagnostics.push(Diagnostic::error(
+                                line_no,
+                                column,
+                                format!("unexpected `{}`", c),
+                            )),
+                        },
+                        _ => {}
+                    }
+                    tokens.push(Token {
+                        text: &line[start..start + c.len_utf8()],
+                        line: line_no,
+                        column,
+                    });
+                }
+            }
+        }
+    }
+
+    for (opener, line, column) in &open {
+        diagnostics.push(Diagnostic::error(
+            *line,
+            *column,
+            format!("unclosed `{}`", opener),
+        ));
+    }
+    (tokens, diagnostics, open)
+}
+
+fn closer(opener: char) -> char {
+    match opener {
+        '{' => '}',
+        '(' => ')',
+        _ => ']',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECHO: &str = r#"agent Echo {
+  mem short
+  goal: "Chat with user"
+
+  on input(msg) {
+    if context includes ["hello", "hi"] {
+      output = "Hello!"
+    }
+  }
+}"#;
+
+    #[test]
+    fn clean_agent_has_no_diagnostics() {
+        assert!(lint(ECHO).is_empty());
+    }
+
+    #[test]
+    fn reports_positions_of_structural_errors() {
+        let code = "agent Broken {\n  mem shrt\n  on input(msg) {\n    output = \"oops\n  }\n";
+        let diagnostics = lint(code);
+        let rendered = render(&diagnostics);
+        assert!(diagnostics.iter().all(Diagnostic::is_error), "{}", rendered);
+        assert_eq!(
+            rendered,
+            "1:14: error: unclosed `{`\n\
+             2:7: error: unknown memory scope `shrt` (expected one of: short, long)\n\
+             4:14: error: unterminated string literal"
+        );
+    }
+
+    #[test]
+    fn missing_declaration_and_on_input() {
+        let diagnostics = lint("on input(msg) { mem.latent[\"k\"] = msg }");
+        assert_eq!(
+            diagnostics[0].message,
+            "missing `agent <Name> { ... }` declaration"
+        );
+        assert_eq!((diagnostics[1].line, diagnostics[1].column), (1, 21));
+
+        let diagnostics = lint("agent Quiet {\n  goal: \"nothing\"\n}");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn positions_of_statements_and_parse_failures() {
+        let code =
+            "mem.short[\"a\"] = \"1\"\n\nagent Echo {\n  on input(msg) {\n    output = ?\n  }\n}";
+        assert_eq!(statement_starts(code), vec![(1, 1), (3, 1)]);
+
+        // Every prefix handed to the parser has its brackets closed.
+        let parses = |src: &str| {
+            let (_, _, open) = tokenize(src);
+            assert!(open.is_empty(), "{}", src);
+            !src.contains('?')
+        };
+        assert_eq!(locate_failure(code, parses), Some((5, 5)));
+        assert_eq!(locate_failure(ECHO, parses), None);
+
+        let long = format!(
+            "{}  output = ?\n",
+            "  mem.short[\"k\"] = \"v\"\n".repeat(4000)
+        );
+        let runs = std::cell::Cell::new(0);
+        let located = locate_failure(&long, |src| {
+            runs.set(runs.get() + 1);
+            !src.contains('?')
+        });
+        assert_eq!(located, Some((4001, 3)));
+        assert!(runs.get() <= 13, "{} parser runs", runs.get());
+    }
+}
@@ -1,8 +1,17 @@
-pub use agent::BaseAgent;
+pub use agent::{BaseAgent, MemScope};
 use once_cell::sync::OnceCell;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+pub use registry::{AgentDefaults, AgentRef, AgentRegistry};
 
 pub mod agent;
+pub mod dsl;
+pub mod react;
+pub mod registry;
+pub mod run;
+pub mod sandbox;
+pub mod tools;
 
-pub static AGENT: OnceCell<Arc<Mutex<BaseAgent>>> = OnceCell::new();
+pub static AGENTS: OnceCell<AgentRegistry> = OnceCell::new();
+
+pub fn registry() -> &'static AgentRegistry {
+    AGENTS.get().expect("agent registry not initialized")
+}
@@ -0,0 +1,386 @@
+use crate::icore::context::Context;
+use crate::icore::llm;
+use crate::memory::semantic::reflect::reflect;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+const MAX_TRACES: usize = 50;
+const MAX_OBSERVATION_CHARS: usize = 1_000;
+const STEP_TOKENS: u16 = 256;
+const RESULTS_K: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReactTool {
+    MemoryGet,
+    MemorySet,
+    Recall,
+    Reflect,
+    LongSearch,
+}
+
+impl ReactTool {
+    pub const ALL: [ReactTool; 5] = [
+        ReactTool::MemoryGet,
+        ReactTool::MemorySet,
+        ReactTool::Recall,
+        ReactTool::Reflect,
+        ReactTool::LongSearch,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ReactTool::MemoryGet => "memory_get",
+            ReactTool::MemorySet => "memory_set",
+            ReactTool::Recall => "recall",
+            ReactTool::Reflect => "reflect",
+            ReactTool::LongSearch => "long_search",
+        }
+    }
+
+    fn usage(self) -> &'static str {
+        match self {
+            ReactTool::MemoryGet => "memory_get[key]: read a value from short-term memory",
+            ReactTool::MemorySet => "memory_set[key=value]: store a value in short-term memory",
+            ReactTool::Recall => "recall[text]: ids of related items in latent memory",
+            ReactTool::Reflect => "reflect[cluster_id]: clusters similar to a semantic cluster",
+            ReactTool::LongSearch => "long_search[text]: long-term memory entries containing text",
+        }
+    }
+}
+
+/// Definition of a ReAct agent, read from a `*.react.json` file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReactConfig {
+    pub name: String,
+    #[serde(default)]
+    pub goal: String,
+    #[serde(default = "default_max_steps")]
+    pub max_steps: usize,
+    /// Tools the model may use; all of them if omitted.
+    pub tools: Option<Vec<ReactTool>>,
+}
+
+fn default_max_steps() -> usize {
+    6
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Step {
+    pub thought: String,
+    pub action: Option<String>,
+    pub input: Option<String>,
+    pub observation: Option<String>,
+    pub elapsed_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Trace {
+    pub namespace: String,
+    pub input: String,
+    pub steps: Vec<Step>,
+    pub answer: String,
+    /// False when the step limit was hit and the answer was forced.
+    pub finished: bool,
+    pub timestamp: i64,
+}
+
+/// An agent that answers by letting the local LLM pick tools in a
+/// Thought/Action/Observation loop until it gives a final answer.
+pub struct ReactAgent {
+    pub name: String,
+    pub goal: String,
+    pub max_steps: usize,
+    pub tools: Vec<ReactTool>,
+    traces: VecDeque<Trace>,
+}
+
+/// What the model asked for in one completion.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Act {
+        thought: String,
+        action: String,
+        input: String,
+    },
+    Answer {
+        thought: String,
+        answer: String,
+    },
+}
+
+impl ReactAgent {
+    pub fn new(config: ReactConfig) -> Self {
+        Self {
+            name: config.name,
+            goal: config.goal,
+            max_steps: config.max_steps.max(1),
+            tools: config.tools.unwrap_or_else(|| ReactTool::ALL.to_vec()),
+            traces: VecDeque::new(),
+        }
+    }
+
+    pub async fn handle(&mut self, input: &str, ctx: &Context) -> Result<String, String> {
+        let mut trace = Trace {
+            namespace: ctx.namespace.clone(),
+            input: input.to_string(),
+            steps: Vec::new(),
+            answer: String::new(),
+            finished: false,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+        };
+        let mut transcript = String::new();
+
+        for index in 1..=self.max_steps {
+            let started = Instant::now();
+            let prompt = self.prompt(input, &transcript);
+            let completion = llm::generate_local_with(&prompt, STEP_TOKENS, &["Observation:"])
+                .await
+                .map_err(|e| e.to_string())?;
+
+            match parse_reply(&completion) {
+                Reply::Answer { thought, answer } => {
+                    trace.steps.push(Step {
+                        thought,
+                        action: None,
+                        input: None,
+                        observation: None,
+                        elapsed_ms: started.elapsed().as_millis(),
+                    });
+                    trace.answer = answer;
+                    trace.finished = true;
+                    break;
+                }
+                Reply::Act {
+                    thought,
+                    action,
+                    input: arg,
+                } => {
+                    let observation = truncate(self.act(&action, &arg, ctx).await);
+                    tracing::info!(
+                        "react '{}' step {}: {}[{}] -> {}",
+                        self.name,
+                        index,
+                        action,
+                        arg,
+                        observation
+                    );
+                    transcript.push_str(&format!(
+                        "Thought: {}\nAction: {}[{}]\nObservation: {}\n",
+                        thought, action, arg, observation
+                    ));
+                    trace.steps.push(Step {
+                        thought,
+                        action: Some(action),
+                        input: Some(arg),
+                        observation: Some(observation),
+                        elapsed_ms: started.elapsed().as_millis(),
+                    });
+                }
+            }
+        }
+
+        if !trace.finished {
+            tracing::warn!(
+                "react '{}' hit the {}-step limit; forcing an answer",
+                self.name,
+                self.max_steps
+            );
+            let prompt = format!(
+                "{}Thought: I have to answer now.\nFinal Answer:",
+                self.prompt(input, &transcript)
+            );
+            trace.answer = llm::generate_local_with(&prompt, STEP_TOKENS, &["\n\n"])
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        let answer = trace.answer.clone();
+        self.traces.push_back(trace);
+        if self.traces.len() > MAX_TRACES {
+            self.traces.pop_front();
+        }
+        Ok(answer)
+    }
+
+    /// Traces of messages sent in `namespace`, newest first.
+    pub fn traces<'a>(&'a self, namespace: &'a str) -> impl Iterator<Item = &'a Trace> {
+        self.traces
+            .iter()
+            .rev()
+            .filter(move |t| t.namespace == namespace)
+    }
+
+    /// Drops the traces of messages sent in `namespace`.
+    pub fn forget_namespace(&mut self, namespace: &str) {
+        self.traces.retain(|t| t.namespace != namespace);
+    }
+
+    fn prompt(&self, input: &str, transcript: &str) -> String {
+        let tools = self
+            .tools
+            .iter()
+            .map(|t| format!("- {}", t.usage()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "You are {}. {}\n\
+             You can use these tools:\n{}\n\n\
+             Use this format:\n\
+             Thought: what to do next\n\
+             Action: tool_name[input]\n\
+             Then wait for the Observation. When you know the answer, write:\n\
+             Thought: I know the answer\n\
+             Final Answer: the answer to the user\n\n\
+             Question: {}\n{}",
+            self.name, self.goal, tools, input, transcript
+        )
+    }
+
+    async fn act(&self, action: &str, arg: &str, ctx: &Context) -> String {
+        let Some(tool) = self.tools.iter().copied().find(|t| t.name() == action) else {
+            return format!("unknown tool '{}'", action);
+        };
+        match run_tool(tool, arg, ctx).await {
+            Ok(observation) => observation,
+            Err(e) => format!("error: {}", e),
+        }
+    }
+}
+
+async fn run_tool(tool: ReactTool, arg: &str, ctx: &Context) -> Result<String, String> {
+    match tool {
+        ReactTool::MemoryGet => Ok(ctx
+            .get_short(arg)
+            .unwrap_or_else(|| "(not set)".to_string())),
+        ReactTool::MemorySet => {
+            let (key, value) = arg.split_once('=').ok_or("expected key=value")?;
+            ctx.set_short(key.trim(), value.trim());
+            Ok("ok".to_string())
+        }
+        ReactTool::Recall => {
+            let vector = ctx.embed(arg).await?;
+            let ids = ctx.query_latent(vector).await?;
+            Ok(json!(ids.into_iter().take(RESULTS_K).collect::<Vec<_>>()).to_string())
+        }
+        ReactTool::Reflect => {
+            let graph = ctx.mem_semantic.lock().map_err(|e| e.to_string())?;
+            if !graph.clusters.contains_key(arg) {
+                return Err(format!("cluster '{}' not found", arg));
+            }
+            let similar = reflect(&graph, arg, RESULTS_K)
+                .into_iter()
+                .map(|(id, score)| json!({ "id": id, "score": score }))
+                .collect::<Vec<_>>();
+            Ok(json!(similar).to_string())
+        }
+        ReactTool::LongSearch => {
+            let entries = ctx.mem_long.search(arg, RESULTS_K).await;
+            Ok(json!(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| json!({ "key": k, "value": v }))
+                    .collect::<Vec<_>>()
+            )
+            .to_string())
+        }
+    }
+}
+
+/// Reads a completion in ReAct format. Text without an `Action:` or
+/// `Final Answer:` line is taken as the answer, since small local models
+/// often drop the format once they know what to say.
+fn parse_reply(text: &str) -> Reply {
+    let mut thought = String::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(rest) = line.strip_prefix("Thought:") {
+            thought = rest.trim().to_string();
+        } else if let Some(rest) = line.strip_prefix("Final Answer:") {
+            let tail = text.split_once("Final Answer:").map_or(rest, |(_, t)| t);
+            return Reply::Answer {
+                thought,
+                answer: tail.trim().to_string(),
+            };
+        } else if let Some(rest) = line.strip_prefix("Action:")
+            && let Some((action, input)) = parse_action(rest.trim())
+        {
+            return Reply::Act {
+                thought,
+                action,
+                input,
+            };
+        }
+    }
+    Reply::Answer {
+        thought,
+        answer: text.trim().to_string(),
+    }
+}
+
+/// Accepts `tool[input]`, `tool(input)` and `tool: input`.
+fn parse_action(action: &str) -> Option<(String, String)> {
+    let (name, input) = match action.find(['[', '(', ':']) {
+        Some(pos) => {
+            let input = action[pos + 1..].trim();
+            let input = input
+                .strip_suffix(']')
+                .or_else(|| input.strip_suffix(')'))
+                .unwrap_or(input);
+            (&action[..pos], input)
+        }
+        None => (action, ""),
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((
+        name.to_lowercase(),
+        input.trim().trim_matches('"').to_string(),
+    ))
+}
+
+fn truncate(mut text: String) -> String {
+    if let Some((pos, _)) = text.char_indices().nth(MAX_OBSERVATION_CHARS) {
+        text.truncate(pos);
+        text.push('…');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_actions_and_answers() {
+        assert_eq!(
+            parse_reply("Thought: look it up\nAction: long_search[favourite colour]\n"),
+            Reply::Act {
+                thought: "look it up".into(),
+                action: "long_search".into(),
+                input: "favourite colour".into(),
+            }
+        );
+        assert_eq!(
+            parse_reply("Thought: done\nFinal Answer: Blue.\nIt was stored last week."),
+            Reply::Answer {
+                thought: "done".into(),
+                answer: "Blue.\nIt was stored last week.".into(),
+            }
+        );
+        assert_eq!(
+            parse_action("memory_set(mood=calm)"),
+            Some(("memory_set".into(), "mood=calm".into()))
+        );
+        assert_eq!(
+            parse_reply("Just blue."),
+            Reply::Answer {
+                thought: String::new(),
+                answer: "Just blue.".into(),
+            }
+        );
+    }
+}
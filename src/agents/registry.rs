@@ -0,0 +1,637 @@
+use crate::agents::BaseAgent;
+use crate::agents::dsl::{self, AgentHeader};
+use crate::agents::react::{ReactAgent, ReactConfig};
+use crate::agents::sandbox::SandboxLimits;
+use crate::agents::tools::Tool;
+use crate::icore::telemetry;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+#[derive(Clone)]
+pub struct AgentEntry {
+    pub agent: Arc<Mutex<BaseAgent>>,
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentKind {
+    /// Rule-based agent written in the Sentience DSL.
+    Sentience,
+    /// LLM tool-use loop, see [`ReactAgent`].
+    React,
+}
+
+/// A registered agent of either kind.
+#[derive(Clone)]
+pub enum AgentRef {
+    Sentience(Arc<Mutex<BaseAgent>>),
+    React(Arc<Mutex<ReactAgent>>),
+}
+
+#[derive(Serialize)]
+pub struct AgentInfo {
+    pub name: String,
+    pub kind: AgentKind,
+    pub goal: String,
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<SandboxLimits>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<usize>,
+    pub tools: Vec<String>,
+    pub default: bool,
+}
+
+/// Settings every agent starts with; each can be changed per agent later.
+#[derive(Debug, Clone, Default)]
+pub struct AgentDefaults {
+    pub limits: SandboxLimits,
+    pub tools: HashSet<Tool>,
+}
+
+impl AgentDefaults {
+    fn apply(&self, agent: &mut BaseAgent) {
+        agent.limits = self.limits;
+        agent.tools = self.tools.clone();
+    }
+}
+
+/// All loaded agents, keyed by the name declared in their DSL or config.
+pub struct AgentRegistry {
+    agents: RwLock<HashMap<String, AgentEntry>>,
+    react: HashMap<String, (Arc<Mutex<ReactAgent>>, PathBuf)>,
+    default: String,
+    dir: PathBuf,
+    defaults: AgentDefaults,
+}
+
+impl AgentRegistry {
+    /// Loads every `*.sent` file in `dir`. Each agent is keyed by its
+    /// `agent <Name>` declaration, falling back to the file stem. When no
+    /// file is found a DSL-less "Reflector" agent is registered instead.
+    /// ReAct agents are loaded from `*.react.json` files alongside. Two
+    /// files declaring the same name are an error.
+    pub async fn load_dir(
+        dir: &Path,
+        default: Option<String>,
+        defaults: AgentDefaults,
+    ) -> Result<Self, String> {
+        let files = sent_files(dir);
+
+        let mut agents = HashMap::new();
+        let mut first = None;
+        for path in files {
+            let code =
+                fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let header = AgentHeader::parse(&code);
+            let name = header.name.unwrap_or_else(|| file_stem(&path));
+            if agents.contains_key(&name) {
+                return Err(format!(
+                    "{}: agent '{}' is already loaded",
+                    path.display(),
+                    name
+                ));
+            }
+
+            for warning in dsl::lint(&code).iter().filter(|d| !d.is_error()) {
+                tracing::warn!("{}:{}", path.display(), warning);
+            }
+            let mut agent = BaseAgent::new(name.clone(), header.goal.unwrap_or_default());
+            defaults.apply(&mut agent);
+            agent.load(&code).await.map_err(|e| {
+                e.lines()
+                    .map(|line| format!("{}:{}", path.display(), line))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?;
+            tracing::info!("Loaded agent '{}' from {}", name, path.display());
+
+            first.get_or_insert_with(|| name.clone());
+            agents.insert(
+                name,
+                AgentEntry {
+                    agent: Arc::new(Mutex::new(agent)),
+                    source: Some(path),
+                },
+            );
+        }
+
+        if agents.is_empty() {
+            tracing::warn!(
+                "No .sent files in {}, running w/o Sentience DSL",
+                dir.display()
+            );
+            let mut agent = BaseAgent::new(
+                "Reflector".to_string(),
+                "Reflective memory agent".to_string(),
+            );
+            defaults.apply(&mut agent);
+            first = Some(agent.name.clone());
+            agents.insert(
+                agent.name.clone(),
+                AgentEntry {
+                    agent: Arc::new(Mutex::new(agent)),
+                    source: None,
+                },
+            );
+        }
+
+        let mut react = HashMap::new();
+        for path in react_files(dir) {
+            let config = fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| {
+                    serde_json::from_str::<ReactConfig>(&json).map_err(|e| e.to_string())
+                })
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            if agents.contains_key(&config.name) || react.contains_key(&config.name) {
+                return Err(format!(
+                    "{}: agent '{}' is already loaded",
+                    path.display(),
+                    config.name
+                ));
+            }
+            tracing::info!(
+                "Loaded ReAct agent '{}' from {}",
+                config.name,
+                path.display()
+            );
+            react.insert(
+                config.name.clone(),
+                (Arc::new(Mutex::new(ReactAgent::new(config))), path),
+            );
+        }
+
+        let default = match default {
+            Some(name) if agents.contains_key(&name) => name,
+            Some(name) if react.contains_key(&name) => {
+                return Err(format!(
+                    "default agent '{}' must be a Sentience agent",
+                    name
+                ));
+            }
+            Some(name) => return Err(format!("default agent '{}' is not loaded", name)),
+            None => first.unwrap_or_default(),
+        };
+
+        Ok(Self {
+            agents: RwLock::new(agents),
+            react,
+            default,
+            dir: dir.to_path_buf(),
+            defaults,
+        })
+    }
+
+    /// Compiles `code` and swaps it into the named agent, keeping its memory.
+    /// On failure the running version stays live. Returns the new version.
+    pub async fn reload(&self, name: &str, code: &str) -> Result<u32, String> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| format!("agent '{}' not found", name))?;
+        let header = AgentHeader::parse(code);
+        if let Some(declared) = &header.name
+            && declared != name
+        {
+            return Err(format!(
+                "source declares agent '{}', expected '{}'",
+                declared, name
+            ));
+        }
+
+        let limits = telemetry::lock_agent("sentience", &entry.agent)
+            .await
+            .limits;
+        let ctx = BaseAgent::compile(code, limits).await?;
+        let mut agent = telemetry::lock_agent("sentience", &entry.agent).await;
+        agent.install(ctx, code);
+        if let Some(goal) = header.goal {
+            agent.goal = goal;
+        }
+        tracing::info!("Reloaded agent '{}' (version {})", name, agent.version());
+        Ok(agent.version())
+    }
+
+    /// Re-installs a previously loaded version as a new version.
+    pub async fn rollback(&self, name: &str, version: Option<u32>) -> Result<u32, String> {
+        let entry = self
+            .get(name)
+            .ok_or_else(|| format!("agent '{}' not found", name))?;
+        let source = {
+            let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+            let versions = agent.versions();
+            let target = match version {
+                Some(v) => versions.iter().find(|av| av.version == v),
+                None => versions.iter().rev().nth(1),
+            };
+            target
+                .map(|v| v.source.clone())
+                .ok_or_else(|| "no such version to roll back to".to_string())?
+        };
+        self.reload(name, &source).await
+    }
+
+    /// Registers an agent from a file that appeared after startup.
+    async fn register_file(&self, path: &Path) -> Result<String, String> {
+        let code = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let header = AgentHeader::parse(&code);
+        let name = header.name.unwrap_or_else(|| file_stem(path));
+        if self.get(&name).is_some() {
+            return Err(format!("agent '{}' is already loaded", name));
+        }
+        let mut agent = BaseAgent::new(name.clone(), header.goal.unwrap_or_default());
+        self.defaults.apply(&mut agent);
+        agent.load(&code).await?;
+        if let Ok(mut agents) = self.agents.write() {
+            agents.insert(
+                name.clone(),
+                AgentEntry {
+                    agent: Arc::new(Mutex::new(agent)),
+                    source: Some(path.to_path_buf()),
+                },
+            );
+        }
+        Ok(name)
+    }
+
+    /// Polls the agents directory and hot-reloads changed `.sent` files.
+    /// A file that fails to compile is logged and the old version kept.
+    pub fn spawn_watcher(&'static self, interval: Duration) {
+        tokio::spawn(async move {
+            let mut seen: HashMap<PathBuf, SystemTime> = sent_files(&self.dir)
+                .into_iter()
+                .filter_map(|p| modified(&p).map(|m| (p, m)))
+                .collect();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                for path in sent_files(&self.dir) {
+                    let Some(mtime) = modified(&path) else {
+                        continue;
+                    };
+                    if seen.get(&path) == Some(&mtime) {
+                        continue;
+                    }
+                    seen.insert(path.clone(), mtime);
+                    self.reload_file(&path).await;
+                }
+            }
+        });
+    }
+
+    async fn reload_file(&self, path: &Path) {
+        let owner = self.agents.read().ok().and_then(|agents| {
+            agents
+                .iter()
+                .find(|(_, e)| e.source.as_deref() == Some(path))
+                .map(|(name, _)| name.clone())
+        });
+        let result = match owner {
+            Some(name) => match fs::read_to_string(path) {
+                Ok(code) if self.is_current(&name, &code).await => return,
+                Ok(code) => self.reload(&name, &code).await.map(|_| name),
+                Err(e) => Err(e.to_string()),
+            },
+            None => self.register_file(path).await,
+        };
+        if let Err(e) = result {
+            tracing::error!("Hot reload of {} failed: {}", path.display(), e);
+        }
+    }
+
+    /// True when `code` is what the agent is already running, e.g. a file
+    /// that was just written by [`AgentRegistry::persist`].
+    async fn is_current(&self, name: &str, code: &str) -> bool {
+        match self.get(name) {
+            Some(entry) => {
+                telemetry::lock_agent("sentience", &entry.agent)
+                    .await
+                    .source()
+                    == Some(code)
+            }
+            None => false,
+        }
+    }
+
+    /// Writes `code` back to the agent's source file so the change survives
+    /// a restart.
+    pub fn persist(&self, name: &str, code: &str) -> Result<(), String> {
+        let path = self
+            .get(name)
+            .and_then(|e| e.source)
+            .unwrap_or_else(|| self.dir.join(format!("{}.sent", name)));
+        fs::write(&path, code).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Source of every agent as `(file name, contents)`: the running
+    /// version of DSL agents and the config file of ReAct agents.
+    pub async fn sources(&self) -> Vec<(String, String)> {
+        let entries = self
+            .agents
+            .read()
+            .map(|a| {
+                a.iter()
+                    .map(|(n, e)| (n.clone(), e.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let mut sources = Vec::new();
+        for (name, entry) in entries {
+            let file = entry
+                .source
+                .as_deref()
+                .and_then(|p| p.file_name())
+                .map(|f| f.to_string_lossy().into_owned())
+                .unwrap_or_else(|| format!("{}.sent", name));
+            let code = match telemetry::lock_agent("sentience", &entry.agent)
+                .await
+                .source()
+            {
+                Some(code) => code.to_string(),
+                None => match entry.source.as_deref().map(fs::read_to_string) {
+                    Some(Ok(code)) => code,
+                    _ => continue,
+                },
+            };
+            sources.push((file, code));
+        }
+        for path in self.react.values().map(|(_, path)| path) {
+            if let (Some(file), Ok(code)) = (path.file_name(), fs::read_to_string(path)) {
+                sources.push((file.to_string_lossy().into_owned(), code));
+            }
+        }
+        sources.sort();
+        sources
+    }
+
+    /// Writes `code` to `file` in the agents directory and loads it, either
+    /// as a new version of the agent it declares or as a new agent. ReAct
+    /// configs are only written and take effect on restart.
+    pub async fn restore_source(&self, file: &str, code: &str) -> Result<(), String> {
+        let path = self.dir.join(
+            Path::new(file)
+                .file_name()
+                .ok_or_else(|| format!("invalid agent file name '{}'", file))?,
+        );
+        if !file.ends_with(".sent") {
+            return fs::write(&path, code).map_err(|e| format!("{}: {}", path.display(), e));
+        }
+        let name = AgentHeader::parse(code)
+            .name
+            .unwrap_or_else(|| file_stem(&path));
+        match self.get(&name) {
+            Some(entry) => {
+                if telemetry::lock_agent("sentience", &entry.agent)
+                    .await
+                    .source()
+                    != Some(code)
+                {
+                    self.reload(&name, code).await?;
+                }
+                self.persist(&name, code)
+            }
+            None => {
+                fs::write(&path, code).map_err(|e| format!("{}: {}", path.display(), e))?;
+                self.register_file(&path).await.map(|_| ())
+            }
+        }
+    }
+
+    /// Drops what every agent holds for a deleted namespace, so a namespace
+    /// created later under the same name starts clean.
+    pub async fn forget_namespace(&self, namespace: &str) {
+        let agents = self
+            .agents
+            .read()
+            .map(|a| a.values().map(|e| e.agent.clone()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        for agent in agents {
+            telemetry::lock_agent("sentience", &agent)
+                .await
+                .forget_namespace(namespace);
+        }
+        for (agent, _) in self.react.values() {
+            telemetry::lock_agent("react", agent)
+                .await
+                .forget_namespace(namespace);
+        }
+    }
+
+    /// Limits new agents start with, and that scratch runs are held to.
+    pub fn default_limits(&self) -> SandboxLimits {
+        self.defaults.limits
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    pub fn get(&self, name: &str) -> Option<AgentEntry> {
+        self.agents.read().ok()?.get(name).cloned()
+    }
+
+    /// Resolves an optional agent name, using the default agent for `None`.
+    pub fn resolve(&self, name: Option<&str>) -> Option<Arc<Mutex<BaseAgent>>> {
+        self.get(name.unwrap_or(&self.default)).map(|e| e.agent)
+    }
+
+    /// Resolves an agent of any kind, using the default agent for `None`.
+    pub fn lookup(&self, name: Option<&str>) -> Option<AgentRef> {
+        let name = name.unwrap_or(&self.default);
+        match self.react.get(name) {
+            Some((agent, _)) => Some(AgentRef::React(agent.clone())),
+            None => self.get(name).map(|e| AgentRef::Sentience(e.agent)),
+        }
+    }
+
+    pub fn react(&self, name: &str) -> Option<Arc<Mutex<ReactAgent>>> {
+        self.react.get(name).map(|(agent, _)| agent.clone())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self
+            .agents
+            .read()
+            .map(|a| a.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        names.extend(self.react.keys().cloned());
+        names.sort();
+        names
+    }
+
+    pub async fn info(&self, name: &str) -> Option<AgentInfo> {
+        if let Some((agent, path)) = self.react.get(name) {
+            let agent = telemetry::lock_agent("react", agent).await;
+            return Some(AgentInfo {
+                name: agent.name.clone(),
+                kind: AgentKind::React,
+                goal: agent.goal.clone(),
+                source: Some(path.display().to_string()),
+                version: None,
+                limits: None,
+                max_steps: Some(agent.max_steps),
+                tools: agent.tools.iter().map(|t| t.name().to_string()).collect(),
+                default: false,
+            });
+        }
+
+        let entry = self.get(name)?;
+        let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+        Some(AgentInfo {
+            name: agent.name.clone(),
+            kind: AgentKind::Sentience,
+            goal: agent.goal.clone(),
+            source: entry.source.map(|p| p.display().to_string()),
+            version: Some(agent.version()),
+            limits: Some(agent.limits),
+            max_steps: None,
+            tools: agent
+                .allowed_tools()
+                .iter()
+                .map(|t| t.name().to_string())
+                .collect(),
+            default: name == self.default,
+        })
+    }
+}
+
+fn sent_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "sent"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+fn react_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| {
+                    p.file_name()
+                        .is_some_and(|n| n.to_string_lossy().ends_with(".react.json"))
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent_dir(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "icore_agents_{}",
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, code) in files {
+            fs::write(dir.join(file), code).unwrap();
+        }
+        dir
+    }
+
+    fn echo(goal: &str) -> String {
+        format!("agent Echo {{\n  goal: \"{}\"\n}}\n", goal)
+    }
+
+    /// The registry loaded from `files`, and its directory to remove.
+    async fn registry(files: &[(&str, &str)]) -> (AgentRegistry, PathBuf) {
+        let dir = agent_dir(files);
+        let registry = AgentRegistry::load_dir(&dir, None, AgentDefaults::default())
+            .await
+            .unwrap();
+        (registry, dir)
+    }
+
+    async fn source(registry: &AgentRegistry, name: &str) -> Option<String> {
+        let entry = registry.get(name)?;
+        telemetry::lock_agent("sentience", &entry.agent)
+            .await
+            .source()
+            .map(str::to_string)
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_fail_to_load() {
+        let first = echo("first");
+        let react = r#"{"name":"Echo","goal":"react"}"#.to_string();
+        for (file, code) in [("b.sent", echo("second")), ("echo.react.json", react)] {
+            let dir = agent_dir(&[("a.sent", &first), (file, &code)]);
+            let loaded = AgentRegistry::load_dir(&dir, None, AgentDefaults::default()).await;
+            let err = loaded.err().unwrap();
+            assert!(err.ends_with("agent 'Echo' is already loaded"), "{}", err);
+            assert!(err.contains(file), "{}", err);
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_reload_keeps_the_running_version() {
+        let live = echo("live");
+        let (registry, dir) = registry(&[("echo.sent", &live)]).await;
+
+        assert!(registry.reload("Echo", "agent Echo {\n").await.is_err());
+        assert!(
+            registry
+                .reload("Echo", &live.replace("Echo", "Other"))
+                .await
+                .is_err()
+        );
+        assert!(registry.reload("Missing", &live).await.is_err());
+
+        let info = registry.info("Echo").await.unwrap();
+        assert_eq!((info.version, info.goal.as_str()), (Some(1), "live"));
+        assert_eq!(source(&registry, "Echo").await, Some(live));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn rollback_reaches_only_the_kept_versions() {
+        let (registry, dir) = registry(&[("echo.sent", &echo("v1"))]).await;
+        for version in 2..=25 {
+            let installed = registry
+                .reload("Echo", &echo(&format!("v{}", version)))
+                .await;
+            assert_eq!(installed, Ok(version));
+        }
+
+        // Only the newest MAX_VERSIONS are kept: 6 through 25.
+        assert!(registry.rollback("Echo", Some(5)).await.is_err());
+        assert_eq!(registry.rollback("Echo", Some(6)).await, Ok(26));
+        assert_eq!(source(&registry, "Echo").await, Some(echo("v6")));
+
+        // Without a version the one before the running version comes back.
+        assert_eq!(registry.rollback("Echo", None).await, Ok(27));
+        assert_eq!(source(&registry, "Echo").await, Some(echo("v25")));
+        assert!(registry.rollback("Echo", Some(6)).await.is_err());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
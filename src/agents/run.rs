@@ -0,0 +1,238 @@
+use crate::agents::dsl::{self, Diagnostic};
+use crate::agents::sandbox::{self, SandboxError, SandboxLimits};
+use sentience::{context::AgentContext, lexer::Lexer, parser::Parser, types::Program};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ValueChange {
+    pub before: String,
+    pub after: String,
+}
+
+/// Key-level difference between two snapshots of one memory scope.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryDiff {
+    pub added: BTreeMap<String, String>,
+    pub changed: BTreeMap<String, ValueChange>,
+    pub removed: Vec<String>,
+}
+
+impl MemoryDiff {
+    pub fn between(before: &HashMap<String, String>, after: &HashMap<String, String>) -> Self {
+        let mut diff = MemoryDiff::default();
+        for (key, value) in after {
+            match before.get(key) {
+                None => {
+                    diff.added.insert(key.clone(), value.clone());
+                }
+                Some(old) if old != value => {
+                    diff.changed.insert(
+                        key.clone(),
+                        ValueChange {
+                            before: old.clone(),
+                            after: value.clone(),
+                        },
+                    );
+                }
+                Some(_) => {}
+            }
+        }
+        diff.removed = before
+            .keys()
+            .filter(|k| !after.contains_key(*k))
+            .cloned()
+            .collect();
+        diff.removed.sort();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Keys and values written by the run, new or overwritten.
+    pub fn writes(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.added
+            .iter()
+            .chain(self.changed.iter().map(|(k, c)| (k, &c.after)))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunOutcome {
+    /// Lines emitted by the evaluator.
+    pub output: Vec<String>,
+    /// The `output` variable set by the program, if any.
+    pub result: Option<String>,
+    pub short: MemoryDiff,
+    pub long: MemoryDiff,
+    pub errors: Vec<Diagnostic>,
+    /// Set when the run was aborted for exceeding a sandbox limit.
+    pub violation: Option<SandboxError>,
+    pub steps: usize,
+}
+
+impl RunOutcome {
+    pub fn succeeded(&self) -> bool {
+        self.errors.is_empty() && self.violation.is_none()
+    }
+}
+
+/// Largest source the parser is handed.
+pub const MAX_SOURCE_BYTES: usize = 1024 * 1024;
+
+/// Runs `f`, turning a panic inside the Sentience parser or evaluator into
+/// a diagnostic at `(line, column)` instead of taking the calling task down.
+pub fn guarded<T>(
+    stage: &str,
+    (line, column): (usize, usize),
+    f: impl FnOnce() -> T,
+) -> Result<T, Diagnostic> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let reason = sandbox::payload_message(payload.as_ref());
+        Diagnostic::error(line, column, format!("{} failed: {}", stage, reason))
+    })
+}
+
+/// Parses `code` on the blocking pool. The Sentience parser reports no
+/// positions, so a failure is placed at the first line it cannot get past.
+pub async fn parse(code: &str) -> Result<Program, Diagnostic> {
+    if code.len() > MAX_SOURCE_BYTES {
+        return Err(Diagnostic::error(
+            1,
+            1,
+            format!("source is larger than {} bytes", MAX_SOURCE_BYTES),
+        ));
+    }
+    let code = code.to_string();
+    tokio::task::spawn_blocking(move || {
+        parse_at(&code, (1, 1)).map_err(|e| {
+            match dsl::locate_failure(&code, |prefix| parse_at(prefix, (1, 1)).is_ok()) {
+                Some(at) => Diagnostic::error(at.0, at.1, e.message),
+                None => e,
+            }
+        })
+    })
+    .await
+    .unwrap_or_else(|e| Err(Diagnostic::error(1, 1, format!("parse failed: {}", e))))
+}
+
+fn parse_at(code: &str, at: (usize, usize)) -> Result<Program, Diagnostic> {
+    guarded("parse", at, || {
+        let mut lexer = Lexer::new(code.trim());
+        let mut parser = Parser::new(&mut lexer);
+        parser.parse_program()
+    })
+}
+
+/// Parses `code` and evaluates it in the sandbox against `ctx`. When
+/// `input` is given and the program declares an agent, its `on input` block
+/// is run with it as well. Memory changes are reported as diffs against the
+/// state before the run; the resulting context is returned only when the
+/// run completed without errors.
+pub async fn execute(
+    code: &str,
+    ctx: AgentContext,
+    input: Option<String>,
+    limits: SandboxLimits,
+) -> (RunOutcome, Option<AgentContext>) {
+    let errors = dsl::lint_snippet(code)
+        .into_iter()
+        .filter(Diagnostic::is_error)
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        return (
+            RunOutcome {
+                errors,
+                ..Default::default()
+            },
+            None,
+        );
+    }
+    let program = match parse(code).await {
+        Ok(program) => program,
+        Err(e) => {
+            return (
+                RunOutcome {
+                    errors: vec![e],
+                    ..Default::default()
+                },
+                None,
+            );
+        }
+    };
+
+    let short_before = ctx.mem_short.clone();
+    let long_before = ctx.mem_long.clone();
+    match sandbox::run(ctx, program.statements, input, limits).await {
+        Ok(run) => {
+            let outcome = RunOutcome {
+                output: run.output,
+                result: run.ctx.output.clone(),
+                short: MemoryDiff::between(&short_before, &run.ctx.mem_short),
+                long: MemoryDiff::between(&long_before, &run.ctx.mem_long),
+                steps: run.steps,
+                ..Default::default()
+            };
+            (outcome, Some(run.ctx))
+        }
+        Err(violation) => (
+            RunOutcome {
+                violation: Some(violation),
+                ..Default::default()
+            },
+            None,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_reports_added_changed_and_removed_keys() {
+        let before = HashMap::from([
+            ("kept".to_string(), "1".to_string()),
+            ("edited".to_string(), "old".to_string()),
+            ("dropped".to_string(), "x".to_string()),
+        ]);
+        let after = HashMap::from([
+            ("kept".to_string(), "1".to_string()),
+            ("edited".to_string(), "new".to_string()),
+            ("fresh".to_string(), "y".to_string()),
+        ]);
+        let diff = MemoryDiff::between(&before, &after);
+        assert_eq!(diff.added.get("fresh").map(String::as_str), Some("y"));
+        assert_eq!(diff.changed["edited"].before, "old");
+        assert_eq!(diff.removed, vec!["dropped"]);
+        assert_eq!(diff.writes().count(), 2);
+        assert!(MemoryDiff::between(&after, &after).is_empty());
+    }
+
+    #[tokio::test]
+    async fn structural_errors_stop_the_run() {
+        let (outcome, ctx) = execute(
+            "mem.short[\"k\" = \"v\"",
+            AgentContext::new(),
+            None,
+            SandboxLimits::default(),
+        )
+        .await;
+        assert_eq!(outcome.errors.len(), 1);
+        assert!(!outcome.succeeded());
+        assert!(ctx.is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_sources_are_not_parsed() {
+        let code = "x".repeat(MAX_SOURCE_BYTES + 1);
+        let err = parse(&code).await.unwrap_err();
+        assert_eq!(
+            err.message,
+            format!("source is larger than {} bytes", MAX_SOURCE_BYTES)
+        );
+    }
+}
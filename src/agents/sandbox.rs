@@ -0,0 +1,373 @@
+use once_cell::sync::Lazy;
+use sentience::{context::AgentContext, eval::eval, types::Statement};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// Runs allowed on the blocking pool at once. A run abandoned on timeout
+/// holds its permit until its thread returns, so programs stuck inside the
+/// evaluator cannot pile up threads; later runs queue, within their own
+/// timeout, for a free permit.
+const MAX_CONCURRENT_RUNS: usize = 8;
+
+static PERMITS: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(MAX_CONCURRENT_RUNS)));
+
+/// Resource limits for one evaluation of agent code.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxLimits {
+    pub timeout_ms: u64,
+    /// Statements dispatched to the evaluator, counting each statement of
+    /// an `on input` block. Work inside a single statement is not metered;
+    /// the timeout covers it.
+    pub max_steps: usize,
+    /// Bytes of evaluator output plus the final `output` value.
+    pub max_output_bytes: usize,
+    /// Distinct short- and long-term keys a run may add or change.
+    pub max_memory_writes: usize,
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 2_000,
+            max_steps: 10_000,
+            max_output_bytes: 64 * 1024,
+            max_memory_writes: 256,
+        }
+    }
+}
+
+/// Partial update of [`SandboxLimits`]; unset fields keep their value.
+#[derive(Debug, Default, Deserialize)]
+pub struct LimitsPatch {
+    pub timeout_ms: Option<u64>,
+    pub max_steps: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+    pub max_memory_writes: Option<usize>,
+}
+
+impl LimitsPatch {
+    pub fn apply(&self, limits: &mut SandboxLimits) {
+        if let Some(v) = self.timeout_ms {
+            limits.timeout_ms = v;
+        }
+        if let Some(v) = self.max_steps {
+            limits.max_steps = v;
+        }
+        if let Some(v) = self.max_output_bytes {
+            limits.max_output_bytes = v;
+        }
+        if let Some(v) = self.max_memory_writes {
+            limits.max_memory_writes = v;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SandboxError {
+    Timeout {
+        limit_ms: u64,
+    },
+    StepLimit {
+        limit: usize,
+    },
+    OutputLimit {
+        limit_bytes: usize,
+    },
+    MemoryQuota {
+        limit: usize,
+    },
+    /// `step` is the statement that panicked, counted like `max_steps`,
+    /// when the panic came from the evaluator.
+    Panic {
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        step: Option<usize>,
+    },
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::Timeout { limit_ms } => {
+                write!(f, "evaluation exceeded {} ms", limit_ms)
+            }
+            SandboxError::StepLimit { limit } => {
+                write!(f, "evaluation exceeded {} steps", limit)
+            }
+            SandboxError::OutputLimit { limit_bytes } => {
+                write!(f, "output exceeded {} bytes", limit_bytes)
+            }
+            SandboxError::MemoryQuota { limit } => {
+                write!(f, "more than {} memory keys written", limit)
+            }
+            SandboxError::Panic {
+                message,
+                step: Some(step),
+            } => write!(f, "evaluator panicked at step {}: {}", step, message),
+            SandboxError::Panic { message, .. } => write!(f, "evaluator panicked: {}", message),
+        }
+    }
+}
+
+/// Result of a completed sandboxed run.
+pub struct Run {
+    pub ctx: AgentContext,
+    pub output: Vec<String>,
+    pub steps: usize,
+}
+
+/// Copies the program and memory of a context. The evaluator works on a
+/// copy so that a run which is abandoned on timeout leaves nothing behind.
+pub fn scratch_copy(ctx: &AgentContext) -> AgentContext {
+    let mut copy = AgentContext::new();
+    copy.current_agent = ctx.current_agent.clone();
+    copy.mem_short = ctx.mem_short.clone();
+    copy.mem_long = ctx.mem_long.clone();
+    copy
+}
+
+/// Sets the flag when the awaiting side goes away, whether it timed out or
+/// its request was dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Evaluates `statements` against `ctx` on the blocking pool, then, if
+/// `input` is given, the `on input` block of the declared agent.
+///
+/// Steps, output, memory writes, the deadline and a cancel flag are checked
+/// before every statement, so a run that overshoots stops at the next
+/// statement. The flag is raised as soon as the caller stops waiting, and
+/// callers must not rely on `ctx` after an error.
+pub async fn run(
+    ctx: AgentContext,
+    statements: Vec<Statement>,
+    input: Option<String>,
+    limits: SandboxLimits,
+) -> Result<Run, SandboxError> {
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(limits.timeout_ms);
+    let timeout = SandboxError::Timeout {
+        limit_ms: limits.timeout_ms,
+    };
+    let Ok(Ok(permit)) = tokio::time::timeout_at(deadline, PERMITS.clone().acquire_owned()).await
+    else {
+        return Err(timeout);
+    };
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _cancel_on_drop = CancelOnDrop(cancel.clone());
+
+    let task = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let mut meter = Meter::new(&ctx, limits, cancel);
+        let mut ctx = ctx;
+        let mut output = Vec::new();
+        let arg = input.as_deref().unwrap_or("");
+
+        for stmt in &statements {
+            meter.step(&ctx, &output)?;
+            eval_step(stmt, arg, &mut ctx, &mut output, meter.steps)?;
+        }
+        if let Some(input) = input.as_deref()
+            && let Some(Statement::AgentDeclaration { body, .. }) = ctx.current_agent.clone()
+        {
+            ctx.set_mem("short", "msg", input);
+            if let Some(Statement::OnInput { body, .. }) = body
+                .into_iter()
+                .find(|s| matches!(s, Statement::OnInput { .. }))
+            {
+                for stmt in &body {
+                    meter.step(&ctx, &output)?;
+                    eval_step(stmt, input, &mut ctx, &mut output, meter.steps)?;
+                }
+            }
+        }
+        meter.check(&ctx, &output)?;
+        Ok(Run {
+            ctx,
+            output,
+            steps: meter.steps,
+        })
+    });
+
+    match tokio::time::timeout_at(deadline, task).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => Err(SandboxError::Panic {
+            message: panic_message(e),
+            step: None,
+        }),
+        Err(_) => Err(timeout),
+    }
+}
+
+/// Evaluates one statement, turning a panic in the evaluator into a
+/// violation that names the step it happened at.
+fn eval_step(
+    stmt: &Statement,
+    arg: &str,
+    ctx: &mut AgentContext,
+    output: &mut Vec<String>,
+    step: usize,
+) -> Result<(), SandboxError> {
+    panic::catch_unwind(AssertUnwindSafe(|| eval(stmt, "", arg, ctx, output))).map_err(|payload| {
+        SandboxError::Panic {
+            message: payload_message(payload.as_ref()),
+            step: Some(step),
+        }
+    })
+}
+
+struct Meter {
+    limits: SandboxLimits,
+    deadline: Instant,
+    cancel: Arc<AtomicBool>,
+    steps: usize,
+    short_before: HashMap<String, String>,
+    long_before: HashMap<String, String>,
+}
+
+impl Meter {
+    fn new(ctx: &AgentContext, limits: SandboxLimits, cancel: Arc<AtomicBool>) -> Self {
+        Self {
+            limits,
+            deadline: Instant::now() + Duration::from_millis(limits.timeout_ms),
+            cancel,
+            steps: 0,
+            short_before: ctx.mem_short.clone(),
+            long_before: ctx.mem_long.clone(),
+        }
+    }
+
+    /// Called before each statement; also re-checks the previous one.
+    fn step(&mut self, ctx: &AgentContext, output: &[String]) -> Result<(), SandboxError> {
+        self.check(ctx, output)?;
+        self.steps += 1;
+        if self.steps > self.limits.max_steps {
+            return Err(SandboxError::StepLimit {
+                limit: self.limits.max_steps,
+            });
+        }
+        // Stops a long run between statements, whether or not the caller
+        // has given up on it yet.
+        if self.cancel.load(Ordering::Relaxed) || Instant::now() > self.deadline {
+            return Err(SandboxError::Timeout {
+                limit_ms: self.limits.timeout_ms,
+            });
+        }
+        Ok(())
+    }
+
+    fn check(&self, ctx: &AgentContext, output: &[String]) -> Result<(), SandboxError> {
+        let bytes = output.iter().map(String::len).sum::<usize>()
+            + ctx.output.as_ref().map_or(0, String::len);
+        if bytes > self.limits.max_output_bytes {
+            return Err(SandboxError::OutputLimit {
+                limit_bytes: self.limits.max_output_bytes,
+            });
+        }
+        let writes =
+            writes(&self.short_before, &ctx.mem_short) + writes(&self.long_before, &ctx.mem_long);
+        if writes > self.limits.max_memory_writes {
+            return Err(SandboxError::MemoryQuota {
+                limit: self.limits.max_memory_writes,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn writes(before: &HashMap<String, String>, after: &HashMap<String, String>) -> usize {
+    after
+        .iter()
+        .filter(|(k, v)| before.get(*k) != Some(*v))
+        .count()
+}
+
+fn panic_message(e: tokio::task::JoinError) -> String {
+    match e.try_into_panic() {
+        Ok(payload) => payload_message(payload.as_ref()),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// The message a panic was raised with.
+pub fn payload_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<String>()
+        .cloned()
+        .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_enforces_steps_output_and_writes() {
+        let limits = SandboxLimits {
+            max_steps: 2,
+            max_output_bytes: 4,
+            max_memory_writes: 1,
+            ..Default::default()
+        };
+        let mut ctx = AgentContext::new();
+        ctx.mem_short.insert("seen".into(), "1".into());
+        let mut meter = Meter::new(&ctx, limits, Arc::default());
+
+        assert_eq!(meter.step(&ctx, &[]), Ok(()));
+        assert_eq!(meter.step(&ctx, &[]), Ok(()));
+        assert_eq!(
+            meter.step(&ctx, &[]),
+            Err(SandboxError::StepLimit { limit: 2 })
+        );
+
+        assert_eq!(
+            meter.check(&ctx, &["hello".to_string()]),
+            Err(SandboxError::OutputLimit { limit_bytes: 4 })
+        );
+
+        ctx.mem_short.insert("seen".into(), "1".into());
+        ctx.mem_short.insert("a".into(), "x".into());
+        assert_eq!(meter.check(&ctx, &[]), Ok(()));
+        ctx.mem_long.insert("b".into(), "y".into());
+        assert_eq!(
+            meter.check(&ctx, &[]),
+            Err(SandboxError::MemoryQuota { limit: 1 })
+        );
+    }
+
+    #[test]
+    fn cancelled_run_stops_at_the_next_statement() {
+        let ctx = AgentContext::new();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut meter = Meter::new(&ctx, SandboxLimits::default(), cancel.clone());
+        assert_eq!(meter.step(&ctx, &[]), Ok(()));
+        drop(CancelOnDrop(cancel));
+        assert_eq!(
+            meter.step(&ctx, &[]),
+            Err(SandboxError::Timeout { limit_ms: 2_000 })
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_program_completes_within_limits() {
+        let Ok(run) = run(AgentContext::new(), vec![], None, SandboxLimits::default()).await else {
+            panic!("empty program failed");
+        };
+        assert_eq!(run.steps, 0);
+        assert!(run.output.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::icore::context::Context;
+use crate::icore::model;
+use crate::memory::semantic::reflect::reflect;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+/// Agents request a tool by writing `mem.short["call:<tool>"] = "<arg>"`,
+/// or `call:<tool>:<id>` to make several calls to one tool (for
+/// `remember_long` the id is the key to write). The host runs the call and
+/// writes the answer under the same name, `mem.short["result:<tool>[:<id>]"]`.
+pub const CALL_PREFIX: &str = "call:";
+pub const RESULT_PREFIX: &str = "result:";
+/// Rounds of "run `on input`, serve its tool calls" per message.
+pub const MAX_TOOL_ROUNDS: usize = 4;
+const MAX_LOGGED_CALLS: usize = 100;
+const RECALL_K: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tool {
+    Recall,
+    Reflect,
+    Generate,
+    RememberLong,
+    Embed,
+}
+
+impl Tool {
+    pub const ALL: [Tool; 5] = [
+        Tool::Recall,
+        Tool::Reflect,
+        Tool::Generate,
+        Tool::RememberLong,
+        Tool::Embed,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tool::Recall => "recall",
+            Tool::Reflect => "reflect",
+            Tool::Generate => "generate",
+            Tool::RememberLong => "remember_long",
+            Tool::Embed => "embed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolCall {
+    /// Name as written by the agent; may not be a known tool.
+    pub name: String,
+    /// The `<id>` of `call:<tool>:<id>`.
+    pub key: Option<String>,
+    pub arg: String,
+}
+
+impl ToolCall {
+    /// Short-term key the call's answer is written to.
+    pub fn result_key(&self) -> String {
+        match &self.key {
+            Some(key) => format!("{}{}:{}", RESULT_PREFIX, self.name, key),
+            None => format!("{}{}", RESULT_PREFIX, self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolCallRecord {
+    pub namespace: String,
+    pub tool: String,
+    pub arg: String,
+    pub ok: bool,
+    pub result: String,
+    pub elapsed_ms: u128,
+    pub timestamp: i64,
+}
+
+/// Removes pending tool calls from an agent's short-term memory.
+pub fn take_calls(mem_short: &mut HashMap<String, String>) -> Vec<ToolCall> {
+    let keys = mem_short
+        .keys()
+        .filter(|k| k.starts_with(CALL_PREFIX))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut calls = keys
+        .into_iter()
+        .filter_map(|k| {
+            let arg = mem_short.remove(&k)?;
+            let spec = &k[CALL_PREFIX.len()..];
+            let (name, key) = match spec.split_once(':') {
+                Some((name, key)) => (name, Some(key.to_string())),
+                None => (spec, None),
+            };
+            Some(ToolCall {
+                name: name.to_string(),
+                key,
+                arg,
+            })
+        })
+        .collect::<Vec<_>>();
+    calls.sort_by(|a, b| a.name.cmp(&b.name).then(a.key.cmp(&b.key)));
+    calls
+}
+
+/// Permission-checks and runs one call over the server's memory and models.
+pub async fn invoke(
+    agent: &str,
+    allowed: &HashSet<Tool>,
+    call: &ToolCall,
+    ctx: &Context,
+) -> ToolCallRecord {
+    let started = Instant::now();
+    let result = match Tool::parse(&call.name) {
+        None => Err(format!("unknown tool '{}'", call.name)),
+        Some(tool) if !allowed.contains(&tool) => {
+            Err(format!("tool '{}' is not permitted", tool.name()))
+        }
+        Some(tool) => run(tool, call, ctx).await,
+    };
+
+    let record = ToolCallRecord {
+        namespace: ctx.namespace.clone(),
+        tool: call.name.clone(),
+        arg: call.arg.clone(),
+        ok: result.is_ok(),
+        result: result.unwrap_or_else(|e| format!("error: {}", e)),
+        elapsed_ms: started.elapsed().as_millis(),
+        timestamp: chrono::Utc::now().timestamp_millis(),
+    };
+    if record.ok {
+        tracing::info!(
+            "agent '{}' called {}({:?}) in {} ms (namespace '{}')",
+            agent,
+            record.tool,
+            record.arg,
+            record.elapsed_ms,
+            ctx.namespace
+        );
+    } else {
+        tracing::warn!(
+            "agent '{}' call {}({:?}) failed: {} (namespace '{}')",
+            agent,
+            record.tool,
+            record.arg,
+            record.result,
+            ctx.namespace
+        );
+    }
+    record
+}
+
+async fn run(tool: Tool, call: &ToolCall, ctx: &Context) -> Result<String, String> {
+    match tool {
+        Tool::Recall => {
+            let vector = ctx.embed(&call.arg).await?;
+            let ids = ctx.query_latent(vector).await?;
+            Ok(json!(ids.into_iter().take(RECALL_K).collect::<Vec<_>>()).to_string())
+        }
+        Tool::Reflect => {
+            let graph = ctx.mem_semantic.lock().map_err(|e| e.to_string())?;
+            if !graph.clusters.contains_key(&call.arg) {
+                return Err(format!("cluster '{}' not found", call.arg));
+            }
+            let similar = reflect(&graph, &call.arg, RECALL_K)
+                .into_iter()
+                .map(|(id, score)| json!({ "id": id, "score": score }))
+                .collect::<Vec<_>>();
+            Ok(json!(similar).to_string())
+        }
+        Tool::Generate => model::generate(&call.arg).await.map_err(|e| e.to_string()),
+        Tool::RememberLong => {
+            let key = call
+                .key
+                .as_deref()
+                .filter(|k| !k.is_empty())
+                .ok_or("remember_long needs a key: call:remember_long:<key>")?;
+            ctx.set_long(key, &call.arg).await?;
+            Ok("ok".to_string())
+        }
+        Tool::Embed => {
+            let vector = ctx.embed(&call.arg).await?;
+            Ok(json!(vector).to_string())
+        }
+    }
+}
+
+/// Keeps the most recent calls for inspection through the API.
+pub fn log_call(log: &mut Vec<ToolCallRecord>, record: ToolCallRecord) {
+    log.push(record);
+    if log.len() > MAX_LOGGED_CALLS {
+        log.remove(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_only_call_keys() {
+        let mut mem = HashMap::from([
+            ("call:recall".to_string(), "cats".to_string()),
+            ("call:remember_long:fav".to_string(), "blue".to_string()),
+            ("msg".to_string(), "hi".to_string()),
+        ]);
+        let calls = take_calls(&mut mem);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "recall");
+        assert_eq!(calls[1].key.as_deref(), Some("fav"));
+        assert_eq!(mem.len(), 1);
+        assert_eq!(calls[0].result_key(), "result:recall");
+        assert_eq!(calls[1].result_key(), "result:remember_long:fav");
+        assert_eq!(Tool::parse("remember_long"), Some(Tool::RememberLong));
+        assert_eq!(Tool::parse("shell"), None);
+    }
+}
@@ -1,57 +1,162 @@
-use crate::agents::AGENT;
+use crate::agents::dsl::Diagnostic;
+use crate::agents::run::{self, RunOutcome};
+use crate::agents::sandbox::{LimitsPatch, SandboxError};
+use crate::agents::tools::Tool;
+use crate::agents::{AgentRef, BaseAgent, MemScope, registry};
+use crate::icore::backup;
 use crate::icore::context::Context;
-use crate::icore::embed::embed_text;
-use crate::memory::semantic::latent_graph::SEMANTIC_GRAPH;
-use crate::memory::semantic::object::{AffectScore, ObjectCluster};
+use crate::icore::embed_cache;
+use crate::icore::interchange;
+use crate::icore::model;
+use crate::icore::namespace::{DEFAULT_NAMESPACE, namespaces};
+use crate::icore::outbox::{self, Task};
+use crate::icore::reembed;
+use crate::icore::telemetry;
+use crate::memory::episodic::{self, EpisodicMemory, Event};
+use crate::memory::long_term::{BulkEntry, ListFilter, LongTermMemory, Precondition};
+use crate::memory::semantic::concepts::{self, ConceptOptions};
+use crate::memory::semantic::evaluator::SemanticEvaluator;
+use crate::memory::semantic::export::{self, ClusterRecord, GraphFormat};
+use crate::memory::semantic::latent_graph::LatentGraph;
+use crate::memory::semantic::object::{Affect, AffectScore};
 use crate::memory::semantic::reflect::reflect;
-use crate::memory::{latent::LatentMemory, long_term::LongTermMemory, short_term::ShortTermMemory};
+use crate::memory::{latent::LatentMemory, short_term::ShortTermMemory, value};
 use axum::{
+    body::{Body, Bytes},
     debug_handler,
-    extract::{Json, Path},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Json, Path, Query},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
 };
+use sentience::context::AgentContext;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{Value, json};
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::OnceLock;
+use std::time::Instant;
 use tokio::sync::Mutex;
 
 pub static SHORT_MEM: OnceLock<ShortTermMemory> = OnceLock::new();
 pub static LONG_MEM: OnceLock<LongTermMemory> = OnceLock::new();
 pub static LATENT_MEM: OnceLock<Arc<Mutex<LatentMemory>>> = OnceLock::new();
+pub static EPISODIC_MEM: OnceLock<EpisodicMemory> = OnceLock::new();
 
-fn mem() -> &'static ShortTermMemory {
-    SHORT_MEM.get().expect("Short-term memory not initialized")
+pub async fn ping() -> &'static str {
+    "pong"
 }
 
-async fn long_mem() -> &'static LongTermMemory {
-    LONG_MEM.get().expect("Long-term memory not initialized")
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
 }
 
-pub async fn ping() -> &'static str {
-    "pong"
+impl ListQuery {
+    fn limit(&self) -> usize {
+        page_size(self.limit)
+    }
 }
 
-pub async fn get_all_short_mem() -> impl IntoResponse {
-    match mem().all() {
-        Some(map) => Json(map).into_response(),
-        None => (StatusCode::INTERNAL_SERVER_ERROR, "memory lock failed").into_response(),
+fn page_size(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+}
+
+#[derive(Serialize)]
+pub struct MemEntry {
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Filters an in-memory map by key prefix and returns one page of it,
+/// ordered by key.
+fn paginate<V: Into<Value>>(map: HashMap<String, V>, query: &ListQuery) -> Page<MemEntry> {
+    let mut entries = map
+        .into_iter()
+        .filter(|(k, _)| query.prefix.as_deref().is_none_or(|p| k.starts_with(p)))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    let total = entries.len();
+    let items = entries
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit())
+        .map(|(key, value)| MemEntry {
+            key,
+            value: value.into(),
+        })
+        .collect();
+    Page {
+        items,
+        total,
+        offset: query.offset,
+        limit: query.limit(),
     }
 }
 
-pub async fn get_short_mem(Path(key): Path<String>) -> impl IntoResponse {
-    match mem().get(&key) {
-        Some(val) => (StatusCode::OK, val),
-        None => (StatusCode::NOT_FOUND, "key not found".to_string()),
+/// Key under which the pre-collection API served the whole map. A stored
+/// key of that name wins; otherwise the legacy listing is returned.
+const LEGACY_ALL_KEY: &str = "all";
+
+fn legacy_listing<T: Serialize>(body: T) -> Response {
+    (
+        [(header::HeaderName::from_static("deprecation"), "true")],
+        Json(body),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ValueQuery {
+    /// JSON path into the stored value, e.g. `$.user.name`.
+    path: Option<String>,
+}
+
+/// Strings are returned as plain text, as they were before values were
+/// typed; structured values and anything selected by `path` as JSON.
+fn value_response(value: &Value, path: Option<&str>) -> Response {
+    let Some(path) = path else {
+        return match value {
+            Value::String(text) => (StatusCode::OK, text.clone()).into_response(),
+            other => Json(other).into_response(),
+        };
+    };
+    match value::select(value, path) {
+        Ok(Some(selected)) => Json(selected).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "path not found").into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
     }
 }
 
-pub async fn get_long_mem(Path(key): Path<String>) -> impl IntoResponse {
-    let mem = long_mem().await;
-    match mem.get(&key).await {
-        Some(val) => (StatusCode::OK, val),
-        None => (StatusCode::NOT_FOUND, "key not found".to_string()),
+pub async fn list_short_mem(ctx: Context, Query(query): Query<ListQuery>) -> impl IntoResponse {
+    match ctx.mem_short.all_values() {
+        Some(map) => Json(paginate(map, &query)).into_response(),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, "memory lock failed").into_response(),
+    }
+}
+
+pub async fn get_short_mem(
+    ctx: Context,
+    Path(key): Path<String>,
+    Query(query): Query<ValueQuery>,
+) -> impl IntoResponse {
+    match ctx.mem_short.get_value(&key) {
+        Some(val) => value_response(&val, query.path.as_deref()),
+        None if key == LEGACY_ALL_KEY => legacy_listing(ctx.mem_short.all().unwrap_or_default()),
+        None => (StatusCode::NOT_FOUND, "key not found".to_string()).into_response(),
     }
 }
 
@@ -60,21 +165,364 @@ pub struct SetValue {
     value: String,
 }
 
+/// A string or any JSON value for the short- and long-term stores.
+#[derive(Deserialize)]
+pub struct SetTypedValue {
+    value: Value,
+}
+
 pub async fn set_short_mem(
+    ctx: Context,
     Path(key): Path<String>,
-    Json(payload): Json<SetValue>,
+    Json(payload): Json<SetTypedValue>,
 ) -> impl IntoResponse {
-    mem().set(key, payload.value);
+    ctx.mem_short.set_value(key, payload.value);
     (StatusCode::OK, "stored")
 }
 
+/// Applies a JSON Merge Patch (RFC 7396) to an existing value.
+pub async fn patch_short_mem(
+    ctx: Context,
+    Path(key): Path<String>,
+    Json(patch): Json<Value>,
+) -> impl IntoResponse {
+    match ctx
+        .mem_short
+        .update(&key, |v| value::merge_patch(v, &patch))
+    {
+        Some(merged) => Json(merged).into_response(),
+        None => (StatusCode::NOT_FOUND, "key not found").into_response(),
+    }
+}
+
+pub async fn delete_short_mem(ctx: Context, Path(key): Path<String>) -> impl IntoResponse {
+    if ctx.mem_short.get(&key).is_none() {
+        return (StatusCode::NOT_FOUND, "key not found").into_response();
+    }
+    ctx.mem_short.delete(&key);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Listing filters for long-term memory; the bounds are RFC 3339
+/// timestamps, `created_after` inclusive and `created_before` exclusive.
+#[derive(Deserialize)]
+pub struct LongListQuery {
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+}
+
+/// Converts an RFC 3339 timestamp to SQLite's `CURRENT_TIMESTAMP` format.
+fn sqlite_timestamp(text: &str) -> Result<String, String> {
+    chrono::DateTime::parse_from_rfc3339(text)
+        .map(|t| {
+            t.with_timezone(&chrono::Utc)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .map_err(|e| format!("invalid timestamp '{}': {}", text, e))
+}
+
+pub async fn list_long_mem(ctx: Context, Query(query): Query<LongListQuery>) -> impl IntoResponse {
+    let bound = |t: &Option<String>| t.as_deref().map(sqlite_timestamp).transpose();
+    let (created_after, created_before) =
+        match (bound(&query.created_after), bound(&query.created_before)) {
+            (Ok(after), Ok(before)) => (after, before),
+            (Err(e), _) | (_, Err(e)) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        };
+    let filter = ListFilter {
+        prefix: query.prefix.as_deref(),
+        created_after,
+        created_before,
+    };
+    let limit = page_size(query.limit);
+    let (items, total) = ctx.mem_long.list(&filter, query.offset, limit).await;
+    Json(Page {
+        items,
+        total,
+        offset: query.offset,
+        limit,
+    })
+    .into_response()
+}
+
+fn etag(version: i64) -> [(header::HeaderName, String); 1] {
+    [(header::ETAG, format!("\"{}\"", version))]
+}
+
+/// Reads `If-Match` (an entry version as sent in `ETag`, or `*`) and
+/// `If-None-Match: *` into a write precondition.
+fn precondition(headers: &HeaderMap) -> Result<Option<Precondition>, String> {
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) {
+        return match value.to_str().map(str::trim) {
+            Ok("*") => Ok(Some(Precondition::Absent)),
+            _ => Err("only `If-None-Match: *` is supported".to_string()),
+        };
+    }
+    let Some(value) = headers.get(header::IF_MATCH) else {
+        return Ok(None);
+    };
+    let text = value.to_str().map_err(|e| e.to_string())?.trim();
+    if text == "*" {
+        return Ok(Some(Precondition::Exists));
+    }
+    text.trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .map(|v| Some(Precondition::Version(v)))
+        .map_err(|_| format!("invalid If-Match value '{}'", text))
+}
+
+fn precondition_failed(current: Option<i64>) -> Response {
+    match current {
+        Some(version) => (
+            StatusCode::PRECONDITION_FAILED,
+            etag(version),
+            format!("precondition failed: current version is {}", version),
+        )
+            .into_response(),
+        None => (
+            StatusCode::PRECONDITION_FAILED,
+            "precondition failed: key not found",
+        )
+            .into_response(),
+    }
+}
+
+pub async fn get_long_mem(
+    ctx: Context,
+    Path(key): Path<String>,
+    Query(query): Query<ValueQuery>,
+) -> impl IntoResponse {
+    let mem = &ctx.mem_long;
+    match mem.record(&key).await {
+        Some(record) => (
+            etag(record.version),
+            value_response(&record.value, query.path.as_deref()),
+        )
+            .into_response(),
+        None if key == LEGACY_ALL_KEY => {
+            legacy_listing(mem.all().await.into_iter().collect::<HashMap<_, _>>())
+        }
+        None => (StatusCode::NOT_FOUND, "key not found".to_string()).into_response(),
+    }
+}
+
+/// Writes unconditionally, or as a compare-and-set when `If-Match` or
+/// `If-None-Match: *` is sent.
 pub async fn set_long_mem(
+    ctx: Context,
     Path(key): Path<String>,
-    Json(payload): Json<SetValue>,
+    headers: HeaderMap,
+    Json(payload): Json<SetTypedValue>,
 ) -> impl IntoResponse {
-    let mem = long_mem().await;
-    mem.set(&key, &payload.value).await;
-    (StatusCode::OK, "stored")
+    let mem = &ctx.mem_long;
+    let condition = match precondition(&headers) {
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        Ok(condition) => condition,
+    };
+    let mut tx = match mem.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    };
+    let written = match condition {
+        Some(condition) => {
+            mem.set_if_in(&mut tx, &key, &payload.value, condition)
+                .await
+        }
+        None => match mem.set_value_in(&mut tx, &key, &payload.value).await {
+            Some(version) => Ok(version),
+            None => return (StatusCode::INTERNAL_SERVER_ERROR, "write failed").into_response(),
+        },
+    };
+    match written {
+        Ok(version) => {
+            let task = Task::long_entry(&key, &payload.value);
+            if let Err(e) = ctx.commit_with(tx, &[task]).await {
+                return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+            }
+            (StatusCode::OK, etag(version), "stored").into_response()
+        }
+        Err(current) => precondition_failed(current),
+    }
+}
+
+/// Attempts at a merge before giving up on concurrent writers.
+const PATCH_ATTEMPTS: usize = 3;
+
+/// Applies a JSON Merge Patch (RFC 7396) to an existing value. The write is
+/// a compare-and-set against the version that was read; with `If-Match`
+/// that version must be the given one, otherwise the merge is retried
+/// when another writer got in between.
+pub async fn patch_long_mem(
+    ctx: Context,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    Json(patch): Json<Value>,
+) -> impl IntoResponse {
+    let mem = &ctx.mem_long;
+    let expected = match precondition(&headers) {
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+        Ok(Some(Precondition::Absent)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "If-None-Match is not supported on patch",
+            )
+                .into_response();
+        }
+        Ok(Some(Precondition::Version(version))) => Some(version),
+        Ok(None | Some(Precondition::Exists)) => None,
+    };
+    for _ in 0..PATCH_ATTEMPTS {
+        let Some(record) = mem.record(&key).await else {
+            return (StatusCode::NOT_FOUND, "key not found").into_response();
+        };
+        if expected.is_some_and(|v| v != record.version) {
+            return precondition_failed(Some(record.version));
+        }
+        let mut merged = record.value;
+        value::merge_patch(&mut merged, &patch);
+        let mut tx = match mem.begin().await {
+            Ok(tx) => tx,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        };
+        match mem
+            .set_if_in(
+                &mut tx,
+                &key,
+                &merged,
+                Precondition::Version(record.version),
+            )
+            .await
+        {
+            Ok(version) => {
+                let task = Task::long_entry(&key, &merged);
+                if let Err(e) = ctx.commit_with(tx, &[task]).await {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+                }
+                return (etag(version), Json(merged)).into_response();
+            }
+            Err(current) if expected.is_some() => return precondition_failed(current),
+            Err(_) => continue,
+        }
+    }
+    (
+        StatusCode::CONFLICT,
+        "value kept changing; patch not applied",
+    )
+        .into_response()
+}
+
+pub async fn delete_long_mem(
+    ctx: Context,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let mem = &ctx.mem_long;
+    match precondition(&headers) {
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+        Ok(Some(Precondition::Absent)) => (
+            StatusCode::BAD_REQUEST,
+            "If-None-Match is not supported on delete",
+        )
+            .into_response(),
+        Ok(Some(Precondition::Version(version))) => {
+            let mut tx = match mem.begin().await {
+                Ok(tx) => tx,
+                Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+            };
+            match mem.delete_if_in(&mut tx, &key, version).await {
+                Ok(()) => match ctx.commit_with(tx, &[Task::forget_long(&[key])]).await {
+                    Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                    Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+                },
+                Err(current) => precondition_failed(current),
+            }
+        }
+        Ok(None | Some(Precondition::Exists)) => match ctx.delete_long(&[key]).await {
+            Ok(0) => (StatusCode::NOT_FOUND, "key not found").into_response(),
+            Ok(_) => StatusCode::NO_CONTENT.into_response(),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+        },
+    }
+}
+
+#[derive(Serialize)]
+pub struct WrittenEntry {
+    pub key: String,
+    pub version: i64,
+}
+
+/// Upserts a JSON array of entries atomically. Entries may carry the
+/// `version` they expect to overwrite (`0` for "must not exist"); one
+/// mismatch rejects the whole batch.
+pub async fn bulk_set_long_mem(
+    ctx: Context,
+    Json(entries): Json<Vec<BulkEntry>>,
+) -> impl IntoResponse {
+    let mut tx = match ctx.mem_long.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    };
+    let written = match ctx.mem_long.upsert_many_in(&mut tx, &entries).await {
+        Ok(versions) => {
+            let tasks = entries
+                .iter()
+                .map(|entry| Task::long_entry(&entry.key, &entry.value))
+                .collect::<Vec<_>>();
+            ctx.commit_with(tx, &tasks)
+                .await
+                .map(|()| versions)
+                .map_err(|e| {
+                    tracing::error!("bulk write failed: {}", e);
+                    Vec::new()
+                })
+        }
+        // Dropping the transaction rolls it back.
+        Err(conflicts) => Err(conflicts),
+    };
+    match written {
+        Ok(versions) => Json(
+            entries
+                .into_iter()
+                .zip(versions)
+                .map(|(entry, version)| WrittenEntry {
+                    key: entry.key,
+                    version,
+                })
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(conflicts) if conflicts.is_empty() => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "write failed").into_response()
+        }
+        Err(conflicts) => (
+            StatusCode::PRECONDITION_FAILED,
+            Json(json!({ "conflicts": conflicts })),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BulkDelete {
+    keys: Vec<String>,
+}
+
+pub async fn bulk_delete_long_mem(
+    ctx: Context,
+    Json(payload): Json<BulkDelete>,
+) -> impl IntoResponse {
+    if payload.keys.is_empty() {
+        return (StatusCode::BAD_REQUEST, "keys must not be empty").into_response();
+    }
+    match ctx.delete_long(&payload.keys).await {
+        Ok(deleted) => Json(json!({ "deleted": deleted })).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
 }
 
 #[derive(Deserialize)]
@@ -83,12 +531,12 @@ pub struct EmbedPayload {
     content: String,
 }
 
-pub async fn embed_latent(Json(payload): Json<EmbedPayload>) -> impl IntoResponse {
-    let Ok(vec) = embed_text(&payload.content).await else {
+pub async fn embed_latent(ctx: Context, Json(payload): Json<EmbedPayload>) -> impl IntoResponse {
+    let Ok(vec) = ctx.embed(&payload.content).await else {
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     };
 
-    let mem = LATENT_MEM.get().unwrap();
+    let mem = &ctx.mem_latent;
     let lock = mem.lock().await;
     match lock.query(vec).await {
         Ok(ids) => axum::Json(json!({ "ids": ids })).into_response(),
@@ -102,9 +550,10 @@ pub struct QueryPayload {
 }
 
 pub async fn query_latent(
+    ctx: Context,
     Json(_payload): Json<QueryPayload>,
 ) -> Result<axum::Json<Vec<String>>, (StatusCode, String)> {
-    let mem = LATENT_MEM.get().unwrap();
+    let mem = &ctx.mem_latent;
     let dummy_vec = vec![0.0; 1536];
     let lock = mem.lock().await;
     match lock.query(dummy_vec).await {
@@ -113,66 +562,284 @@ pub async fn query_latent(
     }
 }
 
+/// Set on chat responses whose memory layers could not be written yet,
+/// e.g. `latent,semantic` while the embedding service is down.
+pub const DEGRADED_HEADER: &str = "x-icore-degraded";
+
+/// Writes a chat's input and output to long-term memory and queues
+/// `tasks`, all or nothing.
+async fn store_exchange(
+    ctx: &Context,
+    timestamp: i64,
+    input: &str,
+    output: &str,
+    tasks: &[Task],
+) -> Result<(), String> {
+    let mut tx = ctx.mem_long.begin().await?;
+    for (key, text) in [("input", input), ("output", output)] {
+        ctx.mem_long
+            .set_value_in(
+                &mut tx,
+                &format!("{}_{}", key, timestamp),
+                &Value::String(text.to_string()),
+            )
+            .await
+            .ok_or_else(|| format!("failed to write {}_{}", key, timestamp))?;
+    }
+    ctx.commit_with(tx, tasks).await
+}
+
 #[derive(Deserialize)]
 pub struct ChatPayload {
     message: String,
+    session: Option<String>,
+    agent: Option<String>,
 }
 
 #[debug_handler]
-pub async fn agent_get_short(Path(key): Path<String>) -> impl IntoResponse {
-    let agent = AGENT.get().unwrap().lock().await;
-    match agent.get_short(&key) {
-        Some(val) => (StatusCode::OK, val),
-        None => (StatusCode::NOT_FOUND, "key not found".to_string()),
-    }
+pub async fn default_agent_list_mem(
+    ctx: Context,
+    Path(scope): Path<MemScope>,
+    query: Query<ListQuery>,
+) -> Response {
+    list_agent_mem(
+        ctx,
+        Path((registry().default_name().to_string(), scope)),
+        query,
+    )
+    .await
 }
 
 #[debug_handler]
-pub async fn agent_get_long(Path(key): Path<String>) -> impl IntoResponse {
-    let agent = AGENT.get().unwrap().lock().await;
-    match agent.get_long(&key) {
-        Some(val) => (StatusCode::OK, val),
-        None => (StatusCode::NOT_FOUND, "key not found".to_string()),
+pub async fn default_agent_get_mem(
+    ctx: Context,
+    Path((scope, key)): Path<(MemScope, String)>,
+) -> Response {
+    get_agent_mem(
+        ctx,
+        Path((registry().default_name().to_string(), scope, key)),
+    )
+    .await
+}
+
+#[debug_handler]
+pub async fn default_agent_set_mem(
+    ctx: Context,
+    Path((scope, key)): Path<(MemScope, String)>,
+    payload: Json<SetValue>,
+) -> Response {
+    set_agent_mem(
+        ctx,
+        Path((registry().default_name().to_string(), scope, key)),
+        payload,
+    )
+    .await
+}
+
+#[debug_handler]
+pub async fn default_agent_delete_mem(
+    ctx: Context,
+    Path((scope, key)): Path<(MemScope, String)>,
+) -> Response {
+    delete_agent_mem(
+        ctx,
+        Path((registry().default_name().to_string(), scope, key)),
+    )
+    .await
+}
+
+fn agent_not_found() -> Response {
+    (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response()
+}
+
+#[debug_handler]
+pub async fn list_agent_mem(
+    ctx: Context,
+    Path((name, scope)): Path<(String, MemScope)>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    let Some(entry) = registry().get(&name) else {
+        return agent_not_found();
+    };
+    let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    Json(paginate(agent.all_mem(&ctx.namespace, scope), &query)).into_response()
+}
+
+#[debug_handler]
+pub async fn clear_agent_mem(
+    ctx: Context,
+    Path((name, scope)): Path<(String, MemScope)>,
+) -> Response {
+    let Some(entry) = registry().get(&name) else {
+        return agent_not_found();
+    };
+    telemetry::lock_agent("sentience", &entry.agent)
+        .await
+        .clear_mem(&ctx.namespace, scope);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[debug_handler]
+pub async fn get_agent_mem(
+    ctx: Context,
+    Path((name, scope, key)): Path<(String, MemScope, String)>,
+) -> Response {
+    let Some(entry) = registry().get(&name) else {
+        return agent_not_found();
+    };
+    let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    match agent.get_mem(&ctx.namespace, scope, &key) {
+        Some(val) => (StatusCode::OK, val).into_response(),
+        None if key == LEGACY_ALL_KEY => legacy_listing(agent.all_mem(&ctx.namespace, scope)),
+        None => (StatusCode::NOT_FOUND, "key not found".to_string()).into_response(),
     }
 }
 
 #[debug_handler]
-pub async fn agent_all_short() -> impl IntoResponse {
-    let agent = AGENT.get().unwrap().lock().await;
-    Json(agent.all_short().unwrap_or_default())
+pub async fn set_agent_mem(
+    ctx: Context,
+    Path((name, scope, key)): Path<(String, MemScope, String)>,
+    Json(payload): Json<SetValue>,
+) -> Response {
+    let Some(entry) = registry().get(&name) else {
+        return agent_not_found();
+    };
+    telemetry::lock_agent("sentience", &entry.agent)
+        .await
+        .set_mem(&ctx.namespace, scope, key, payload.value);
+    (StatusCode::OK, "stored").into_response()
 }
 
 #[debug_handler]
-pub async fn agent_all_long() -> impl IntoResponse {
-    let agent = AGENT.get().unwrap().lock().await;
-    Json(agent.all_long().unwrap_or_default())
+pub async fn delete_agent_mem(
+    ctx: Context,
+    Path((name, scope, key)): Path<(String, MemScope, String)>,
+) -> Response {
+    let Some(entry) = registry().get(&name) else {
+        return agent_not_found();
+    };
+    if telemetry::lock_agent("sentience", &entry.agent)
+        .await
+        .delete_mem(&ctx.namespace, scope, &key)
+    {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "key not found".to_string()).into_response()
+    }
+}
+
+/// Loads the namespace's short- and long-term memory into the agent's
+/// memory for that namespace.
+#[debug_handler]
+pub async fn seed_agent_mem(ctx: Context, Path(name): Path<String>) -> Response {
+    let Some(entry) = registry().get(&name) else {
+        return agent_not_found();
+    };
+    let (short, long) = telemetry::lock_agent("sentience", &entry.agent)
+        .await
+        .seed_from_global(&ctx)
+        .await;
+    Json(json!({ "short": short, "long": long })).into_response()
 }
 
 #[derive(Deserialize)]
 pub struct SentienceRequest {
     pub code: String,
+    /// Run against a copy of this agent's program and memory instead of an
+    /// empty context.
+    pub agent: Option<String>,
+    /// Passed to the program's `on input` block, if it declares one.
+    pub input: Option<String>,
+    /// Keep memory writes: apply them to the agent and to global memory.
+    #[serde(default)]
+    pub commit: bool,
 }
 
 #[derive(Serialize)]
 pub struct SentienceResponse {
-    pub output: String,
+    #[serde(flatten)]
+    pub outcome: RunOutcome,
+    pub committed: bool,
 }
 
 #[debug_handler]
 pub async fn sentience_run_handler(
+    ctx: Context,
     Json(payload): Json<SentienceRequest>,
-) -> axum::Json<SentienceResponse> {
-    let agent_lock = AGENT.get().unwrap().clone();
-    let mut agent = agent_lock.lock().await;
+) -> Response {
+    let agent_lock = match payload.agent.as_deref() {
+        Some(name) => match registry().resolve(Some(name)) {
+            Some(agent) => Some(agent),
+            None => return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response(),
+        },
+        None => None,
+    };
+    let mut agent = match &agent_lock {
+        Some(lock) => Some(telemetry::lock_agent("sentience", lock).await),
+        None => None,
+    };
 
-    let mut ctx = Context::new();
-    let output = agent.handle(&payload.code, &mut ctx).await;
-    agent.flush_to_global_short(&mut ctx);
-    agent.flush_to_global_long(&ctx).await;
+    let (scratch, limits) = match agent.as_ref() {
+        Some(a) => (a.scratch_context(&ctx.namespace), a.limits),
+        None => (AgentContext::new(), registry().default_limits()),
+    };
+    let started = Instant::now();
+    let (outcome, scratch) = run::execute(&payload.code, scratch, payload.input, limits).await;
+    telemetry::observe_agent_eval(
+        agent.as_ref().map_or("scratch", |a| a.name.as_str()),
+        started,
+    );
 
-    axum::Json(SentienceResponse {
-        output: output.unwrap_or_else(|| "".to_string()),
-    })
+    let committed = payload.commit && outcome.succeeded();
+    if committed && !(outcome.short.is_empty() && outcome.long.is_empty()) {
+        let long = outcome
+            .long
+            .writes()
+            .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+            .collect::<Vec<_>>();
+        if let Err(e) = ctx.write_long(&long).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+        if let Err(e) = ctx.delete_long(&outcome.long.removed).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+        }
+        for (k, v) in outcome.short.writes() {
+            ctx.set_short(k, v);
+        }
+        for k in &outcome.short.removed {
+            ctx.mem_short.delete(k);
+        }
+        if let (Some(agent), Some(scratch)) = (agent.as_mut(), scratch) {
+            agent.adopt_memory(&ctx.namespace, scratch);
+        }
+    }
+
+    Json(SentienceResponse { outcome, committed }).into_response()
+}
+
+/// Maps a sandbox violation to a structured error response.
+fn sandbox_error(e: SandboxError) -> Response {
+    let status = match e {
+        SandboxError::Panic { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::UNPROCESSABLE_ENTITY,
+    };
+    let message = e.to_string();
+    (status, Json(json!({ "error": e, "message": message }))).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ValidatePayload {
+    pub code: String,
+}
+
+/// Lints and parses DSL source without loading it.
+#[debug_handler]
+pub async fn sentience_validate(Json(payload): Json<ValidatePayload>) -> impl IntoResponse {
+    let diagnostics = BaseAgent::validate(&payload.code, registry().default_limits()).await;
+    Json(json!({
+        "valid": !diagnostics.iter().any(Diagnostic::is_error),
+        "diagnostics": diagnostics,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -182,75 +849,985 @@ pub struct SemanticPayload {
 }
 
 #[axum::debug_handler]
-pub async fn embed_semantic(Json(payload): Json<SemanticPayload>) -> impl IntoResponse {
-    let mut graph = SEMANTIC_GRAPH.lock().unwrap();
-    let cluster = ObjectCluster {
-        name: payload.id.clone(),
+pub async fn embed_semantic(
+    ctx: Context,
+    Json(payload): Json<SemanticPayload>,
+) -> impl IntoResponse {
+    let task = Task::Embedding {
+        id: payload.id,
         embedding: payload.embedding,
-        tags: vec![],
-        affect: AffectScore::from_value(0.0),
-        known: false,
     };
-    graph.add_cluster(payload.id.clone(), cluster);
-    (StatusCode::OK, Json(json!({ "status": "ok" })))
+    match outbox::enqueue(&ctx.namespace, &task).await {
+        Ok(job) => (
+            StatusCode::ACCEPTED,
+            Json(json!({ "status": "queued", "job": job })),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
 }
 
 #[axum::debug_handler]
-pub async fn reflect_semantic(Path(id): Path<String>) -> impl IntoResponse {
-    let graph = SEMANTIC_GRAPH.lock().unwrap();
-    let result = reflect(&graph, &id);
+pub async fn reflect_semantic(
+    ctx: Context,
+    Path(id): Path<String>,
+    Query(query): Query<ReflectQuery>,
+) -> impl IntoResponse {
+    let graph = ctx.mem_semantic.lock().unwrap();
+    let result = reflect(&graph, &id, query.k.unwrap_or(5));
     (StatusCode::OK, Json(result))
 }
 
+#[derive(Deserialize)]
+pub struct ReflectQuery {
+    pub k: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct ClusterFilter {
+    pub known: Option<bool>,
+    pub tag: Option<String>,
+    pub affect: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ClusterSummary {
+    pub id: String,
+    pub name: String,
+    pub tags: Vec<String>,
+    pub affect: AffectScore,
+    pub known: bool,
+}
+
 #[debug_handler]
-pub async fn chat(Json(payload): Json<ChatPayload>) -> axum::Json<String> {
-    let input = payload.message.trim();
-    let timestamp = chrono::Utc::now().timestamp_millis();
-    let id = format!("chat_{}", timestamp);
+pub async fn list_clusters(ctx: Context, Query(filter): Query<ClusterFilter>) -> impl IntoResponse {
+    let affect = match filter.affect.as_deref().map(Affect::parse) {
+        Some(None) => {
+            return (StatusCode::BAD_REQUEST, "unknown affect class".to_string()).into_response();
+        }
+        Some(class) => class,
+        None => None,
+    };
+
+    let graph = ctx.mem_semantic.lock().unwrap();
+    let mut clusters = graph
+        .clusters
+        .iter()
+        .filter(|(_, c)| filter.known.is_none_or(|known| c.known == known))
+        .filter(|(_, c)| filter.tag.as_ref().is_none_or(|tag| c.tags.contains(tag)))
+        .filter(|(_, c)| affect.as_ref().is_none_or(|class| &c.affect.class == class))
+        .map(|(id, c)| ClusterSummary {
+            id: id.clone(),
+            name: c.name.clone(),
+            tags: c.tags.clone(),
+            affect: c.affect.clone(),
+            known: c.known,
+        })
+        .collect::<Vec<_>>();
+    clusters.sort_by(|a, b| a.id.cmp(&b.id));
+    Json(clusters).into_response()
+}
 
-    let agent_lock = AGENT.get().unwrap().clone();
-    let mut agent = agent_lock.lock().await;
-    let mut ctx = Context::new();
+#[debug_handler]
+pub async fn get_cluster(ctx: Context, Path(id): Path<String>) -> impl IntoResponse {
+    let graph = ctx.mem_semantic.lock().unwrap();
+    match graph.clusters.get(&id) {
+        Some(cluster) => Json(ClusterRecord {
+            id,
+            cluster: cluster.clone(),
+        })
+        .into_response(),
+        None => (StatusCode::NOT_FOUND, "cluster not found".to_string()).into_response(),
+    }
+}
 
-    // 1. Embed input (llama.cpp)
-    let embed_vec = match embed_text(input).await {
-        Ok(vec) => vec,
-        Err(e) => {
-            eprintln!("Embedding failed: {}", e);
-            vec![0.0; 1536]
+#[derive(Deserialize)]
+pub struct ClusterPatch {
+    pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub add_tags: Vec<String>,
+    #[serde(default)]
+    pub remove_tags: Vec<String>,
+    pub affect: Option<f32>,
+    pub affect_delta: Option<f32>,
+    pub known: Option<bool>,
+}
+
+#[debug_handler]
+pub async fn patch_cluster(
+    ctx: Context,
+    Path(id): Path<String>,
+    Json(patch): Json<ClusterPatch>,
+) -> impl IntoResponse {
+    let mut graph = ctx.mem_semantic.lock().unwrap();
+    let Some(cluster) = graph.clusters.get_mut(&id) else {
+        return (StatusCode::NOT_FOUND, "cluster not found".to_string()).into_response();
+    };
+
+    if let Some(tags) = patch.tags {
+        cluster.tags.clear();
+        for tag in &tags {
+            cluster.add_tag(tag);
         }
+    }
+    for tag in &patch.add_tags {
+        cluster.add_tag(tag);
+    }
+    for tag in &patch.remove_tags {
+        cluster.remove_tag(tag);
+    }
+    if let Some(value) = patch.affect {
+        cluster.affect = AffectScore::from_value(value);
+    }
+    if let Some(delta) = patch.affect_delta {
+        cluster.update_affect(delta);
+    }
+    if let Some(known) = patch.known {
+        cluster.known = known;
+    }
+
+    Json(ClusterRecord {
+        id,
+        cluster: cluster.clone(),
+    })
+    .into_response()
+}
+
+#[debug_handler]
+pub async fn delete_cluster(ctx: Context, Path(id): Path<String>) -> impl IntoResponse {
+    let mut graph = ctx.mem_semantic.lock().unwrap();
+    match graph.remove_cluster(&id) {
+        Some(_) => (StatusCode::OK, "deleted".to_string()),
+        None => (StatusCode::NOT_FOUND, "cluster not found".to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MergePayload {
+    pub source: String,
+}
+
+#[debug_handler]
+pub async fn merge_cluster(
+    ctx: Context,
+    Path(id): Path<String>,
+    Json(payload): Json<MergePayload>,
+) -> impl IntoResponse {
+    let mut graph = ctx.mem_semantic.lock().unwrap();
+    if let Err(e) = graph.merge_clusters(&id, &payload.source) {
+        let status =
+            if graph.clusters.contains_key(&id) && graph.clusters.contains_key(&payload.source) {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::NOT_FOUND
+            };
+        return (status, e).into_response();
+    }
+    let cluster = graph.clusters[&id].clone();
+    Json(ClusterRecord { id, cluster }).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct GraphFormatQuery {
+    pub format: Option<String>,
+    pub mode: Option<String>,
+}
+
+fn graph_format(query: &GraphFormatQuery) -> Result<GraphFormat, (StatusCode, String)> {
+    let raw = query.format.as_deref().unwrap_or("json");
+    GraphFormat::parse(raw).ok_or((
+        StatusCode::BAD_REQUEST,
+        format!("unsupported format: {}", raw),
+    ))
+}
+
+#[debug_handler]
+pub async fn export_semantic(
+    ctx: Context,
+    Query(query): Query<GraphFormatQuery>,
+) -> impl IntoResponse {
+    let format = match graph_format(&query) {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
     };
+    let body = {
+        let graph = ctx.mem_semantic.lock().unwrap();
+        export::export(&graph, format)
+    };
+    ([(header::CONTENT_TYPE, format.content_type())], body).into_response()
+}
 
-    let _ = ctx.embed_latent(&id, embed_vec.clone()).await;
+#[debug_handler]
+pub async fn import_semantic(
+    ctx: Context,
+    Query(query): Query<GraphFormatQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let format = match graph_format(&query) {
+        Ok(format) => format,
+        Err(e) => return e.into_response(),
+    };
+    let snapshot = match export::import(format, &body) {
+        Ok(snapshot) => snapshot,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
 
-    // 2. Semantic cluster
-    {
-        let mut graph = SEMANTIC_GRAPH.lock().unwrap();
-        let cluster = ObjectCluster {
-            name: id.clone(),
-            embedding: embed_vec,
-            tags: vec!["chat_input".to_string()],
-            affect: AffectScore::from_value(0.0),
-            known: true,
+    let mut graph = ctx.mem_semantic.lock().unwrap();
+    if query.mode.as_deref() == Some("replace") {
+        *graph = LatentGraph::new();
+    }
+    let summary = snapshot.apply(&mut graph);
+    (StatusCode::OK, Json(summary)).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ConceptRequest {
+    #[serde(default = "default_dry_run")]
+    pub dry_run: bool,
+    #[serde(flatten)]
+    pub options: ConceptOptions,
+}
+
+fn default_dry_run() -> bool {
+    true
+}
+
+/// Runs concept clustering over the semantic graph. With `dry_run` (the
+/// default) the proposals are only returned; otherwise they are added as
+/// parent concept clusters linked by `is_a` relations.
+#[debug_handler]
+pub async fn cluster_concepts(
+    ctx: Context,
+    Json(payload): Json<ConceptRequest>,
+) -> impl IntoResponse {
+    let dry_run = payload.dry_run;
+    let prefix = format!("concept_{}", chrono::Utc::now().timestamp_millis());
+    // Clustering runs on a copy; the graph is locked again only to add the
+    // concepts, which skips members removed in the meantime.
+    let clusters = concepts::candidates(&ctx.mem_semantic.lock().unwrap());
+    let result = tokio::task::spawn_blocking(move || {
+        let proposals = concepts::propose(&clusters, &payload.options);
+        let created = if dry_run {
+            vec![]
+        } else {
+            let mut graph = ctx.mem_semantic.lock().unwrap();
+            concepts::materialize(&mut graph, &proposals, &prefix)
         };
-        graph.add_cluster(id.clone(), cluster);
+        (proposals, created)
+    })
+    .await;
+
+    match result {
+        Ok((proposals, created)) => Json(json!({
+            "dry_run": dry_run,
+            "created": created,
+            "proposals": proposals,
+        }))
+        .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EpisodeQuery {
+    pub session: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[debug_handler]
+pub async fn record_episode_event(ctx: Context, Json(mut event): Json<Event>) -> impl IntoResponse {
+    if event.embedding.is_none() {
+        event.embedding = ctx.embed(&event.content).await.ok();
+    }
+    match ctx.mem_episodic.record(event).await {
+        Ok(event) => Json(event).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn list_episodes(ctx: Context, Query(query): Query<EpisodeQuery>) -> impl IntoResponse {
+    let episodes = ctx
+        .mem_episodic
+        .episodes(
+            query.session.as_deref(),
+            query.limit.unwrap_or(20).clamp(1, MAX_PAGE_SIZE) as i64,
+        )
+        .await;
+    Json(episodes)
+}
+
+#[debug_handler]
+pub async fn get_episode(ctx: Context, Path(id): Path<i64>) -> impl IntoResponse {
+    let mem = &ctx.mem_episodic;
+    match mem.episode(id).await {
+        Some(episode) => {
+            let events = mem.events(id).await;
+            Json(json!({ "episode": episode, "events": events })).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "episode not found".to_string()).into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn summarize_episode(ctx: Context, Path(id): Path<i64>) -> impl IntoResponse {
+    let mem = &ctx.mem_episodic;
+    if mem.episode(id).await.is_none() {
+        return (StatusCode::NOT_FOUND, "episode not found".to_string()).into_response();
+    }
+    let events = mem.events(id).await;
+    let summary = match model::generate(&episodic::summary_prompt(&events)).await {
+        Ok(summary) => summary,
+        Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    };
+    if let Err(e) = mem.set_summary(id, &summary).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+    }
+    Json(json!({ "id": id, "summary": summary })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct EpisodeRecallPayload {
+    pub query: String,
+    pub session: Option<String>,
+    pub k: Option<usize>,
+}
+
+/// Answers "what happened last time we talked about X": episodes ranked by
+/// similarity to the query, each with its summary and events.
+#[debug_handler]
+pub async fn recall_episodes(
+    ctx: Context,
+    Json(payload): Json<EpisodeRecallPayload>,
+) -> impl IntoResponse {
+    let embedding = match ctx.embed(&payload.query).await {
+        Ok(vec) => vec,
+        Err(e) => return (StatusCode::BAD_GATEWAY, e.to_string()).into_response(),
+    };
+    let mem = &ctx.mem_episodic;
+    let mut results = Vec::new();
+    for (episode, score) in mem
+        .recall(
+            &embedding,
+            payload.session.as_deref(),
+            payload.k.unwrap_or(3),
+        )
+        .await
+    {
+        let events = mem.events(episode.id).await;
+        results.push(json!({ "episode": episode, "score": score, "events": events }));
+    }
+    Json(results).into_response()
+}
+
+#[debug_handler]
+pub async fn list_agents() -> impl IntoResponse {
+    let registry = registry();
+    let mut agents = Vec::new();
+    for name in registry.names() {
+        if let Some(info) = registry.info(&name).await {
+            agents.push(info);
+        }
+    }
+    Json(agents)
+}
+
+#[debug_handler]
+pub async fn get_agent(Path(name): Path<String>) -> impl IntoResponse {
+    match registry().info(&name).await {
+        Some(info) => Json(info).into_response(),
+        None => (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SourceQuery {
+    #[serde(default)]
+    pub persist: bool,
+}
+
+/// Replaces an agent's DSL with the request body. The new program is
+/// compiled before the swap, so a bad upload leaves the old one running.
+/// Agents are shared by every namespace, so this is an admin endpoint.
+#[debug_handler]
+pub async fn put_agent_source(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Query(query): Query<SourceQuery>,
+    code: String,
+) -> impl IntoResponse {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
     }
+    let registry = registry();
+    if registry.get(&name).is_none() {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    }
+    let version = match registry.reload(&name, &code).await {
+        Ok(v) => v,
+        Err(e) => return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    };
+    if query.persist
+        && let Err(e) = registry.persist(&name, &code)
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+    }
+    Json(json!({ "name": name, "version": version, "persisted": query.persist })).into_response()
+}
+
+#[debug_handler]
+pub async fn list_agent_versions(Path(name): Path<String>) -> impl IntoResponse {
+    let Some(entry) = registry().get(&name) else {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    };
+    let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    Json(agent.versions().to_vec()).into_response()
+}
+
+#[debug_handler]
+pub async fn get_agent_limits(Path(name): Path<String>) -> impl IntoResponse {
+    let Some(entry) = registry().get(&name) else {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    };
+    let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    Json(agent.limits).into_response()
+}
 
-    // 3. Handle input
-    let response = agent.handle(input, &mut ctx).await;
+/// Changes an agent's sandbox limits. Admin only, since the limits are what
+/// keep agent code from hogging the server.
+#[debug_handler]
+pub async fn patch_agent_limits(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(patch): Json<LimitsPatch>,
+) -> impl IntoResponse {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let Some(entry) = registry().get(&name) else {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    };
+    let mut agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    patch.apply(&mut agent.limits);
+    Json(agent.limits).into_response()
+}
+
+#[debug_handler]
+pub async fn get_agent_tools(ctx: Context, Path(name): Path<String>) -> impl IntoResponse {
+    let Some(entry) = registry().get(&name) else {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    };
+    let agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    Json(json!({
+        "allowed": agent.allowed_tools(),
+        "recent": agent.tool_log(&ctx.namespace),
+    }))
+    .into_response()
+}
+
+/// Replaces the set of tools the agent may call, in every namespace.
+#[debug_handler]
+pub async fn put_agent_tools(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(tools): Json<Vec<Tool>>,
+) -> impl IntoResponse {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let Some(entry) = registry().get(&name) else {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    };
+    let mut agent = telemetry::lock_agent("sentience", &entry.agent).await;
+    agent.tools = tools.into_iter().collect();
+    tracing::info!("agent '{}' tools set to {:?}", name, agent.allowed_tools());
+    Json(agent.allowed_tools()).into_response()
+}
+
+/// Recent step traces of a ReAct agent, newest first.
+#[debug_handler]
+pub async fn get_agent_traces(ctx: Context, Path(name): Path<String>) -> impl IntoResponse {
+    let Some(agent) = registry().react(&name) else {
+        return (StatusCode::NOT_FOUND, "ReAct agent not found".to_string()).into_response();
+    };
+    let agent = telemetry::lock_agent("react", &agent).await;
+    Json(agent.traces(&ctx.namespace).collect::<Vec<_>>()).into_response()
+}
+
+#[derive(Deserialize, Default)]
+pub struct RollbackPayload {
+    /// Version to restore; the one before the current version if omitted.
+    pub version: Option<u32>,
+}
+
+#[debug_handler]
+pub async fn rollback_agent(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    payload: Option<Json<RollbackPayload>>,
+) -> impl IntoResponse {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let registry = registry();
+    if registry.get(&name).is_none() {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    }
+    let version = payload.and_then(|Json(p)| p.version);
+    match registry.rollback(&name, version).await {
+        Ok(v) => Json(json!({ "name": name, "version": v })).into_response(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn chat(mut ctx: Context, Json(payload): Json<ChatPayload>) -> Response {
+    let input = payload.message.trim();
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    let id = format!("chat_{}", timestamp);
+
+    let Some(agent_ref) = registry().lookup(payload.agent.as_deref()) else {
+        return (StatusCode::NOT_FOUND, "agent not found".to_string()).into_response();
+    };
+
+    // 1. Embed input (llama.cpp) for the episode. Without a vector the
+    // indexing queued in step 5 waits for the embedding service to return.
+    let mut degraded = Vec::new();
+    let embed_vec = match ctx.embed(input).await {
+        Ok(vec) => Some(vec),
+        Err(e) => {
+            tracing::warn!("embedding failed, indexing deferred: {}", e);
+            degraded.extend(["latent", "semantic"]);
+            None
+        }
+    };
+
+    // 2. Handle input
+    let (agent_name, response) = match &agent_ref {
+        AgentRef::Sentience(lock) => {
+            let mut agent = telemetry::lock_agent("sentience", lock).await;
+            let started = Instant::now();
+            let result = agent.handle(input, &mut ctx).await;
+            telemetry::observe_agent_eval(&agent.name, started);
+            match result {
+                Ok(response) => (agent.name.clone(), response),
+                Err(e) => return sandbox_error(e),
+            }
+        }
+        AgentRef::React(lock) => {
+            let mut agent = telemetry::lock_agent("react", lock).await;
+            let started = Instant::now();
+            let result = agent.handle(input, &ctx).await;
+            telemetry::observe_agent_eval(&agent.name, started);
+            match result {
+                Ok(answer) => (agent.name.clone(), Some(answer)),
+                Err(e) => {
+                    tracing::warn!("react agent '{}' failed: {}", agent.name, e);
+                    return (StatusCode::BAD_GATEWAY, e).into_response();
+                }
+            }
+        }
+    };
     let output = response
         .clone()
         .unwrap_or_else(|| "No response.".to_string());
 
+    // 3. Episodic chain
+    let session = payload.session.as_deref().unwrap_or("default");
+    let affect = SemanticEvaluator::calculate_affect(&SemanticEvaluator::assign_tags(input));
+    let events = [
+        Event {
+            id: 0,
+            episode_id: 0,
+            session: session.to_string(),
+            timestamp,
+            participants: vec!["user".to_string(), agent_name.clone()],
+            content: input.to_string(),
+            clusters: vec![id.clone()],
+            affect: affect.value,
+            embedding: embed_vec,
+        },
+        Event {
+            id: 0,
+            episode_id: 0,
+            session: session.to_string(),
+            timestamp,
+            participants: vec![agent_name, "user".to_string()],
+            content: output.clone(),
+            clusters: vec![],
+            affect: 0.0,
+            embedding: None,
+        },
+    ];
+    for event in events {
+        if let Err(e) = ctx.mem_episodic.record(event).await {
+            tracing::warn!("failed to record episode event: {}", e);
+        }
+    }
+
     // 4. Persist to memory
     ctx.set_short("last_input", input);
     ctx.set_short("last_output", &output);
-    ctx.set_long(&format!("input_{}", timestamp), input).await;
-    ctx.set_long(&format!("output_{}", timestamp), &output)
-        .await;
 
-    agent.flush_to_global_short(&mut ctx);
-    agent.flush_to_global_long(&ctx).await;
+    // 5. Store the exchange and queue the input's vector and semantic
+    // cluster in one transaction. The outbox worker writes them, so a
+    // Chroma failure cannot leave the layers half-written.
+    let tasks = [
+        Task::Vector {
+            id: id.clone(),
+            text: input.to_string(),
+        },
+        Task::Cluster {
+            id: id.clone(),
+            text: input.to_string(),
+        },
+    ];
+    if let Err(e) = store_exchange(&ctx, timestamp, input, &output, &tasks).await {
+        tracing::error!("failed to store chat '{}': {}", id, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, e).into_response();
+    }
+
+    if degraded.is_empty() {
+        Json(output).into_response()
+    } else {
+        ([(DEGRADED_HEADER, degraded.join(","))], Json(output)).into_response()
+    }
+}
+
+#[debug_handler]
+pub async fn list_namespaces(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    Json(namespaces().list().await).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct NamespacePayload {
+    pub name: String,
+}
+
+#[debug_handler]
+pub async fn create_namespace(
+    headers: HeaderMap,
+    Json(payload): Json<NamespacePayload>,
+) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match namespaces().create(&payload.name).await {
+        Ok(()) => (StatusCode::CREATED, Json(json!({ "name": payload.name }))).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// Deletes a namespace with all of its memory.
+#[debug_handler]
+pub async fn delete_namespace(headers: HeaderMap, Path(name): Path<String>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match namespaces().delete(&name).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// Streams a `.tar.gz` archive of every memory layer and agent source.
+#[debug_handler]
+pub async fn create_backup(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match backup::create().await {
+        Ok(archive) => {
+            let file = format!(
+                "attachment; filename=\"icore-backup-{}.tar.gz\"",
+                chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+            );
+            (
+                [
+                    (header::CONTENT_TYPE, "application/gzip".to_string()),
+                    (header::CONTENT_DISPOSITION, file),
+                ],
+                archive,
+            )
+                .into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// Replaces all memory with a backup archive sent as the request body.
+#[debug_handler]
+pub async fn restore_backup(headers: HeaderMap, body: Bytes) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match backup::restore(body).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub namespace: Option<String>,
+    /// Comma-separated layers; all when absent.
+    pub layers: Option<String>,
+}
+
+/// Streams memory records as JSONL.
+#[debug_handler]
+pub async fn export_memories(headers: HeaderMap, Query(query): Query<ExportQuery>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let layers = match interchange::Layer::parse_list(query.layers.as_deref()) {
+        Ok(layers) => layers,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let names = match query.namespace {
+        Some(name) => {
+            if let Err(e) = namespaces().context(&name).await {
+                return (e.status(), e.to_string()).into_response();
+            }
+            vec![name]
+        }
+        None => namespaces()
+            .list()
+            .await
+            .into_iter()
+            .map(|ns| ns.name)
+            .collect(),
+    };
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(interchange::export(names, layers)),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    /// Recompute vector and cluster embeddings with the current model.
+    #[serde(default)]
+    pub reembed: bool,
+}
+
+/// Reads JSONL memory records from the request body as it streams in.
+#[debug_handler]
+pub async fn import_memories(
+    headers: HeaderMap,
+    Query(query): Query<ImportQuery>,
+    body: Body,
+) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match interchange::import(body.into_data_stream(), query.reembed).await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn list_reembed_jobs(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    Json(reembed::list().await).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ReembedPayload {
+    pub namespace: Option<String>,
+    pub model: Option<String>,
+}
+
+/// Starts rebuilding a namespace's vectors with another embedding model.
+#[debug_handler]
+pub async fn start_reembed_job(
+    headers: HeaderMap,
+    Json(payload): Json<ReembedPayload>,
+) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let namespace = payload.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+    match reembed::start(namespace, payload.model).await {
+        Ok(job) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn get_reembed_job(headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match reembed::get(id).await {
+        Some(job) => Json(job).into_response(),
+        None => (StatusCode::NOT_FOUND, "re-embedding job not found").into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn resume_reembed_job(headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match reembed::resume(id).await {
+        Ok(job) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
 
-    axum::Json(output)
+#[derive(Deserialize)]
+pub struct JobQuery {
+    pub status: Option<String>,
+    pub kind: Option<String>,
+    pub namespace: Option<String>,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// Outbox jobs, oldest first, with the queue's counts by status.
+#[debug_handler]
+pub async fn list_outbox_jobs(headers: HeaderMap, Query(query): Query<JobQuery>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let limit = page_size(query.limit);
+    let (items, total) = outbox::list(
+        query.status.as_deref(),
+        query.kind.as_deref(),
+        query.namespace.as_deref(),
+        query.offset,
+        limit,
+    )
+    .await;
+    Json(json!({
+        "stats": outbox::stats().await,
+        "items": items,
+        "total": total,
+        "offset": query.offset,
+        "limit": limit,
+    }))
+    .into_response()
+}
+
+#[debug_handler]
+pub async fn get_outbox_job(headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match outbox::get(id).await {
+        Some(job) => Json(job).into_response(),
+        None => (StatusCode::NOT_FOUND, "job not found").into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn delete_outbox_job(headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match outbox::delete(id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// Runs a pending or dead job again right away.
+#[debug_handler]
+pub async fn replay_outbox_job(headers: HeaderMap, Path(id): Path<i64>) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match outbox::replay(id).await {
+        Ok(job) => (StatusCode::ACCEPTED, Json(job)).into_response(),
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+/// Replays every dead-lettered job.
+#[debug_handler]
+pub async fn replay_dead_outbox_jobs(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match outbox::replay_dead().await {
+        Ok(replayed) => {
+            (StatusCode::ACCEPTED, Json(json!({ "replayed": replayed }))).into_response()
+        }
+        Err(e) => (e.status(), e.to_string()).into_response(),
+    }
+}
+
+fn embed_cache() -> Result<&'static embed_cache::EmbedCache, (StatusCode, &'static str)> {
+    embed_cache::cache().ok_or((StatusCode::NOT_FOUND, "embedding cache is disabled"))
+}
+
+#[debug_handler]
+pub async fn embed_cache_stats(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match embed_cache() {
+        Ok(cache) => Json(cache.stats()).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[debug_handler]
+pub async fn clear_embed_cache(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    match embed_cache() {
+        Ok(cache) => {
+            cache.clear().await;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WarmupPayload {
+    pub namespace: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Loads embeddings of stored vectors into the cache.
+#[debug_handler]
+pub async fn warmup_embed_cache(
+    headers: HeaderMap,
+    payload: Option<Json<WarmupPayload>>,
+) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let cache = match embed_cache() {
+        Ok(cache) => cache,
+        Err(e) => return e.into_response(),
+    };
+    let payload = payload.map(|Json(p)| p);
+    let namespace = payload
+        .as_ref()
+        .and_then(|p| p.namespace.clone())
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+    let limit = payload
+        .and_then(|p| p.limit)
+        .unwrap_or_else(|| cache.stats().capacity);
+    let ctx = match namespaces().context(&namespace).await {
+        Ok(ctx) => ctx,
+        Err(e) => return (e.status(), e.to_string()).into_response(),
+    };
+    match cache.warmup(&ctx, limit).await {
+        Ok(added) => Json(json!({ "namespace": namespace, "added": added })).into_response(),
+        Err(e) => (StatusCode::BAD_GATEWAY, e).into_response(),
+    }
 }
@@ -1,23 +1,142 @@
 use crate::api::handlers;
+use crate::icore::backup;
 use axum::{
     Router,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post, put},
 };
 
 pub fn routes() -> Router {
     Router::new()
+        .merge(legacy_routes())
         .route("/ping", get(handlers::ping))
-        .route("/mem/short/:key", get(handlers::get_short_mem))
-        .route("/mem/short/:key", post(handlers::set_short_mem))
-        .route("/mem/short/all", get(handlers::get_all_short_mem))
-        .route("/mem/long/:key", get(handlers::get_long_mem))
-        .route("/mem/long/:key", post(handlers::set_long_mem))
+        .route("/mem/short", get(handlers::list_short_mem))
+        .route(
+            "/mem/short/:key",
+            get(handlers::get_short_mem)
+                .put(handlers::set_short_mem)
+                .patch(handlers::patch_short_mem)
+                .delete(handlers::delete_short_mem),
+        )
+        .route(
+            "/mem/long",
+            get(handlers::list_long_mem)
+                .post(handlers::bulk_set_long_mem)
+                .delete(handlers::bulk_delete_long_mem),
+        )
+        .route(
+            "/mem/long/:key",
+            get(handlers::get_long_mem)
+                .put(handlers::set_long_mem)
+                .patch(handlers::patch_long_mem)
+                .delete(handlers::delete_long_mem),
+        )
         .route("/mem/latent/embed", post(handlers::embed_latent))
         .route("/mem/latent/query", post(handlers::query_latent))
         .route("/chat", post(handlers::chat))
+        .route("/agents", get(handlers::list_agents))
+        .route("/agents/:name", get(handlers::get_agent))
+        .route("/agents/:name/source", put(handlers::put_agent_source))
+        .route("/agents/:name/versions", get(handlers::list_agent_versions))
+        .route("/agents/:name/rollback", post(handlers::rollback_agent))
+        .route("/agents/:name/traces", get(handlers::get_agent_traces))
+        .route(
+            "/agents/:name/mem/:scope",
+            get(handlers::list_agent_mem).delete(handlers::clear_agent_mem),
+        )
+        .route(
+            "/agents/:name/mem/:scope/:key",
+            get(handlers::get_agent_mem)
+                .put(handlers::set_agent_mem)
+                .delete(handlers::delete_agent_mem),
+        )
+        .route("/agents/:name/seed", post(handlers::seed_agent_mem))
+        .route(
+            "/agents/:name/limits",
+            get(handlers::get_agent_limits).patch(handlers::patch_agent_limits),
+        )
+        .route(
+            "/agents/:name/tools",
+            get(handlers::get_agent_tools).put(handlers::put_agent_tools),
+        )
+        .route(
+            "/admin/namespaces",
+            get(handlers::list_namespaces).post(handlers::create_namespace),
+        )
+        .route(
+            "/admin/namespaces/:name",
+            delete(handlers::delete_namespace),
+        )
+        .route(
+            "/admin/reembed",
+            get(handlers::list_reembed_jobs).post(handlers::start_reembed_job),
+        )
+        .route("/admin/reembed/:id", get(handlers::get_reembed_job))
+        .route(
+            "/admin/reembed/:id/resume",
+            post(handlers::resume_reembed_job),
+        )
+        .route("/admin/jobs", get(handlers::list_outbox_jobs))
+        .route(
+            "/admin/jobs/replay",
+            post(handlers::replay_dead_outbox_jobs),
+        )
+        .route(
+            "/admin/jobs/:id",
+            get(handlers::get_outbox_job).delete(handlers::delete_outbox_job),
+        )
+        .route("/admin/jobs/:id/replay", post(handlers::replay_outbox_job))
+        .route(
+            "/admin/embed-cache",
+            get(handlers::embed_cache_stats).delete(handlers::clear_embed_cache),
+        )
+        .route(
+            "/admin/embed-cache/warmup",
+            post(handlers::warmup_embed_cache),
+        )
+        .route("/admin/backup", post(handlers::create_backup))
+        .route("/admin/export", get(handlers::export_memories))
+        .route("/admin/import", post(handlers::import_memories))
+        .route(
+            "/admin/restore",
+            post(handlers::restore_backup).layer(DefaultBodyLimit::max(backup::MAX_ARCHIVE_BYTES)),
+        )
+        .route("/episodes", get(handlers::list_episodes))
+        .route("/episodes/events", post(handlers::record_episode_event))
+        .route("/episodes/recall", post(handlers::recall_episodes))
+        .route("/episodes/:id", get(handlers::get_episode))
+        .route("/episodes/:id/summarize", post(handlers::summarize_episode))
         .route("/sentience/run", post(handlers::sentience_run_handler))
-        .route("/agent/short/:key", get(handlers::agent_get_short))
-        .route("/agent/long/:key", get(handlers::agent_get_long))
-        .route("/agent/short/all", get(handlers::agent_all_short))
-        .route("/agent/long/all", get(handlers::agent_all_long))
+        .route("/sentience/validate", post(handlers::sentience_validate))
+        .route("/agent/:scope", get(handlers::default_agent_list_mem))
+        .route(
+            "/agent/:scope/:key",
+            get(handlers::default_agent_get_mem)
+                .put(handlers::default_agent_set_mem)
+                .delete(handlers::default_agent_delete_mem),
+        )
+        .route("/semantic/embed", post(handlers::embed_semantic))
+        .route("/semantic/reflect/:id", get(handlers::reflect_semantic))
+        .route("/semantic/clusters", get(handlers::list_clusters))
+        .route(
+            "/semantic/clusters/:id",
+            get(handlers::get_cluster)
+                .patch(handlers::patch_cluster)
+                .delete(handlers::delete_cluster),
+        )
+        .route(
+            "/semantic/clusters/:id/merge",
+            post(handlers::merge_cluster),
+        )
+        .route("/semantic/export", get(handlers::export_semantic))
+        .route("/admin/semantic/concepts", post(handlers::cluster_concepts))
+        .route("/semantic/import", post(handlers::import_semantic))
+}
+
+/// Paths kept for clients of the pre-collection memory API: writes via
+/// `POST`, and `GET .../all` (served by the `:key` handlers) for listings.
+fn legacy_routes() -> Router {
+    Router::new()
+        .route("/mem/short/:key", post(handlers::set_short_mem))
+        .route("/mem/long/:key", post(handlers::set_long_mem))
 }
@@ -1,4 +1,7 @@
+use crate::agents::sandbox::SandboxLimits;
+use crate::agents::tools::Tool;
 use serde::Deserialize;
+use std::collections::HashSet;
 use std::env;
 
 #[derive(Deserialize, Debug)]
@@ -6,14 +9,131 @@ pub struct Settings {
     pub env: String,
     pub chromadb_url: String,
     pub database_url: String,
+    pub episode_gap_secs: i64,
+    pub episode_topic_threshold: f32,
+    pub agents_dir: String,
+    pub default_agent: Option<String>,
+    pub agent_watch_interval_secs: u64,
+    pub agent_limits: SandboxLimits,
+    pub agent_tools: HashSet<Tool>,
+    /// HS256 secret for bearer tokens; namespaces are selected by header
+    /// alone when unset.
+    pub jwt_secret: Option<String>,
+    /// Embeddings kept in memory; `0` turns the cache off.
+    pub embed_cache_size: usize,
+    /// SQLite file for the persistent cache tier; memory only when unset.
+    pub embed_cache_db: Option<String>,
+    /// Rows kept in the persistent cache tier; `0` keeps every row.
+    pub embed_cache_db_rows: usize,
+    /// Failed runs before an outbox job is dead-lettered.
+    pub outbox_max_attempts: i64,
+    /// Retry delay after an outbox job's first failure; doubled after each
+    /// further one.
+    pub outbox_backoff_secs: i64,
+    /// How long `/health/ready` reuses its last probe.
+    pub health_cache_secs: u64,
 }
 
 impl Settings {
-    pub fn new() -> Self {
-        Self {
+    /// Reads the environment. Missing required variables panic; values
+    /// that are present but invalid are returned as an error.
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
             env: env::var("ICORE_ENV").unwrap_or_else(|_| "development".into()),
             chromadb_url: env::var("CHROMADB_URL").expect("CHROMADB_URL is not set"),
             database_url: env::var("DATABASE_URL").expect("DATABASE_URL is not set"),
+            episode_gap_secs: env::var("EPISODE_GAP_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1800),
+            episode_topic_threshold: env::var("EPISODE_TOPIC_THRESHOLD")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0.35),
+            agents_dir: env::var("AGENTS_DIR").unwrap_or_else(|_| ".".into()),
+            default_agent: env::var("DEFAULT_AGENT").ok(),
+            agent_watch_interval_secs: env::var("AGENT_WATCH_INTERVAL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(2),
+            agent_limits: agent_limits(),
+            agent_tools: agent_tools()?,
+            jwt_secret: env::var("JWT_SECRET").ok().filter(|s| !s.is_empty()),
+            embed_cache_size: env::var("EMBED_CACHE_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10_000),
+            embed_cache_db: env::var("EMBED_CACHE_DB").ok().filter(|s| !s.is_empty()),
+            embed_cache_db_rows: env::var("EMBED_CACHE_DB_ROWS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100_000),
+            outbox_max_attempts: env::var("OUTBOX_MAX_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(10),
+            outbox_backoff_secs: env::var("OUTBOX_BACKOFF_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|&secs| secs > 0)
+                .unwrap_or(5),
+            health_cache_secs: env::var("HEALTH_CACHE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(10),
+        })
+    }
+}
+
+/// How the `backup` and `restore` commands reach a running server.
+#[derive(Debug)]
+pub struct ClientSettings {
+    pub server_url: String,
+    /// Bearer token with the `admin` claim, needed when `JWT_SECRET` is set.
+    pub token: Option<String>,
+}
+
+impl ClientSettings {
+    pub fn new() -> Self {
+        Self {
+            server_url: env::var("ICORE_URL")
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_else(|_| "http://127.0.0.1:8080".into()),
+            token: env::var("ICORE_TOKEN").ok().filter(|s| !s.is_empty()),
         }
     }
 }
+
+/// Default sandbox limits for every agent; each can be changed per agent at
+/// runtime through the API.
+fn agent_limits() -> SandboxLimits {
+    fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+        env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    }
+    let d = SandboxLimits::default();
+    SandboxLimits {
+        timeout_ms: var("AGENT_TIMEOUT_MS", d.timeout_ms),
+        max_steps: var("AGENT_MAX_STEPS", d.max_steps),
+        max_output_bytes: var("AGENT_MAX_OUTPUT_BYTES", d.max_output_bytes),
+        max_memory_writes: var("AGENT_MAX_MEMORY_WRITES", d.max_memory_writes),
+    }
+}
+
+/// Tools granted to every agent, from the comma-separated `AGENT_TOOLS`
+/// (`*` for all). None by default.
+fn agent_tools() -> Result<HashSet<Tool>, String> {
+    match env::var("AGENT_TOOLS") {
+        Ok(list) if list.trim() == "*" => Ok(Tool::ALL.into_iter().collect()),
+        Ok(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(|t| Tool::parse(t).ok_or_else(|| format!("AGENT_TOOLS: unknown tool '{}'", t)))
+            .collect(),
+        Err(_) => Ok(HashSet::new()),
+    }
+}
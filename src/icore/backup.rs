@@ -0,0 +1,621 @@
+use crate::agents;
+use crate::icore::namespace::{DEFAULT_NAMESPACE, namespaces};
+use crate::memory::latent::VectorRecord;
+use crate::memory::long_term::DEFAULT_TABLE;
+use crate::memory::semantic::LatentGraph;
+use crate::memory::semantic::export::GraphSnapshot;
+use axum::body::Bytes;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::Row;
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::Sqlite;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+pub const FORMAT: &str = "icore-backup";
+pub const FORMAT_VERSION: u32 = 1;
+/// Largest archive the restore endpoint accepts, compressed or unpacked.
+pub const MAX_ARCHIVE_BYTES: usize = 512 * 1024 * 1024;
+
+const MANIFEST: &str = "manifest.json";
+const SQLITE: &str = "sqlite/icore.db";
+
+/// Backups and restores run one at a time.
+static LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Describes an archive; every other file in it must be listed here with
+/// its SHA-256.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: String,
+    pub version: u32,
+    pub created_at: String,
+    pub namespaces: Vec<String>,
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreSummary {
+    pub namespaces: Vec<String>,
+    pub tables: usize,
+    pub rows: u64,
+    pub vectors: usize,
+    pub short_term: usize,
+    pub clusters: usize,
+    pub relations: usize,
+    pub agents: usize,
+    /// Parts that could not be restored; everything else was.
+    pub warnings: Vec<String>,
+}
+
+fn vectors_path(namespace: &str) -> String {
+    format!("namespaces/{}/vectors.jsonl", namespace)
+}
+
+fn short_term_path(namespace: &str) -> String {
+    format!("namespaces/{}/short_term.json", namespace)
+}
+
+fn semantic_path(namespace: &str) -> String {
+    format!("namespaces/{}/semantic.json", namespace)
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+fn temp_db() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "icore-{}-{}.db",
+        std::process::id(),
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    ))
+}
+
+/// Builds a `.tar.gz` archive of every memory layer of every namespace:
+/// an online copy of the SQLite database, the Chroma vectors with their
+/// metadata, short-term memory, the semantic graph, and agent sources.
+pub async fn create() -> Result<Vec<u8>, String> {
+    let _guard = LOCK.lock().await;
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+
+    // VACUUM INTO gives a consistent copy while the database is in use.
+    let path = temp_db();
+    let copied = sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy().into_owned())
+        .execute(namespaces().pool())
+        .await
+        .map_err(|e| format!("SQLite backup failed: {}", e));
+    let db = match copied {
+        Ok(_) => tokio::fs::read(&path).await.map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    tokio::fs::remove_file(&path).await.ok();
+    files.push((SQLITE.to_string(), db?));
+
+    let names: Vec<String> = namespaces()
+        .list()
+        .await
+        .into_iter()
+        .map(|ns| ns.name)
+        .collect();
+    for name in &names {
+        let ctx = namespaces()
+            .context(name)
+            .await
+            .map_err(|e| format!("namespace '{}': {}", name, e))?;
+
+        let short: BTreeMap<String, Value> = ctx
+            .mem_short
+            .all_values()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        files.push((short_term_path(name), to_json(&short)?));
+
+        let snapshot = {
+            let graph = ctx
+                .mem_semantic
+                .lock()
+                .map_err(|_| "semantic graph lock poisoned".to_string())?;
+            GraphSnapshot::from_graph(&graph)
+        };
+        files.push((semantic_path(name), to_json(&snapshot)?));
+
+        let latent = ctx.mem_latent.lock().await.clone();
+        let vectors = latent
+            .export_all()
+            .await
+            .map_err(|e| format!("namespace '{}': {}", name, e))?;
+        let mut jsonl = Vec::new();
+        for record in &vectors {
+            serde_json::to_writer(&mut jsonl, record).map_err(|e| e.to_string())?;
+            jsonl.push(b'\n');
+        }
+        files.push((vectors_path(name), jsonl));
+    }
+
+    for (file, code) in agents::registry().sources().await {
+        files.push((format!("agents/{}", file), code.into_bytes()));
+    }
+
+    // Hashing and compressing the archive keeps a thread busy for a while.
+    tokio::task::spawn_blocking(move || pack(names, &files))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn pack(names: Vec<String>, files: &[(String, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    let manifest = Manifest {
+        format: FORMAT.to_string(),
+        version: FORMAT_VERSION,
+        created_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        namespaces: names,
+        files: files
+            .iter()
+            .map(|(path, bytes)| FileEntry {
+                path: path.clone(),
+                size: bytes.len() as u64,
+                sha256: sha256(bytes),
+            })
+            .collect(),
+    };
+
+    let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    append(&mut tar, MANIFEST, &to_json(&manifest)?)?;
+    for (path, bytes) in files {
+        append(&mut tar, path, bytes)?;
+    }
+    tar.into_inner()
+        .and_then(|gz| gz.finish())
+        .map_err(|e| e.to_string())
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    serde_json::to_vec_pretty(value).map_err(|e| e.to_string())
+}
+
+fn append(
+    tar: &mut tar::Builder<GzEncoder<Vec<u8>>>,
+    path: &str,
+    bytes: &[u8],
+) -> Result<(), String> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+    tar.append_data(&mut header, path, bytes)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Unpacks `archive` and checks it against its manifest. Fails as soon as
+/// the unpacked files add up to more than `max_bytes`.
+fn unpack(
+    archive: &[u8],
+    max_bytes: usize,
+) -> Result<(Manifest, HashMap<String, Vec<u8>>), String> {
+    let mut files = HashMap::new();
+    let mut total = 0;
+    let mut tar = tar::Archive::new(GzDecoder::new(archive));
+    for entry in tar
+        .entries()
+        .map_err(|e| format!("invalid archive: {}", e))?
+    {
+        let mut entry = entry.map_err(|e| format!("invalid archive: {}", e))?;
+        let path = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .to_string_lossy()
+            .into_owned();
+        let mut bytes = Vec::new();
+        entry
+            .by_ref()
+            .take((max_bytes - total) as u64 + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        total += bytes.len();
+        if total > max_bytes {
+            return Err(format!("archive unpacks to more than {} bytes", max_bytes));
+        }
+        files.insert(path, bytes);
+    }
+
+    let manifest: Manifest = serde_json::from_slice(
+        &files
+            .remove(MANIFEST)
+            .ok_or_else(|| format!("archive has no {}", MANIFEST))?,
+    )
+    .map_err(|e| format!("{}: {}", MANIFEST, e))?;
+    if manifest.format != FORMAT || manifest.version > FORMAT_VERSION {
+        return Err(format!(
+            "unsupported archive format {} v{}",
+            manifest.format, manifest.version
+        ));
+    }
+    for entry in &manifest.files {
+        let bytes = files
+            .get(&entry.path)
+            .ok_or_else(|| format!("{} is missing from the archive", entry.path))?;
+        if bytes.len() as u64 != entry.size || sha256(bytes) != entry.sha256 {
+            return Err(format!("checksum mismatch for {}", entry.path));
+        }
+    }
+    if let Some(extra) = files
+        .keys()
+        .find(|path| !manifest.files.iter().any(|f| &f.path == *path))
+    {
+        return Err(format!("{} is not listed in the manifest", extra));
+    }
+    if !files.contains_key(SQLITE) {
+        return Err(format!("archive has no {}", SQLITE));
+    }
+    Ok((manifest, files))
+}
+
+/// The in-process layers of one namespace, parsed before anything is
+/// touched so that a malformed archive changes nothing.
+struct NamespaceData {
+    name: String,
+    short: BTreeMap<String, Value>,
+    semantic: GraphSnapshot,
+    vectors: Vec<VectorRecord>,
+}
+
+fn parse_namespace(name: &str, files: &HashMap<String, Vec<u8>>) -> Result<NamespaceData, String> {
+    let read = |path: String| {
+        files
+            .get(&path)
+            .map(|bytes| (path.clone(), bytes))
+            .ok_or_else(|| format!("{} is missing from the archive", path))
+    };
+    let (path, bytes) = read(short_term_path(name))?;
+    let short = serde_json::from_slice(bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let (path, bytes) = read(semantic_path(name))?;
+    let semantic = serde_json::from_slice(bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let (path, bytes) = read(vectors_path(name))?;
+    let vectors = bytes
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice)
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(NamespaceData {
+        name: name.to_string(),
+        short,
+        semantic,
+        vectors,
+    })
+}
+
+/// Replaces every memory layer with the contents of `archive`. The
+/// SQLite tables are swapped in one transaction, each Chroma collection
+/// is dropped and re-created from the archived vectors, and short-term
+/// memory, semantic graphs and agents are reloaded. Namespaces missing
+/// from the archive are removed.
+pub async fn restore(archive: Bytes) -> Result<RestoreSummary, String> {
+    let _guard = LOCK.lock().await;
+    let (manifest, files, data) = tokio::task::spawn_blocking(move || {
+        let (manifest, files) = unpack(&archive, MAX_ARCHIVE_BYTES)?;
+        let data = manifest
+            .namespaces
+            .iter()
+            .map(|name| parse_namespace(name, &files))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok::<_, String>((manifest, files, data))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    let mut summary = RestoreSummary::default();
+
+    let stale = {
+        let mut stale = Vec::new();
+        for ns in namespaces().list().await {
+            if !manifest.namespaces.contains(&ns.name)
+                && let Ok(ctx) = namespaces().context(&ns.name).await
+            {
+                stale.push(ctx);
+            }
+        }
+        stale
+    };
+
+    let path = temp_db();
+    tokio::fs::write(&path, &files[SQLITE])
+        .await
+        .map_err(|e| e.to_string())?;
+    let copied = restore_sqlite(&path, &mut summary).await;
+    tokio::fs::remove_file(&path).await.ok();
+    copied?;
+    namespaces().reset().await;
+
+    for ctx in stale {
+        let latent = ctx.mem_latent.lock().await;
+        if let Err(e) = latent.delete_collection().await {
+            summary.warnings.push(format!(
+                "namespace '{}': stale collection not deleted: {}",
+                ctx.namespace, e
+            ));
+        }
+    }
+
+    for ns in data {
+        let ctx = match namespaces().context(&ns.name).await {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                summary
+                    .warnings
+                    .push(format!("namespace '{}': {}", ns.name, e));
+                continue;
+            }
+        };
+
+        ctx.mem_short.clear();
+        summary.short_term += ns.short.len();
+        for (key, value) in ns.short {
+            ctx.mem_short.set_value(key, value);
+        }
+
+        if let Ok(mut graph) = ctx.mem_semantic.lock() {
+            *graph = LatentGraph::new();
+            let applied = ns.semantic.apply(&mut graph);
+            summary.clusters += applied.clusters;
+            summary.relations += applied.relations;
+        }
+
+        let mut latent = ctx.mem_latent.lock().await;
+        let restored = match latent.recreate().await {
+            Ok(fresh) => {
+                *latent = fresh;
+                if ns.name == DEFAULT_NAMESPACE
+                    && let Err(e) = latent.save_id()
+                {
+                    summary.warnings.push(e);
+                }
+                latent.add_many(&ns.vectors).await
+            }
+            Err(e) => Err(e),
+        };
+        match restored {
+            Ok(()) => summary.vectors += ns.vectors.len(),
+            Err(e) => summary.warnings.push(format!(
+                "namespace '{}': vectors not restored: {}",
+                ns.name, e
+            )),
+        }
+        summary.namespaces.push(ns.name);
+    }
+
+    for entry in &manifest.files {
+        let Some(file) = entry.path.strip_prefix("agents/") else {
+            continue;
+        };
+        let code = String::from_utf8_lossy(&files[&entry.path]);
+        match agents::registry().restore_source(file, &code).await {
+            Ok(()) => summary.agents += 1,
+            Err(e) => summary.warnings.push(format!("agent {}: {}", file, e)),
+        }
+    }
+
+    tracing::info!(
+        "restored backup from {} ({} namespaces, {} warnings)",
+        manifest.created_at,
+        summary.namespaces.len(),
+        summary.warnings.len()
+    );
+    Ok(summary)
+}
+
+/// Copies every table of the database at `path` over the live one.
+async fn restore_sqlite(
+    path: &std::path::Path,
+    summary: &mut RestoreSummary,
+) -> Result<(), String> {
+    let mut conn = namespaces()
+        .pool()
+        .acquire()
+        .await
+        .map_err(|e| e.to_string())?;
+    sqlx::query("ATTACH DATABASE ? AS backup")
+        .bind(path.to_string_lossy().into_owned())
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| format!("cannot open archived database: {}", e))?;
+    let copied = copy_tables(&mut conn, summary).await;
+    sqlx::query("DETACH DATABASE backup")
+        .execute(&mut *conn)
+        .await
+        .ok();
+    copied
+}
+
+async fn copy_tables(
+    conn: &mut PoolConnection<Sqlite>,
+    summary: &mut RestoreSummary,
+) -> Result<(), String> {
+    const TABLES: &str =
+        "SELECT name, sql FROM {}.sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'";
+    let archived: Vec<(String, String)> = sqlx::query(&TABLES.replace("{}", "backup"))
+        .fetch_all(&mut **conn)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|row| (row.get("name"), row.get("sql")))
+        .collect();
+    let live: HashSet<String> = sqlx::query(&TABLES.replace("{}", "main"))
+        .fetch_all(&mut **conn)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|row| row.get("name"))
+        .collect();
+
+    sqlx::query("BEGIN IMMEDIATE")
+        .execute(&mut **conn)
+        .await
+        .map_err(|e| e.to_string())?;
+    let result = async {
+        let namespace_tables = format!("{}_", DEFAULT_TABLE);
+        for table in &live {
+            if table.starts_with(&namespace_tables) && !archived.iter().any(|(t, _)| t == table) {
+                sqlx::query(&format!("DROP TABLE main.{}", quote(table)))
+                    .execute(&mut **conn)
+                    .await?;
+            }
+        }
+        for (table, sql) in &archived {
+            if !live.contains(table) {
+                sqlx::query(sql).execute(&mut **conn).await?;
+            }
+            let columns: Vec<String> = sqlx::query_scalar(
+                "SELECT b.name FROM pragma_table_info(?1, 'backup') b
+                 JOIN pragma_table_info(?1, 'main') m ON m.name = b.name",
+            )
+            .bind(table)
+            .fetch_all(&mut **conn)
+            .await?;
+            let columns = columns
+                .iter()
+                .map(|c| quote(c))
+                .collect::<Vec<_>>()
+                .join(", ");
+            sqlx::query(&format!("DELETE FROM main.{}", quote(table)))
+                .execute(&mut **conn)
+                .await?;
+            summary.rows += sqlx::query(&format!(
+                "INSERT INTO main.{t} ({c}) SELECT {c} FROM backup.{t}",
+                t = quote(table),
+                c = columns
+            ))
+            .execute(&mut **conn)
+            .await?
+            .rows_affected();
+            summary.tables += 1;
+        }
+        Ok::<_, sqlx::Error>(())
+    }
+    .await;
+
+    let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+    sqlx::query(end)
+        .execute(&mut **conn)
+        .await
+        .map_err(|e| e.to_string())?;
+    result.map_err(|e| format!("SQLite restore failed: {}", e))
+}
+
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// `backup <file>`: fetches an archive from a running server.
+pub async fn download(server_url: &str, token: Option<&str>, file: &str) -> Result<String, String> {
+    let mut request = reqwest::Client::new().post(format!("{}/api/admin/backup", server_url));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let resp = request.send().await.map_err(|e| e.to_string())?;
+    let status = resp.status();
+    let bytes = resp.bytes().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{}: {}", status, String::from_utf8_lossy(&bytes)));
+    }
+    tokio::fs::write(file, &bytes)
+        .await
+        .map_err(|e| format!("{}: {}", file, e))?;
+    Ok(format!("wrote {} ({} bytes)", file, bytes.len()))
+}
+
+/// `restore <file>`: uploads an archive to a running server.
+pub async fn upload(server_url: &str, token: Option<&str>, file: &str) -> Result<String, String> {
+    let bytes = tokio::fs::read(file)
+        .await
+        .map_err(|e| format!("{}: {}", file, e))?;
+    let mut request = reqwest::Client::new()
+        .post(format!("{}/api/admin/restore", server_url))
+        .header("content-type", "application/gzip")
+        .body(bytes);
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let resp = request.send().await.map_err(|e| e.to_string())?;
+    let status = resp.status();
+    let text = resp.text().await.map_err(|e| e.to_string())?;
+    if status.is_success() {
+        Ok(text)
+    } else {
+        Err(format!("{}: {}", status, text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(files: &[(&str, &[u8])], manifest: &Manifest) -> Vec<u8> {
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        append(&mut tar, MANIFEST, &to_json(manifest).unwrap()).unwrap();
+        for (path, bytes) in files {
+            append(&mut tar, path, bytes).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn manifest(files: &[(&str, &[u8])]) -> Manifest {
+        Manifest {
+            format: FORMAT.to_string(),
+            version: FORMAT_VERSION,
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            namespaces: vec![],
+            files: files
+                .iter()
+                .map(|(path, bytes)| FileEntry {
+                    path: path.to_string(),
+                    size: bytes.len() as u64,
+                    sha256: sha256(bytes),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn unpacks_archives_matching_their_manifest() {
+        let files: &[(&str, &[u8])] = &[(SQLITE, b"db"), ("agents/A.sent", b"agent A {}")];
+        let (_, unpacked) = unpack(&archive(files, &manifest(files)), MAX_ARCHIVE_BYTES).unwrap();
+        assert_eq!(unpacked["agents/A.sent"], b"agent A {}");
+    }
+
+    #[test]
+    fn rejects_archives_that_unpack_past_the_limit() {
+        let files: &[(&str, &[u8])] = &[(SQLITE, &[0; 4096])];
+        let packed = archive(files, &manifest(files));
+        assert!(packed.len() < 1024);
+        let err = unpack(&packed, 1024).unwrap_err();
+        assert_eq!(err, "archive unpacks to more than 1024 bytes");
+    }
+
+    #[test]
+    fn rejects_tampered_or_incomplete_archives() {
+        let files: &[(&str, &[u8])] = &[(SQLITE, b"db")];
+        let tampered: &[(&str, &[u8])] = &[(SQLITE, b"dB")];
+        assert!(unpack(&archive(tampered, &manifest(files)), MAX_ARCHIVE_BYTES).is_err());
+
+        let extra: &[(&str, &[u8])] = &[(SQLITE, b"db"), ("x", b"")];
+        assert!(unpack(&archive(extra, &manifest(files)), MAX_ARCHIVE_BYTES).is_err());
+
+        assert!(unpack(&archive(&[], &manifest(files)), MAX_ARCHIVE_BYTES).is_err());
+        assert!(unpack(b"not an archive", MAX_ARCHIVE_BYTES).is_err());
+    }
+}
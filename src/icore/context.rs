@@ -1,38 +1,108 @@
-use crate::api::handlers::{LATENT_MEM, LONG_MEM, SHORT_MEM};
+use crate::api::handlers::{EPISODIC_MEM, LATENT_MEM, LONG_MEM, SHORT_MEM};
+use crate::icore::embed;
+use crate::icore::namespace::DEFAULT_NAMESPACE;
+use crate::icore::outbox::{self, Task};
+use crate::memory::episodic::EpisodicMemory;
 use crate::memory::latent::LatentMemory;
 use crate::memory::long_term::LongTermMemory;
 use crate::memory::semantic::LatentGraph;
+use crate::memory::semantic::latent_graph::SEMANTIC_GRAPH;
+use crate::memory::semantic::object::{AffectScore, ObjectCluster};
 use crate::memory::short_term::ShortTermMemory;
+use serde_json::Value;
+use sqlx::{Sqlite, Transaction};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// The memory layers of one namespace. Handlers receive it as an extractor
+/// (see `icore::namespace`); `Context::new()` is the default namespace.
 #[derive(Clone)]
 pub struct Context {
+    pub namespace: String,
     pub mem_short: ShortTermMemory,
     pub mem_long: LongTermMemory,
+    pub mem_episodic: EpisodicMemory,
     pub mem_latent: Arc<Mutex<LatentMemory>>,
-    pub mem_semantic: LatentGraph,
+    pub mem_semantic: Arc<std::sync::Mutex<LatentGraph>>,
 }
 
 impl Context {
     pub fn new() -> Self {
         Self {
+            namespace: DEFAULT_NAMESPACE.to_string(),
             mem_short: SHORT_MEM
                 .get()
                 .expect("short memory not initialized")
                 .clone(),
             mem_long: LONG_MEM.get().expect("long memory not initialized").clone(),
+            mem_episodic: EPISODIC_MEM
+                .get()
+                .expect("episodic memory not initialized")
+                .clone(),
             mem_latent: LATENT_MEM
                 .get()
                 .expect("latent memory not initialized")
                 .clone(),
-            mem_semantic: LatentGraph::default(),
+            mem_semantic: SEMANTIC_GRAPH.clone(),
         }
     }
 
-    pub async fn embed_latent(&self, id: &str, vec: Vec<f32>) -> Result<(), String> {
+    /// Embeds `text` with the model of this namespace's collection, so the
+    /// vector can be stored in or compared against it.
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        let model = self.mem_latent.lock().await.model.clone();
+        embed::embed_with(&model, text)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Model of this namespace's vectors.
+    pub async fn embed_model(&self) -> String {
+        self.mem_latent.lock().await.model.clone()
+    }
+
+    /// Stores a vector embedded with `model`; fails if the namespace's
+    /// collection has moved to another model since.
+    pub async fn embed_latent(
+        &self,
+        id: &str,
+        vec: Vec<f32>,
+        document: &str,
+        model: &str,
+    ) -> Result<(), String> {
         let lock = self.mem_latent.lock().await;
-        lock.embed(id, vec).await
+        lock.embed(id, vec, Some(document), model).await
+    }
+
+    /// Adds a chat input, embedded with `model`, to the semantic graph as a
+    /// known cluster.
+    pub fn add_input_cluster(&self, id: &str, embedding: Vec<f32>, model: String) {
+        let cluster = ObjectCluster {
+            name: id.to_string(),
+            embedding,
+            tags: vec!["chat_input".to_string()],
+            affect: AffectScore::from_value(0.0),
+            known: true,
+            model: Some(model),
+        };
+        if let Ok(mut graph) = self.mem_semantic.lock() {
+            graph.add_cluster(id.to_string(), cluster);
+        }
+    }
+
+    /// Queues `tasks` for this namespace on `tx` and commits, so the jobs
+    /// exist exactly when the writes made on `tx` do.
+    pub async fn commit_with(
+        &self,
+        mut tx: Transaction<'static, Sqlite>,
+        tasks: &[Task],
+    ) -> Result<(), String> {
+        for task in tasks {
+            outbox::enqueue_in(&mut tx, &self.namespace, task).await?;
+        }
+        tx.commit().await.map_err(|e| e.to_string())?;
+        outbox::wake();
+        Ok(())
     }
 
     pub async fn query_latent(&self, vec: Vec<f32>) -> Result<Vec<String>, String> {
@@ -56,8 +126,38 @@ impl Context {
             .collect()
     }
 
-    pub async fn set_long(&self, key: &str, value: &str) {
-        self.mem_long.set(key, value).await;
+    /// Writes a text entry to long-term memory and queues its vector.
+    pub async fn set_long(&self, key: &str, value: &str) -> Result<(), String> {
+        self.write_long(&[(key.to_string(), Value::String(value.to_string()))])
+            .await
+    }
+
+    /// Writes `entries` to long-term memory and queues their vectors in one
+    /// transaction, so every long-term write reaches the latent index.
+    pub async fn write_long(&self, entries: &[(String, Value)]) -> Result<(), String> {
+        let mut tx = self.mem_long.begin().await?;
+        for (key, value) in entries {
+            self.mem_long
+                .set_value_in(&mut tx, key, value)
+                .await
+                .ok_or_else(|| format!("failed to write '{}'", key))?;
+        }
+        let tasks = entries
+            .iter()
+            .map(|(key, value)| Task::long_entry(key, value))
+            .collect::<Vec<_>>();
+        self.commit_with(tx, &tasks).await
+    }
+
+    /// Deletes `keys` from long-term memory and queues the removal of their
+    /// vectors in one transaction. Returns how many of the keys existed.
+    pub async fn delete_long(&self, keys: &[String]) -> Result<u64, String> {
+        let mut tx = self.mem_long.begin().await?;
+        let deleted = self.mem_long.delete_many_in(&mut tx, keys).await?;
+        if deleted > 0 {
+            self.commit_with(tx, &[Task::forget_long(keys)]).await?;
+        }
+        Ok(deleted)
     }
 
     pub async fn get_long(&self, key: &str) -> Option<String> {
@@ -1,6 +1,12 @@
+use crate::icore::{embed_cache, telemetry};
 use anyhow::{Result, anyhow};
+use once_cell::sync::Lazy;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::env;
+use std::time::Instant;
+
+static CLIENT: Lazy<Client> = Lazy::new(Client::new);
 
 #[derive(Serialize)]
 struct EmbeddingRequest<'a> {
@@ -13,14 +19,52 @@ struct EmbeddingResponse {
     embedding: Vec<f32>,
 }
 
-pub async fn embed_text(prompt: &str) -> Result<Vec<f32>> {
-    let client = Client::new();
-    let res = client
-        .post("http://127.0.0.1:11434/api/embeddings")
-        .json(&EmbeddingRequest {
-            model: "tinyllama",
-            prompt,
-        })
+/// Model new collections are embedded with, from `EMBED_MODEL`.
+pub fn default_model() -> String {
+    env::var("EMBED_MODEL")
+        .ok()
+        .filter(|m| !m.is_empty())
+        .unwrap_or_else(|| "tinyllama".into())
+}
+
+fn embed_url() -> String {
+    env::var("EMBED_URL").unwrap_or_else(|_| "http://127.0.0.1:11434".into())
+}
+
+/// Embeds `prompt` with `model`, answering from the embedding cache when
+/// it can.
+pub async fn embed_with(model: &str, prompt: &str) -> Result<Vec<f32>> {
+    let cache = embed_cache::cache();
+    if let Some(cache) = cache
+        && let Some(embedding) = cache.get(model, prompt).await
+    {
+        return Ok(embedding);
+    }
+
+    let embedding = request(model, prompt).await?;
+    if let Some(cache) = cache {
+        cache.put(model, prompt, &embedding).await;
+    }
+    Ok(embedding)
+}
+
+/// Embeds a short text with the default model, past the cache, to check
+/// that the embedding server answers.
+pub async fn probe() -> Result<()> {
+    request(&default_model(), "ping").await.map(|_| ())
+}
+
+async fn request(model: &str, prompt: &str) -> Result<Vec<f32>> {
+    let started = Instant::now();
+    let result = send(model, prompt).await;
+    telemetry::observe_call("embedding", "embed", started, result.is_ok());
+    result
+}
+
+async fn send(model: &str, prompt: &str) -> Result<Vec<f32>> {
+    let res = CLIENT
+        .post(format!("{}/api/embeddings", embed_url()))
+        .json(&EmbeddingRequest { model, prompt })
         .send()
         .await?;
 
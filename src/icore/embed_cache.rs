@@ -0,0 +1,414 @@
+use crate::icore::context::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Sqlite, sqlite::SqlitePoolOptions};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub static EMBED_CACHE: OnceLock<EmbedCache> = OnceLock::new();
+
+/// The cache, if the server set one up.
+pub fn cache() -> Option<&'static EmbedCache> {
+    EMBED_CACHE.get()
+}
+
+/// Vectors read from a collection per request during warmup.
+const WARMUP_PAGE: usize = 500;
+
+/// SQLite writes between trims of the persistent tier back to its cap.
+const PRUNE_EVERY: u64 = 64;
+
+/// Collapses runs of whitespace and trims, so inputs that differ only in
+/// spacing share an entry. Case is kept: it can change the embedding.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Cache key of `text` embedded with `model`.
+pub fn key(model: &str, text: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(model.as_bytes());
+    hasher.update([0]);
+    hasher.update(normalize(text).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Least-recently-used map from key to embedding.
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<String, (Vec<f32>, u64)>,
+    /// Last use → key, oldest first.
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &str) -> Option<u64> {
+        self.tick += 1;
+        let (_, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        Some(self.tick)
+    }
+
+    fn get(&mut self, key: &str) -> Option<Vec<f32>> {
+        self.touch(key)?;
+        self.entries
+            .get(key)
+            .map(|(embedding, _)| embedding.clone())
+    }
+
+    /// Stores `embedding`; returns how many entries were evicted for it.
+    fn put(&mut self, key: String, embedding: Vec<f32>) -> usize {
+        if self.capacity == 0 {
+            return 0;
+        }
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.0 = embedding;
+            self.touch(&key);
+            return 0;
+        }
+        let mut evicted = 0;
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            evicted += 1;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (embedding, self.tick));
+        evicted
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub persistent: bool,
+    pub memory_hits: u64,
+    pub sqlite_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Rows trimmed from the SQLite tier to keep it under its cap.
+    pub sqlite_evictions: u64,
+    /// Share of lookups served from either tier.
+    pub hit_rate: f64,
+}
+
+/// Embeddings by (model, normalized text): an in-memory LRU in front of an
+/// optional SQLite table that survives restarts. The table keeps its
+/// `max_rows` most recently written entries.
+pub struct EmbedCache {
+    memory: Mutex<Lru>,
+    pool: Option<Pool<Sqlite>>,
+    max_rows: usize,
+    sqlite_writes: AtomicU64,
+    memory_hits: AtomicU64,
+    sqlite_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    sqlite_evictions: AtomicU64,
+}
+
+impl EmbedCache {
+    /// `capacity` entries in memory; `database_url` enables the SQLite tier,
+    /// holding at most `max_rows` entries (`0` for no limit).
+    pub async fn new(capacity: usize, database_url: Option<&str>, max_rows: usize) -> Self {
+        let pool = match database_url {
+            Some(url) => {
+                let options = sqlx::sqlite::SqliteConnectOptions::new()
+                    .filename(url)
+                    .create_if_missing(true);
+                let pool = SqlitePoolOptions::new()
+                    .connect_with(options)
+                    .await
+                    .expect("Failed to connect to SQLite");
+                sqlx::query(
+                    "CREATE TABLE IF NOT EXISTS embedding_cache (
+                        key TEXT PRIMARY KEY,
+                        model TEXT NOT NULL,
+                        embedding BLOB NOT NULL,
+                        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                    );",
+                )
+                .execute(&pool)
+                .await
+                .expect("Failed to create embedding_cache table");
+                sqlx::query(
+                    "CREATE INDEX IF NOT EXISTS embedding_cache_created
+                        ON embedding_cache (created_at)",
+                )
+                .execute(&pool)
+                .await
+                .expect("Failed to index embedding_cache");
+                Some(pool)
+            }
+            None => None,
+        };
+        Self {
+            memory: Mutex::new(Lru::new(capacity)),
+            pool,
+            max_rows,
+            sqlite_writes: AtomicU64::new(0),
+            memory_hits: AtomicU64::new(0),
+            sqlite_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            sqlite_evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn get(&self, model: &str, text: &str) -> Option<Vec<f32>> {
+        let key = key(model, text);
+        // A poisoned lock skips the memory tier; the lookup still counts.
+        let cached = self.memory.lock().ok().and_then(|mut m| m.get(&key));
+        if let Some(embedding) = cached {
+            self.memory_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(embedding);
+        }
+        if let Some(pool) = &self.pool {
+            let blob: Option<Vec<u8>> =
+                sqlx::query_scalar("SELECT embedding FROM embedding_cache WHERE key = ?")
+                    .bind(&key)
+                    .fetch_optional(pool)
+                    .await
+                    .ok()
+                    .flatten();
+            if let Some(blob) = blob {
+                let embedding = from_blob(&blob);
+                self.sqlite_hits.fetch_add(1, Ordering::Relaxed);
+                self.remember(key, embedding.clone());
+                return Some(embedding);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub async fn put(&self, model: &str, text: &str, embedding: &[f32]) {
+        let key = key(model, text);
+        if let Some(pool) = &self.pool
+            && let Err(e) = sqlx::query(
+                "INSERT OR REPLACE INTO embedding_cache (key, model, embedding) VALUES (?, ?, ?)",
+            )
+            .bind(&key)
+            .bind(model)
+            .bind(to_blob(embedding))
+            .execute(pool)
+            .await
+        {
+            tracing::warn!("embedding cache write failed: {}", e);
+        }
+        if self.pool.is_some()
+            && (self.sqlite_writes.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(PRUNE_EVERY)
+        {
+            self.prune().await;
+        }
+        self.remember(key, embedding.to_vec());
+    }
+
+    /// Deletes the oldest SQLite rows beyond `max_rows`, by `created_at`.
+    async fn prune(&self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+        if self.max_rows == 0 {
+            return;
+        }
+        match sqlx::query(
+            "DELETE FROM embedding_cache WHERE key IN (
+                SELECT key FROM embedding_cache
+                ORDER BY created_at DESC, rowid DESC LIMIT -1 OFFSET ?
+            )",
+        )
+        .bind(self.max_rows as i64)
+        .execute(pool)
+        .await
+        {
+            Ok(done) => {
+                self.sqlite_evictions
+                    .fetch_add(done.rows_affected(), Ordering::Relaxed);
+            }
+            Err(e) => tracing::warn!("embedding cache prune failed: {}", e),
+        }
+    }
+
+    fn remember(&self, key: String, embedding: Vec<f32>) {
+        if let Ok(mut memory) = self.memory.lock() {
+            let evicted = memory.put(key, embedding);
+            self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, capacity) = self
+            .memory
+            .lock()
+            .map(|m| (m.entries.len(), m.capacity))
+            .unwrap_or_default();
+        let memory_hits = self.memory_hits.load(Ordering::Relaxed);
+        let sqlite_hits = self.sqlite_hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = memory_hits + sqlite_hits + misses;
+        CacheStats {
+            entries,
+            capacity,
+            persistent: self.pool.is_some(),
+            memory_hits,
+            sqlite_hits,
+            misses,
+            evictions: self.evictions.load(Ordering::Relaxed),
+            sqlite_evictions: self.sqlite_evictions.load(Ordering::Relaxed),
+            hit_rate: if lookups > 0 {
+                (memory_hits + sqlite_hits) as f64 / lookups as f64
+            } else {
+                0.0
+            },
+        }
+    }
+
+    /// Empties both tiers and resets the counters.
+    pub async fn clear(&self) {
+        if let Ok(mut memory) = self.memory.lock() {
+            memory.clear();
+        }
+        if let Some(pool) = &self.pool {
+            sqlx::query("DELETE FROM embedding_cache")
+                .execute(pool)
+                .await
+                .ok();
+        }
+        for counter in [
+            &self.memory_hits,
+            &self.sqlite_hits,
+            &self.misses,
+            &self.evictions,
+            &self.sqlite_evictions,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Fills the cache from vectors already stored in `ctx`'s collection,
+    /// keyed by their document, without calling the embedding server.
+    /// Stops after `limit` entries. Returns how many were added.
+    pub async fn warmup(&self, ctx: &Context, limit: usize) -> Result<usize, String> {
+        let latent = ctx.mem_latent.lock().await.clone();
+        let mut offset = 0;
+        let mut added = 0;
+        while added < limit {
+            let page = latent.export_page(offset, WARMUP_PAGE).await?;
+            offset += page.len();
+            let done = page.len() < WARMUP_PAGE;
+            for vector in page {
+                let Some(document) = vector.document else {
+                    continue;
+                };
+                // Vectors carry their model since it was recorded; older
+                // ones are from the collection's model.
+                let model = vector
+                    .metadata
+                    .as_ref()
+                    .and_then(|m| m.get("model"))
+                    .and_then(|m| m.as_str())
+                    .unwrap_or(&latent.model);
+                if model == latent.model && added < limit {
+                    self.put(model, &document, &vector.embedding).await;
+                    added += 1;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        Ok(added)
+    }
+}
+
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_ignore_spacing_but_not_model_or_case() {
+        assert_eq!(key("m", "  hi \n there "), key("m", "hi there"));
+        assert_ne!(key("m", "hi"), key("other", "hi"));
+        assert_ne!(key("m", "hi"), key("m", "Hi"));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.put("a".into(), vec![1.0]);
+        lru.put("b".into(), vec![2.0]);
+        assert_eq!(lru.get("a"), Some(vec![1.0]));
+        assert_eq!(lru.put("c".into(), vec![3.0]), 1);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(vec![1.0]));
+        assert_eq!(lru.get("c"), Some(vec![3.0]));
+        assert_eq!(lru.order.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sqlite_tier_keeps_the_newest_rows() {
+        let path = std::env::temp_dir().join(format!(
+            "icore_embed_cache_{}.db",
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let cache = EmbedCache::new(0, Some(path.to_str().unwrap()), 3).await;
+        for i in 0..PRUNE_EVERY {
+            cache.put("m", &format!("text {}", i), &[i as f32]).await;
+        }
+
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM embedding_cache")
+            .fetch_one(cache.pool.as_ref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(rows, 3);
+        assert_eq!(cache.stats().sqlite_evictions, PRUNE_EVERY - 3);
+        assert_eq!(cache.get("m", "text 0").await, None);
+        let last = PRUNE_EVERY - 1;
+        assert_eq!(
+            cache.get("m", &format!("text {}", last)).await,
+            Some(vec![last as f32])
+        );
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn blobs_round_trip() {
+        let embedding = vec![0.5, -1.25, 3.0e-7];
+        assert_eq!(from_blob(&to_blob(&embedding)), embedding);
+    }
+}
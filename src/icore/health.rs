@@ -0,0 +1,162 @@
+use crate::api::handlers::LATENT_MEM;
+use crate::icore::namespace::namespaces;
+use crate::icore::{embed, llm};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Probes slower than this count as failed.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a readiness report is reused; set once at startup.
+pub static CACHE_TTL: OnceLock<Duration> = OnceLock::new();
+
+/// Last report and when it was taken. Callers wait on the lock while a
+/// probe runs, so concurrent requests share it.
+static LAST: Lazy<Mutex<Option<(Instant, Report)>>> = Lazy::new(Default::default);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// An optional dependency is down; the server answers with less.
+    Degraded,
+    /// A dependency the server cannot work without is down.
+    Down,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub status: Status,
+    pub latency_ms: f64,
+    /// Whether the server is down, rather than degraded, without it.
+    pub critical: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub checked_at: String,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// The readiness of every dependency, probed at most once per
+/// [`CACHE_TTL`].
+pub async fn ready() -> Report {
+    let ttl = CACHE_TTL.get().copied().unwrap_or_default();
+    let mut last = LAST.lock().await;
+    if let Some((taken, report)) = last.as_ref()
+        && taken.elapsed() < ttl
+    {
+        return report.clone();
+    }
+    let report = probe().await;
+    *last = Some((Instant::now(), report.clone()));
+    report
+}
+
+async fn probe() -> Report {
+    let (sqlite, chroma, embedding, llm) = tokio::join!(
+        check(true, async {
+            sqlx::query("SELECT 1")
+                .execute(namespaces().pool())
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }),
+        check(false, async {
+            let latent = LATENT_MEM
+                .get()
+                .ok_or("latent memory not initialized")?
+                .lock()
+                .await
+                .clone();
+            latent.heartbeat().await
+        }),
+        check(false, async {
+            embed::probe().await.map_err(|e| e.to_string())
+        }),
+        check(false, async {
+            llm::generate_local_with("ping", 1, &[])
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }),
+    );
+    let checks = BTreeMap::from([
+        ("sqlite", sqlite),
+        ("chroma", chroma),
+        ("embedding", embedding),
+        ("llm", llm),
+    ]);
+    Report {
+        status: overall(checks.values()),
+        checked_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        checks,
+    }
+}
+
+async fn check(critical: bool, probe: impl Future<Output = Result<(), String>>) -> Check {
+    let started = Instant::now();
+    let result = tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {:?}", PROBE_TIMEOUT)));
+    Check {
+        status: if result.is_ok() {
+            Status::Ok
+        } else {
+            Status::Down
+        },
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        critical,
+        error: result.err(),
+    }
+}
+
+fn overall<'a>(checks: impl Iterator<Item = &'a Check>) -> Status {
+    checks.fold(Status::Ok, |status, check| {
+        match (check.status, check.critical) {
+            (Status::Ok, _) => status,
+            (_, true) => Status::Down,
+            (_, false) if status == Status::Down => Status::Down,
+            (_, false) => Status::Degraded,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(status: Status, critical: bool) -> Check {
+        Check {
+            status,
+            latency_ms: 0.0,
+            critical,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn only_critical_failures_take_the_server_down() {
+        let ok = check(Status::Ok, true);
+        let optional_down = check(Status::Down, false);
+        let critical_down = check(Status::Down, true);
+        assert_eq!(overall([&ok].into_iter()), Status::Ok);
+        assert_eq!(overall([&ok, &optional_down].into_iter()), Status::Degraded);
+        assert_eq!(
+            overall([&critical_down, &optional_down].into_iter()),
+            Status::Down
+        );
+        assert_eq!(
+            overall([&optional_down, &critical_down].into_iter()),
+            Status::Down
+        );
+    }
+}
@@ -0,0 +1,673 @@
+use crate::icore::context::Context;
+use crate::icore::embed;
+use crate::icore::namespace::{DEFAULT_NAMESPACE, NamespaceError, namespaces};
+use crate::memory::latent::{VectorRecord, vector_metadata};
+use crate::memory::long_term::{ListFilter, LongTermMemory, Record};
+use crate::memory::semantic::LatentGraph;
+use crate::memory::semantic::export::{ClusterRecord, GraphSnapshot, RelationRecord};
+use crate::memory::semantic::object::ObjectCluster;
+use crate::memory::value;
+use axum::body::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+
+/// Rows or vectors read from a backend per round trip while exporting.
+const PAGE_SIZE: usize = 500;
+/// Vectors buffered per namespace before they are written to Chroma.
+const VECTOR_BATCH: usize = 200;
+/// Errors kept in an [`ImportSummary`]; later ones are only counted.
+const MAX_ERRORS: usize = 100;
+/// Longest import line; longer ones are skipped without being buffered.
+const MAX_LINE_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    Short,
+    Long,
+    Latent,
+    Semantic,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 4] = [Layer::Short, Layer::Long, Layer::Latent, Layer::Semantic];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "short" => Some(Layer::Short),
+            "long" => Some(Layer::Long),
+            "latent" => Some(Layer::Latent),
+            "semantic" => Some(Layer::Semantic),
+            _ => None,
+        }
+    }
+
+    /// Parses a comma-separated list such as `short,long`; all layers when
+    /// absent.
+    pub fn parse_list(list: Option<&str>) -> Result<Vec<Layer>, String> {
+        let Some(list) = list.filter(|l| !l.trim().is_empty()) else {
+            return Ok(Layer::ALL.to_vec());
+        };
+        let mut layers = list
+            .split(',')
+            .map(|l| Layer::parse(l.trim()).ok_or_else(|| format!("unknown layer '{}'", l)))
+            .collect::<Result<Vec<_>, _>>()?;
+        layers.sort();
+        layers.dedup();
+        Ok(layers)
+    }
+}
+
+/// One line of an export. `value` is the stored value: the entry itself
+/// for short- and long-term memory, the source document (if any) for a
+/// vector, and the cluster without its embedding for the semantic graph.
+/// Long-term `metadata` holds `version` and `content_type`; vectors keep
+/// their Chroma metadata; clusters list their outgoing `relations`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryRecord {
+    pub layer: Layer,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub key: String,
+    #[serde(default)]
+    pub value: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub metadata: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+type Line = Result<Bytes, std::io::Error>;
+
+/// Streams `layers` of `names` as JSONL, one record per line. Pages are
+/// read from SQLite and Chroma as the client consumes the body, so the
+/// whole store is never held in memory.
+pub fn export(names: Vec<String>, layers: Vec<Layer>) -> impl Stream<Item = Line> {
+    let (mut tx, rx) = mpsc::channel::<Line>(16);
+    tokio::spawn(async move {
+        if let Err(e) = export_into(&mut tx, &names, &layers).await {
+            tracing::error!("export failed: {}", e);
+            tx.send(Err(std::io::Error::other(e))).await.ok();
+        }
+    });
+    rx
+}
+
+async fn send(tx: &mut mpsc::Sender<Line>, record: &MemoryRecord) -> Result<(), String> {
+    let mut line = serde_json::to_vec(record).map_err(|e| e.to_string())?;
+    line.push(b'\n');
+    tx.send(Ok(Bytes::from(line)))
+        .await
+        .map_err(|_| "client went away".to_string())
+}
+
+async fn export_into(
+    tx: &mut mpsc::Sender<Line>,
+    names: &[String],
+    layers: &[Layer],
+) -> Result<(), String> {
+    for name in names {
+        let ctx = namespaces()
+            .context(name)
+            .await
+            .map_err(|e| format!("namespace '{}': {}", name, e))?;
+        for layer in layers {
+            match layer {
+                Layer::Short => export_short(tx, &ctx).await?,
+                Layer::Long => export_long(tx, &ctx).await?,
+                Layer::Latent => export_latent(tx, &ctx).await?,
+                Layer::Semantic => export_semantic(tx, &ctx).await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn export_short(tx: &mut mpsc::Sender<Line>, ctx: &Context) -> Result<(), String> {
+    let entries: BTreeMap<String, Value> = ctx
+        .mem_short
+        .all_values()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    for (key, value) in entries {
+        let record = MemoryRecord {
+            layer: Layer::Short,
+            namespace: ctx.namespace.clone(),
+            key,
+            value,
+            embedding: None,
+            metadata: Value::Null,
+            created_at: None,
+        };
+        send(tx, &record).await?;
+    }
+    Ok(())
+}
+
+async fn export_long(tx: &mut mpsc::Sender<Line>, ctx: &Context) -> Result<(), String> {
+    let filter = ListFilter::default();
+    let mut offset = 0;
+    loop {
+        let (page, _) = ctx.mem_long.list(&filter, offset, PAGE_SIZE).await;
+        offset += page.len();
+        let done = page.len() < PAGE_SIZE;
+        for entry in page {
+            send(tx, &long_record(&ctx.namespace, entry)).await?;
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+fn long_record(namespace: &str, entry: Record) -> MemoryRecord {
+    MemoryRecord {
+        layer: Layer::Long,
+        namespace: namespace.to_string(),
+        key: entry.key,
+        value: entry.value,
+        embedding: None,
+        metadata: json!({ "version": entry.version, "content_type": entry.content_type }),
+        created_at: Some(entry.created_at),
+    }
+}
+
+async fn export_latent(tx: &mut mpsc::Sender<Line>, ctx: &Context) -> Result<(), String> {
+    let latent = ctx.mem_latent.lock().await.clone();
+    let mut offset = 0;
+    loop {
+        let page = latent.export_page(offset, PAGE_SIZE).await?;
+        offset += page.len();
+        let done = page.len() < PAGE_SIZE;
+        for vector in page {
+            let record = MemoryRecord {
+                layer: Layer::Latent,
+                namespace: ctx.namespace.clone(),
+                key: vector.id,
+                value: vector.document.map(Value::String).unwrap_or_default(),
+                embedding: Some(vector.embedding),
+                metadata: vector.metadata.unwrap_or_default(),
+                created_at: None,
+            };
+            send(tx, &record).await?;
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+async fn export_semantic(tx: &mut mpsc::Sender<Line>, ctx: &Context) -> Result<(), String> {
+    let snapshot = {
+        let graph = ctx
+            .mem_semantic
+            .lock()
+            .map_err(|_| "semantic graph lock poisoned".to_string())?;
+        GraphSnapshot::from_graph(&graph)
+    };
+    for record in semantic_records(&ctx.namespace, snapshot)? {
+        send(tx, &record).await?;
+    }
+    Ok(())
+}
+
+/// One record per cluster, carrying its outgoing relations.
+fn semantic_records(namespace: &str, snapshot: GraphSnapshot) -> Result<Vec<MemoryRecord>, String> {
+    let mut relations: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for rel in snapshot.relations {
+        relations
+            .entry(rel.source)
+            .or_default()
+            .push(json!({ "relation": rel.relation, "target": rel.target }));
+    }
+
+    let mut records = Vec::new();
+    for ClusterRecord { id, mut cluster } in snapshot.clusters {
+        let embedding = std::mem::take(&mut cluster.embedding);
+        let mut value = serde_json::to_value(&cluster).map_err(|e| e.to_string())?;
+        if let Value::Object(fields) = &mut value {
+            fields.remove("embedding");
+        }
+        records.push(MemoryRecord {
+            layer: Layer::Semantic,
+            namespace: namespace.to_string(),
+            metadata: relations_metadata(relations.remove(&id)),
+            key: id,
+            value,
+            embedding: Some(embedding),
+            created_at: None,
+        });
+    }
+    // Relations whose source is not a cluster still need a home.
+    for (source, edges) in relations {
+        records.push(MemoryRecord {
+            layer: Layer::Semantic,
+            namespace: namespace.to_string(),
+            key: source,
+            value: Value::Null,
+            embedding: None,
+            metadata: relations_metadata(Some(edges)),
+            created_at: None,
+        });
+    }
+    Ok(records)
+}
+
+fn relations_metadata(edges: Option<Vec<Value>>) -> Value {
+    match edges {
+        Some(edges) => json!({ "relations": edges }),
+        None => Value::Null,
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportSummary {
+    pub records: usize,
+    pub layers: BTreeMap<Layer, usize>,
+    pub reembedded: usize,
+    pub failed: usize,
+    /// `line N: reason` for the first failures.
+    pub errors: Vec<String>,
+}
+
+/// Reads JSONL records from `body` as it arrives and writes each one to
+/// its namespace, which is created if needed. With `reembed`, vectors and
+/// clusters get a fresh embedding of their source text (the document or
+/// cluster name) instead of the exported one. Bad lines are counted and
+/// skipped.
+pub async fn import<S, E>(mut body: S, reembed: bool) -> Result<ImportSummary, String>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut importer = Importer {
+        reembed,
+        summary: ImportSummary::default(),
+        vectors: HashMap::new(),
+    };
+    let mut lines = Lines::new(MAX_LINE_BYTES);
+    while let Some(chunk) = body.next().await {
+        for (line_no, line) in lines.push(&chunk.map_err(|e| e.to_string())?) {
+            importer.read(line_no, line).await;
+        }
+    }
+    if let Some((line_no, line)) = lines.finish() {
+        importer.read(line_no, line).await;
+    }
+    importer.flush_all().await;
+    Ok(importer.summary)
+}
+
+/// Splits a body into numbered lines as chunks arrive. A line longer than
+/// `max` is reported as an error once and its bytes are dropped up to the
+/// next newline.
+struct Lines {
+    max: usize,
+    buf: Vec<u8>,
+    line_no: usize,
+    /// Set while dropping the rest of an overlong line.
+    skipping: bool,
+}
+
+type NumberedLine = (usize, Result<Vec<u8>, String>);
+
+impl Lines {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            buf: Vec::new(),
+            line_no: 0,
+            skipping: false,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) -> Vec<NumberedLine> {
+        let mut lines = Vec::new();
+        for part in chunk.split_inclusive(|b| *b == b'\n') {
+            let ends = part.ends_with(b"\n");
+            if !self.skipping {
+                self.buf.extend_from_slice(part);
+                if self.buf.len() > self.max + usize::from(ends) {
+                    self.buf = Vec::new();
+                    self.skipping = true;
+                    lines.push((
+                        self.line_no + 1,
+                        Err(format!("line longer than {} bytes", self.max)),
+                    ));
+                }
+            }
+            if ends {
+                self.line_no += 1;
+                if !std::mem::take(&mut self.skipping) {
+                    lines.push((self.line_no, Ok(std::mem::take(&mut self.buf))));
+                }
+            }
+        }
+        lines
+    }
+
+    /// The last line, if the body did not end with a newline.
+    fn finish(self) -> Option<NumberedLine> {
+        (!self.buf.is_empty() && !self.skipping).then(|| (self.line_no + 1, Ok(self.buf)))
+    }
+}
+
+struct Importer {
+    reembed: bool,
+    summary: ImportSummary,
+    /// Vectors waiting to be written, per namespace.
+    vectors: HashMap<String, Vec<VectorRecord>>,
+}
+
+impl Importer {
+    async fn read(&mut self, line_no: usize, line: Result<Vec<u8>, String>) {
+        match line {
+            Ok(line) => self.line(line_no, &line).await,
+            Err(e) => self.fail(format!("line {}: {}", line_no, e)),
+        }
+    }
+
+    async fn line(&mut self, line_no: usize, line: &[u8]) {
+        if line.trim_ascii().is_empty() {
+            return;
+        }
+        let result = match serde_json::from_slice::<MemoryRecord>(line) {
+            Ok(record) => self.apply(record).await,
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = result {
+            self.fail(format!("line {}: {}", line_no, e));
+        }
+    }
+
+    fn fail(&mut self, error: String) {
+        self.summary.failed += 1;
+        if self.summary.errors.len() < MAX_ERRORS {
+            self.summary.errors.push(error);
+        }
+    }
+
+    async fn apply(&mut self, mut record: MemoryRecord) -> Result<(), String> {
+        let ctx = context(&record.namespace).await?;
+        if self.reembed {
+            let text = match record.layer {
+                Layer::Latent => Some(record.value.as_str().ok_or_else(|| {
+                    format!("vector '{}' has no source text to re-embed", record.key)
+                })?),
+                Layer::Semantic if record.value.is_object() => Some(
+                    record
+                        .value
+                        .get("name")
+                        .and_then(Value::as_str)
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or(&record.key),
+                ),
+                _ => None,
+            };
+            if let Some(text) = text {
+                let model = ctx.embed_model().await;
+                let embedding = embed::embed_with(&model, text)
+                    .await
+                    .map_err(|e| format!("re-embedding '{}' failed: {}", record.key, e))?;
+                match record.layer {
+                    Layer::Latent => {
+                        let tags = vector_metadata(&model, embedding.len());
+                        if record.metadata.is_object() {
+                            value::merge_patch(&mut record.metadata, &tags);
+                        } else {
+                            record.metadata = tags;
+                        }
+                    }
+                    _ => record.value["model"] = json!(model),
+                }
+                record.embedding = Some(embedding);
+                self.summary.reembedded += 1;
+            }
+        }
+
+        let layer = record.layer;
+        match layer {
+            Layer::Short => ctx.mem_short.set_value(record.key, record.value),
+            Layer::Long => apply_long(&ctx.mem_long, &record).await?,
+            Layer::Latent => {
+                let embedding = record
+                    .embedding
+                    .ok_or_else(|| format!("vector '{}' has no embedding", record.key))?;
+                let pending = self.vectors.entry(ctx.namespace.clone()).or_default();
+                pending.push(VectorRecord {
+                    id: record.key,
+                    embedding,
+                    metadata: Some(record.metadata).filter(|m| !m.is_null()),
+                    document: record.value.as_str().map(str::to_string),
+                });
+                if pending.len() >= VECTOR_BATCH {
+                    self.flush(&ctx.namespace).await;
+                }
+            }
+            Layer::Semantic => {
+                let mut graph = ctx
+                    .mem_semantic
+                    .lock()
+                    .map_err(|_| "semantic graph lock poisoned".to_string())?;
+                apply_semantic(&mut graph, record)?;
+            }
+        }
+        self.summary.records += 1;
+        *self.summary.layers.entry(layer).or_default() += 1;
+        Ok(())
+    }
+
+    async fn flush(&mut self, namespace: &str) {
+        let Some(pending) = self.vectors.remove(namespace) else {
+            return;
+        };
+        let result = match context(namespace).await {
+            Ok(ctx) => ctx.mem_latent.lock().await.upsert_many(&pending).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            // The vectors were already counted as imported.
+            self.summary.records -= pending.len();
+            *self.summary.layers.entry(Layer::Latent).or_default() -= pending.len();
+            for vector in &pending {
+                self.fail(format!("vector '{}': {}", vector.id, e));
+            }
+        }
+    }
+
+    async fn flush_all(&mut self) {
+        let namespaces: Vec<String> = self.vectors.keys().cloned().collect();
+        for namespace in namespaces {
+            self.flush(&namespace).await;
+        }
+    }
+}
+
+async fn apply_long(mem: &LongTermMemory, record: &MemoryRecord) -> Result<(), String> {
+    mem.import(&record.key, &record.value, record.created_at.as_deref())
+        .await
+        .map(|_| ())
+        .ok_or_else(|| format!("failed to store '{}'", record.key))
+}
+
+fn apply_semantic(graph: &mut LatentGraph, record: MemoryRecord) -> Result<(), String> {
+    let mut snapshot = GraphSnapshot::default();
+    if let Value::Object(mut fields) = record.value {
+        if let Some(embedding) = record.embedding {
+            fields.insert("embedding".into(), json!(embedding));
+        }
+        let cluster: ObjectCluster =
+            serde_json::from_value(Value::Object(fields)).map_err(|e| e.to_string())?;
+        snapshot.clusters.push(ClusterRecord {
+            id: record.key.clone(),
+            cluster,
+        });
+    }
+    if let Some(edges) = record.metadata.get("relations").and_then(Value::as_array) {
+        for edge in edges {
+            let (Some(relation), Some(target)) = (
+                edge.get("relation").and_then(Value::as_str),
+                edge.get("target").and_then(Value::as_str),
+            ) else {
+                return Err(format!("invalid relation on '{}'", record.key));
+            };
+            snapshot.relations.push(RelationRecord {
+                source: record.key.clone(),
+                relation: relation.to_string(),
+                target: target.to_string(),
+            });
+        }
+    }
+    snapshot.apply(graph);
+    Ok(())
+}
+
+/// The namespace's memory, registering the namespace first if the import
+/// is the first to mention it.
+async fn context(name: &str) -> Result<Context, String> {
+    match namespaces().context(name).await {
+        Err(NamespaceError::NotFound) => match namespaces().create(name).await {
+            Ok(()) | Err(NamespaceError::Exists) => {
+                namespaces().context(name).await.map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        },
+        other => other.map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_layer_lists() {
+        assert_eq!(Layer::parse_list(None), Ok(Layer::ALL.to_vec()));
+        assert_eq!(
+            Layer::parse_list(Some("semantic, short,short")),
+            Ok(vec![Layer::Short, Layer::Semantic])
+        );
+        assert!(Layer::parse_list(Some("short,episodic")).is_err());
+    }
+
+    #[test]
+    fn overlong_lines_are_skipped_without_buffering() {
+        let mut lines = Lines::new(8);
+        let mut read = lines.push(b"{\"a\":1}\n{\"toolong\"");
+        read.extend(lines.push(b":\"xxxxxxxx\"}\n"));
+        read.extend(lines.push(b"{}"));
+        assert!(lines.buf.len() <= 8);
+        read.extend(lines.finish());
+
+        assert_eq!(
+            read,
+            vec![
+                (1, Ok(b"{\"a\":1}\n".to_vec())),
+                (2, Err("line longer than 8 bytes".to_string())),
+                (3, Ok(b"{}".to_vec())),
+            ]
+        );
+    }
+
+    /// Serializes records to JSONL and reads them back, as an export
+    /// followed by an import does.
+    fn through_jsonl(records: Vec<MemoryRecord>) -> Vec<MemoryRecord> {
+        let mut jsonl = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut jsonl, record).unwrap();
+            jsonl.push(b'\n');
+        }
+        let mut lines = Lines::new(MAX_LINE_BYTES);
+        lines
+            .push(&jsonl)
+            .into_iter()
+            .map(|(_, line)| serde_json::from_slice(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn long_term_records_survive_a_round_trip() {
+        let source = LongTermMemory::in_memory().await;
+        source.set("note", "plain text").await;
+        source.set_value("profile", &json!({ "name": "Ada" })).await;
+        source
+            .set_value("profile", &json!({ "name": "Ada", "age": 36 }))
+            .await;
+        source
+            .import("old", &json!("from 2020"), Some("2020-05-01T10:00:00Z"))
+            .await;
+
+        let (entries, _) = source.list(&ListFilter::default(), 0, 10).await;
+        let records = entries
+            .into_iter()
+            .map(|e| long_record("acme", e))
+            .collect();
+        let target = LongTermMemory::in_memory().await;
+        for record in through_jsonl(records) {
+            assert_eq!(record.namespace, "acme");
+            apply_long(&target, &record).await.unwrap();
+        }
+
+        let (before, _) = source.list(&ListFilter::default(), 0, 10).await;
+        let (after, _) = target.list(&ListFilter::default(), 0, 10).await;
+        assert_eq!(after.len(), 3);
+        for (a, b) in before.iter().zip(&after) {
+            assert_eq!(
+                (&a.key, &a.value, &a.content_type, &a.created_at),
+                (&b.key, &b.value, &b.content_type, &b.created_at)
+            );
+        }
+        assert_eq!(after[1].created_at, "2020-05-01T10:00:00Z");
+        assert_eq!(after[2].value, json!({ "name": "Ada", "age": 36 }));
+    }
+
+    #[test]
+    fn semantic_records_survive_a_round_trip() {
+        let mut source = LatentGraph::new();
+        let mut knife = ObjectCluster::new("knife".into(), vec![1.0, 0.0], vec!["sharp".into()]);
+        knife.model = Some("nomic-embed-text".into());
+        source.add_cluster("knife".into(), knife);
+        source.add_cluster(
+            "kitchen".into(),
+            ObjectCluster::new("kitchen".into(), vec![0.0, 1.0], vec![]),
+        );
+        source.add_relation("knife", "in", "kitchen");
+        source.add_relation("ghost", "haunts", "kitchen");
+
+        let records = semantic_records("acme", GraphSnapshot::from_graph(&source)).unwrap();
+        let mut target = LatentGraph::new();
+        for record in through_jsonl(records) {
+            apply_semantic(&mut target, record).unwrap();
+        }
+
+        assert_eq!(target.clusters.len(), 2);
+        let knife = &target.clusters["knife"];
+        assert_eq!(knife.embedding, vec![1.0, 0.0]);
+        assert_eq!(knife.tags, vec!["sharp"]);
+        assert_eq!(knife.model.as_deref(), Some("nomic-embed-text"));
+        assert!(target.has_relation("knife", "in", "kitchen"));
+        assert!(target.has_relation("ghost", "haunts", "kitchen"));
+        assert_eq!(target.find_nearest(&[0.0, 1.0]).unwrap().0, "kitchen");
+    }
+
+    #[test]
+    fn records_default_to_the_default_namespace() {
+        let record: MemoryRecord =
+            serde_json::from_str(r#"{"layer":"long","key":"k","value":{"a":1}}"#).unwrap();
+        assert_eq!(record.namespace, DEFAULT_NAMESPACE);
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            json!({ "layer": "long", "namespace": "default", "key": "k", "value": { "a": 1 } })
+        );
+    }
+}
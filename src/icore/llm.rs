@@ -1,5 +1,7 @@
 use std::env;
+use std::time::Instant;
 
+use crate::icore::telemetry;
 use anyhow::{Result, anyhow};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
@@ -9,6 +11,8 @@ struct LlamaRequest<'a> {
     prompt: &'a str,
     n_predict: u16,
     stream: bool,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop: &'a [&'a str],
 }
 
 #[derive(Deserialize)]
@@ -18,14 +22,27 @@ struct LlamaResponse {
 }
 
 pub async fn generate_local(prompt: &str) -> Result<String> {
+    generate_local_with(prompt, 128, &[]).await
+}
+
+/// Like [`generate_local`], with a token budget and stop sequences at which
+/// the server ends the completion.
+pub async fn generate_local_with(prompt: &str, n_predict: u16, stop: &[&str]) -> Result<String> {
     if prompt.trim().is_empty() {
         return Err(anyhow!("prompt is empty"));
     }
+    let started = Instant::now();
+    let result = complete(prompt, n_predict, stop).await;
+    telemetry::observe_call("llm", "generate", started, result.is_ok());
+    result
+}
 
+async fn complete(prompt: &str, n_predict: u16, stop: &[&str]) -> Result<String> {
     let req = LlamaRequest {
         prompt,
-        n_predict: 128,
+        n_predict,
         stream: false,
+        stop,
     };
 
     let url = env::var("LLM_URL").map_err(|_| anyhow!("LLM_URL not set"))?;
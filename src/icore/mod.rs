@@ -1,5 +1,13 @@
+pub mod backup;
 pub mod context;
 pub mod embed;
+pub mod embed_cache;
+pub mod health;
+pub mod interchange;
 pub mod llm;
 pub mod model;
+pub mod namespace;
+pub mod outbox;
 pub mod protocol;
+pub mod reembed;
+pub mod telemetry;
@@ -0,0 +1,607 @@
+use crate::agents::AGENTS;
+use crate::icore::context::Context;
+use crate::icore::embed;
+use crate::memory::latent::{LEGACY_MODEL, LatentMemory};
+use crate::memory::semantic::LatentGraph;
+use crate::memory::short_term::ShortTermMemory;
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{HeaderMap, StatusCode, header, request::Parts},
+};
+use jsonwebtoken::{DecodingKey, Validation, decode};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite, sqlite::SqlitePoolOptions};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::{Mutex, RwLock};
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+pub const NAMESPACE_HEADER: &str = "x-icore-namespace";
+const MAX_NAME_LEN: usize = 48;
+
+pub static NAMESPACES: OnceLock<Namespaces> = OnceLock::new();
+
+pub fn namespaces() -> &'static Namespaces {
+    NAMESPACES.get().expect("Namespaces not initialized")
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NamespaceInfo {
+    pub name: String,
+    pub created_at: String,
+}
+
+#[derive(Debug)]
+pub enum NamespaceError {
+    Invalid(String),
+    Exists,
+    NotFound,
+    /// The default namespace cannot be deleted.
+    Protected,
+    Backend(String),
+}
+
+impl NamespaceError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            NamespaceError::Invalid(_) => StatusCode::BAD_REQUEST,
+            NamespaceError::Exists | NamespaceError::Protected => StatusCode::CONFLICT,
+            NamespaceError::NotFound => StatusCode::NOT_FOUND,
+            NamespaceError::Backend(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+impl fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NamespaceError::Invalid(reason) => write!(f, "invalid namespace name: {}", reason),
+            NamespaceError::Exists => write!(f, "namespace already exists"),
+            NamespaceError::NotFound => write!(f, "namespace not found"),
+            NamespaceError::Protected => write!(f, "the default namespace cannot be deleted"),
+            NamespaceError::Backend(e) => write!(f, "namespace storage failed: {}", e),
+        }
+    }
+}
+
+/// Claims read from `Authorization: Bearer <jwt>` tokens (HS256, `exp`
+/// required).
+#[derive(Debug, Deserialize)]
+struct Claims {
+    /// Namespace the token is for; the default one if absent.
+    ns: Option<String>,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// Known namespaces and the memory layers of each.
+///
+/// Short-term memory and the semantic graph are kept per namespace in
+/// process. Long-term memory lives in a table per namespace
+/// (`memories_<name>`) and latent memory in a Chroma collection per
+/// namespace (`mem_<name>`); the default namespace keeps the original
+/// `memories` table and `mem` collection.
+pub struct Namespaces {
+    pool: Pool<Sqlite>,
+    jwt_secret: Option<String>,
+    contexts: RwLock<HashMap<String, Context>>,
+}
+
+impl Namespaces {
+    /// Must be called once the default memory layers are initialized.
+    pub async fn new(database_url: &str, jwt_secret: Option<String>) -> Self {
+        let options = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(database_url)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("Failed to connect to SQLite");
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS namespaces (
+                name TEXT PRIMARY KEY,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            );",
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to create namespaces table");
+        // Active collection and its model; unset until first needed.
+        for column in ["collection", "embed_model"] {
+            sqlx::query(&format!(
+                "ALTER TABLE namespaces ADD COLUMN {} TEXT",
+                column
+            ))
+            .execute(&pool)
+            .await
+            .ok();
+        }
+        sqlx::query("INSERT OR IGNORE INTO namespaces (name) VALUES (?)")
+            .bind(DEFAULT_NAMESPACE)
+            .execute(&pool)
+            .await
+            .expect("Failed to register the default namespace");
+
+        let contexts = HashMap::from([(DEFAULT_NAMESPACE.to_string(), Context::new())]);
+        let namespaces = Self {
+            pool,
+            jwt_secret,
+            contexts: RwLock::new(contexts),
+        };
+        if let Err(e) = namespaces.sync_default().await {
+            tracing::warn!("default namespace: {}", e);
+        }
+        namespaces
+    }
+
+    /// Points the default namespace at its recorded collection and model.
+    async fn sync_default(&self) -> Result<(), String> {
+        let ctx = Context::new();
+        let current = ctx.mem_latent.lock().await.clone();
+        let latent = self.latent_for(DEFAULT_NAMESPACE, &current, true).await?;
+        *ctx.mem_latent.lock().await = latent;
+        Ok(())
+    }
+
+    /// The latent memory of `name`, in the collection recorded for it.
+    /// Without one it is `base` itself if `is_current`, else the
+    /// namespace's first collection. If Chroma cannot be reached the handle
+    /// is left detached until [`Namespaces::reconnect`] resolves it.
+    async fn latent_for(
+        &self,
+        name: &str,
+        base: &LatentMemory,
+        is_current: bool,
+    ) -> Result<LatentMemory, String> {
+        let (collection, model): (Option<String>, Option<String>) =
+            sqlx::query_as("SELECT collection, embed_model FROM namespaces WHERE name = ?")
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .await
+                .map_err(|e| e.to_string())?
+                .unwrap_or_default();
+        let recorded = model.is_some();
+        let model = model.unwrap_or_else(|| LEGACY_MODEL.to_string());
+        let collection = match collection {
+            Some(collection) => collection,
+            None if is_current => {
+                let mut latent = base.clone();
+                latent.model = model;
+                if !recorded {
+                    self.record_model(name, &mut latent).await?;
+                }
+                return Ok(latent);
+            }
+            None => LatentMemory::collection_for(name),
+        };
+        let mut latent = match base.with_collection(&collection, &model).await {
+            Ok(latent) => latent,
+            Err(e) => {
+                tracing::warn!(
+                    "namespace '{}': collection '{}' unavailable: {}",
+                    name,
+                    collection,
+                    e
+                );
+                base.detached(&collection, &model)
+            }
+        };
+        if !recorded {
+            self.record_model(name, &mut latent).await?;
+        }
+        Ok(latent)
+    }
+
+    /// Namespaces from before models were recorded get one: the legacy
+    /// model if the collection already holds vectors, the configured one if
+    /// it is empty. Left for later while Chroma cannot be asked.
+    async fn record_model(&self, name: &str, latent: &mut LatentMemory) -> Result<(), String> {
+        if !latent.is_connected() {
+            return Ok(());
+        }
+        match latent.count().await {
+            Ok(0) => latent.model = embed::default_model(),
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("namespace '{}': cannot count vectors: {}", name, e);
+                return Ok(());
+            }
+        }
+        sqlx::query("UPDATE namespaces SET embed_model = ? WHERE name = ?")
+            .bind(&latent.model)
+            .bind(name)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Connects the collections of opened namespaces that Chroma could not
+    /// resolve before. Returns how many are still detached.
+    pub async fn reconnect(&self) -> usize {
+        let mut detached = 0;
+        for ctx in self.opened().await {
+            let mut latent = ctx.mem_latent.lock().await;
+            if latent.is_connected() {
+                continue;
+            }
+            if let Err(e) = latent.connect().await {
+                tracing::debug!("namespace '{}': still detached: {}", ctx.namespace, e);
+                detached += 1;
+                continue;
+            }
+            tracing::info!(
+                "namespace '{}': collection '{}' connected",
+                ctx.namespace,
+                latent.collection_name
+            );
+            if ctx.namespace == DEFAULT_NAMESPACE
+                && let Err(e) = latent.save_id()
+            {
+                tracing::warn!("default namespace: {}", e);
+            }
+            let recorded: Option<String> =
+                sqlx::query_scalar("SELECT embed_model FROM namespaces WHERE name = ?")
+                    .bind(&ctx.namespace)
+                    .fetch_optional(&self.pool)
+                    .await
+                    .ok()
+                    .flatten()
+                    .flatten();
+            if recorded.is_none()
+                && let Err(e) = self.record_model(&ctx.namespace, &mut latent).await
+            {
+                tracing::warn!("namespace '{}': {}", ctx.namespace, e);
+            }
+        }
+        detached
+    }
+
+    /// Makes `latent` the collection of `name` from now on. The caller
+    /// swaps it into the namespace's context.
+    pub async fn switch_collection(&self, name: &str, latent: &LatentMemory) -> Result<(), String> {
+        sqlx::query("UPDATE namespaces SET collection = ?, embed_model = ? WHERE name = ?")
+            .bind(&latent.collection_name)
+            .bind(&latent.model)
+            .bind(name)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| e.to_string())?;
+        if name == DEFAULT_NAMESPACE {
+            latent.save_id()?;
+        }
+        Ok(())
+    }
+
+    /// Contexts of the namespaces opened so far.
+    pub async fn opened(&self) -> Vec<Context> {
+        self.contexts.read().await.values().cloned().collect()
+    }
+
+    /// Connection pool on the SQLite database behind every namespace.
+    pub fn pool(&self) -> &Pool<Sqlite> {
+        &self.pool
+    }
+
+    pub async fn list(&self) -> Vec<NamespaceInfo> {
+        sqlx::query(
+            "SELECT name, strftime('%Y-%m-%dT%H:%M:%SZ', created_at) AS created_at
+             FROM namespaces ORDER BY name",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|row| NamespaceInfo {
+            name: row.get("name"),
+            created_at: row.get("created_at"),
+        })
+        .collect()
+    }
+
+    async fn exists(&self, name: &str) -> bool {
+        sqlx::query("SELECT 1 FROM namespaces WHERE name = ?")
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await
+            .ok()
+            .flatten()
+            .is_some()
+    }
+
+    /// Registers `name` and provisions its table and collection.
+    pub async fn create(&self, name: &str) -> Result<(), NamespaceError> {
+        validate_name(name).map_err(NamespaceError::Invalid)?;
+        let inserted = sqlx::query("INSERT OR IGNORE INTO namespaces (name) VALUES (?)")
+            .bind(name)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| NamespaceError::Backend(e.to_string()))?
+            .rows_affected();
+        if inserted == 0 {
+            return Err(NamespaceError::Exists);
+        }
+        if let Err(e) = self.context(name).await {
+            self.unregister(name).await;
+            return Err(e);
+        }
+        tracing::info!("namespace '{}' created", name);
+        Ok(())
+    }
+
+    /// Removes `name` and everything stored under it.
+    pub async fn delete(&self, name: &str) -> Result<(), NamespaceError> {
+        if name == DEFAULT_NAMESPACE {
+            return Err(NamespaceError::Protected);
+        }
+        let ctx = self.context(name).await?;
+        self.unregister(name).await;
+        if let Some(agents) = AGENTS.get() {
+            agents.forget_namespace(name).await;
+        }
+        ctx.mem_long
+            .drop_table()
+            .await
+            .map_err(NamespaceError::Backend)?;
+        ctx.mem_episodic
+            .clear()
+            .await
+            .map_err(NamespaceError::Backend)?;
+        let latent = ctx.mem_latent.lock().await;
+        if let Err(e) = latent.delete_collection().await {
+            tracing::warn!(
+                "namespace '{}': failed to delete collection '{}': {}",
+                name,
+                latent.collection_name,
+                e
+            );
+        }
+        tracing::info!("namespace '{}' deleted", name);
+        Ok(())
+    }
+
+    async fn unregister(&self, name: &str) {
+        self.contexts.write().await.remove(name);
+        sqlx::query("DELETE FROM namespaces WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await
+            .ok();
+    }
+
+    /// Forgets every opened namespace but the default one, so the next use
+    /// reopens it from storage, and re-reads the default one's collection.
+    pub async fn reset(&self) {
+        self.contexts
+            .write()
+            .await
+            .retain(|name, _| name == DEFAULT_NAMESPACE);
+        if let Err(e) = self.sync_default().await {
+            tracing::warn!("default namespace: {}", e);
+        }
+    }
+
+    /// Memory layers of `name`, opened on first use.
+    pub async fn context(&self, name: &str) -> Result<Context, NamespaceError> {
+        if let Some(ctx) = self.contexts.read().await.get(name) {
+            return Ok(ctx.clone());
+        }
+        if !self.exists(name).await {
+            return Err(NamespaceError::NotFound);
+        }
+
+        let base = Context::new();
+        let mem_long = base
+            .mem_long
+            .scoped(name)
+            .await
+            .map_err(NamespaceError::Backend)?;
+        let latent = base.mem_latent.lock().await.clone();
+        let mem_latent = self
+            .latent_for(name, &latent, false)
+            .await
+            .map_err(NamespaceError::Backend)?;
+        let ctx = Context {
+            namespace: name.to_string(),
+            mem_short: ShortTermMemory::new(),
+            mem_long,
+            mem_episodic: base.mem_episodic.scoped(name),
+            mem_latent: Arc::new(Mutex::new(mem_latent)),
+            mem_semantic: Arc::new(std::sync::Mutex::new(LatentGraph::new())),
+        };
+        // Another request may have opened it meanwhile; keep the first.
+        let mut contexts = self.contexts.write().await;
+        Ok(contexts.entry(name.to_string()).or_insert(ctx).clone())
+    }
+
+    /// Picks the namespace of a request. A bearer token decides when one is
+    /// sent, and `X-Icore-Namespace` must then agree with it. Without a
+    /// token the header is trusted, unless `JWT_SECRET` is set, in which
+    /// case only the default namespace is open.
+    pub fn resolve(&self, headers: &HeaderMap) -> Result<String, (StatusCode, String)> {
+        let requested = match headers.get(NAMESPACE_HEADER) {
+            Some(value) => Some(value.to_str().map(str::trim).map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    "invalid X-Icore-Namespace header".to_string(),
+                )
+            })?),
+            None => None,
+        };
+        match (self.claims(headers)?, requested) {
+            (Some(claims), requested) => {
+                let granted = claims.ns.unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+                if requested.is_some_and(|r| r != granted) {
+                    return Err((
+                        StatusCode::FORBIDDEN,
+                        format!("token is for namespace '{}'", granted),
+                    ));
+                }
+                Ok(granted)
+            }
+            (None, Some(requested))
+                if self.jwt_secret.is_some() && requested != DEFAULT_NAMESPACE =>
+            {
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    format!("namespace '{}' requires a token", requested),
+                ))
+            }
+            (None, requested) => Ok(requested.unwrap_or(DEFAULT_NAMESPACE).to_string()),
+        }
+    }
+
+    /// Admin endpoints are open unless `JWT_SECRET` is set; then they need
+    /// a token with `"admin": true`.
+    pub fn authorize_admin(&self, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        if self.jwt_secret.is_none() {
+            return Ok(());
+        }
+        match self.claims(headers)? {
+            Some(claims) if claims.admin => Ok(()),
+            Some(_) => Err((StatusCode::FORBIDDEN, "admin token required".to_string())),
+            None => Err((StatusCode::UNAUTHORIZED, "admin token required".to_string())),
+        }
+    }
+
+    fn claims(&self, headers: &HeaderMap) -> Result<Option<Claims>, (StatusCode, String)> {
+        let Some(value) = headers.get(header::AUTHORIZATION) else {
+            return Ok(None);
+        };
+        let unauthorized = |message: String| (StatusCode::UNAUTHORIZED, message);
+        let token = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized("expected a Bearer token".to_string()))?;
+        let secret = self
+            .jwt_secret
+            .as_deref()
+            .ok_or_else(|| unauthorized("token auth is not configured".to_string()))?;
+        decode::<Claims>(
+            token.trim(),
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::default(),
+        )
+        .map(|data| Some(data.claims))
+        .map_err(|e| unauthorized(format!("invalid token: {}", e)))
+    }
+}
+
+/// Names become part of table and collection names, so they are kept to
+/// lowercase letters, digits and `_`, starting with a letter.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("must be 1 to {} characters", MAX_NAME_LEN));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+        return Err("must start with a lowercase letter".to_string());
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err("only lowercase letters, digits and '_' are allowed".to_string());
+    }
+    Ok(())
+}
+
+/// Lets handlers take the [`Context`] of the request's namespace.
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Context {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let namespaces = namespaces();
+        let name = namespaces.resolve(&parts.headers)?;
+        namespaces.context(&name).await.map_err(|e| match e {
+            NamespaceError::NotFound => (
+                StatusCode::NOT_FOUND,
+                format!("namespace '{}' not found", name),
+            ),
+            e => (e.status(), e.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+
+    fn registry(secret: Option<&str>) -> Namespaces {
+        Namespaces {
+            pool: SqlitePoolOptions::new()
+                .connect_lazy("sqlite::memory:")
+                .unwrap(),
+            jwt_secret: secret.map(str::to_string),
+            contexts: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn headers(token: Option<serde_json::Value>, namespace: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(claims) = token {
+            let token = encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(b"secret"),
+            )
+            .unwrap();
+            headers.insert(
+                header::AUTHORIZATION,
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+        }
+        if let Some(ns) = namespace {
+            headers.insert(NAMESPACE_HEADER, ns.parse().unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn tokens_decide_the_namespace() {
+        let exp = chrono::Utc::now().timestamp() + 60;
+        let open = registry(None);
+        assert_eq!(open.resolve(&headers(None, None)).unwrap(), "default");
+        assert_eq!(open.resolve(&headers(None, Some("acme"))).unwrap(), "acme");
+
+        let secured = registry(Some("secret"));
+        let acme = json!({ "ns": "acme", "exp": exp });
+        assert_eq!(
+            secured.resolve(&headers(Some(acme.clone()), None)).unwrap(),
+            "acme"
+        );
+        assert_eq!(
+            secured
+                .resolve(&headers(Some(acme.clone()), Some("other")))
+                .unwrap_err()
+                .0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            secured.resolve(&headers(None, Some("acme"))).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        let expired = json!({ "ns": "acme", "exp": exp - 3600 });
+        assert!(secured.resolve(&headers(Some(expired), None)).is_err());
+        assert!(secured.authorize_admin(&headers(Some(acme), None)).is_err());
+        let admin = json!({ "admin": true, "exp": exp });
+        assert!(secured.authorize_admin(&headers(Some(admin), None)).is_ok());
+    }
+
+    #[test]
+    fn names_are_safe_identifiers() {
+        assert!(validate_name("acme_prod2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("2fast").is_err());
+        assert!(validate_name("Acme").is_err());
+        assert!(validate_name("a-b").is_err());
+        assert!(validate_name("x; DROP TABLE memories").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,597 @@
+use crate::icore::embed;
+use crate::icore::namespace::{NamespaceError, namespaces};
+use crate::memory::semantic::object::{AffectScore, ObjectCluster};
+use crate::memory::value;
+use axum::http::StatusCode;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Row, SqliteConnection, sqlite::SqliteRow};
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Jobs claimed per round.
+const BATCH_SIZE: i64 = 50;
+/// Longest wait between rounds; detached collections are retried then too.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Upper bound of the retry delay.
+const MAX_BACKOFF_SECS: i64 = 3600;
+
+/// Wakes the worker when a job is queued or replayed.
+static WAKE: Lazy<Notify> = Lazy::new(Notify::new);
+
+const JOB_COLUMNS: &str = "id, kind, namespace, payload, status, attempts, last_error,
+    strftime('%Y-%m-%dT%H:%M:%SZ', next_run_at, 'unixepoch') AS next_run_at,
+    strftime('%Y-%m-%dT%H:%M:%SZ', created_at) AS created_at,
+    strftime('%Y-%m-%dT%H:%M:%SZ', updated_at) AS updated_at";
+
+/// Indexing work deferred from a request. Each task embeds its text with
+/// the namespace's model (the embedding cache makes repeats cheap), so it
+/// can be retried on its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+    /// Upserts the vector of `text` under `id` in the namespace's collection.
+    Vector { id: String, text: String },
+    /// Adds `text` to the namespace's semantic graph as cluster `id`.
+    Cluster { id: String, text: String },
+    /// Adds a ready-made embedding to the semantic graph as unknown
+    /// cluster `id`.
+    Embedding { id: String, embedding: Vec<f32> },
+    /// Removes the vectors stored under `ids` from the namespace's
+    /// collection.
+    Delete { ids: Vec<String> },
+}
+
+impl Task {
+    fn kind(&self) -> &'static str {
+        match self {
+            Task::Vector { .. } => "vector",
+            Task::Cluster { .. } => "cluster",
+            Task::Embedding { .. } => "embedding",
+            Task::Delete { .. } => "delete",
+        }
+    }
+
+    /// Indexes a long-term entry in the namespace's collection as
+    /// `long:<key>`.
+    pub fn long_entry(key: &str, value: &Value) -> Self {
+        Task::Vector {
+            id: format!("long:{}", key),
+            text: value::to_text(value),
+        }
+    }
+
+    /// Removes the `long:<key>` vectors of deleted long-term entries.
+    pub fn forget_long(keys: &[String]) -> Self {
+        Task::Delete {
+            ids: keys.iter().map(|key| format!("long:{}", key)).collect(),
+        }
+    }
+}
+
+/// The long-term key a `long:<key>` vector belongs to.
+fn long_key(id: &str) -> Option<&str> {
+    id.strip_prefix("long:")
+}
+
+/// A queued task. `pending` jobs run once `next_run_at` has passed; a job
+/// that keeps failing becomes `dead` and stays until it is replayed or
+/// deleted. Jobs that succeed are removed.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub namespace: String,
+    pub task: Value,
+    /// `pending`, `running` or `dead`.
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub next_run_at: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct QueueStats {
+    pub pending: i64,
+    pub running: i64,
+    pub dead: i64,
+}
+
+#[derive(Debug)]
+pub enum JobError {
+    NotFound,
+    /// The job is being processed.
+    Running,
+    Backend(String),
+}
+
+impl JobError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JobError::NotFound => StatusCode::NOT_FOUND,
+            JobError::Running => StatusCode::CONFLICT,
+            JobError::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::NotFound => write!(f, "job not found"),
+            JobError::Running => write!(f, "job is running"),
+            JobError::Backend(e) => write!(f, "job queue failed: {}", e),
+        }
+    }
+}
+
+/// Retry policy of the worker.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Failed runs before a job is dead-lettered.
+    pub max_attempts: i64,
+    /// Delay after the first failure; doubled after each further one.
+    pub backoff_secs: i64,
+}
+
+impl RetryPolicy {
+    /// Seconds to wait after the `attempts`-th failure.
+    fn delay(&self, attempts: i64) -> i64 {
+        let exponent = (attempts - 1).clamp(0, 30) as u32;
+        self.backoff_secs
+            .max(1)
+            .saturating_mul(1 << exponent)
+            .min(MAX_BACKOFF_SECS)
+    }
+}
+
+/// Creates the queue table and starts the worker. Jobs that were running
+/// when the server stopped are run again.
+pub async fn init(policy: RetryPolicy) {
+    let pool = namespaces().pool();
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS outbox_jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            kind TEXT NOT NULL,
+            namespace TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            next_run_at INTEGER NOT NULL DEFAULT (unixepoch()),
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );",
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to create outbox_jobs table");
+    sqlx::query("CREATE INDEX IF NOT EXISTS outbox_jobs_due ON outbox_jobs (status, next_run_at)")
+        .execute(pool)
+        .await
+        .expect("Failed to index outbox_jobs");
+    sqlx::query("UPDATE outbox_jobs SET status = 'pending' WHERE status = 'running'")
+        .execute(pool)
+        .await
+        .ok();
+    migrate_pending().await;
+
+    tokio::spawn(async move {
+        loop {
+            namespaces().reconnect().await;
+            let done = drain(policy).await;
+            if done > 0 {
+                tracing::info!("outbox: {} jobs done", done);
+            }
+            let wait = next_due().await.unwrap_or(POLL_INTERVAL).min(POLL_INTERVAL);
+            tokio::select! {
+                _ = WAKE.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    });
+}
+
+/// Moves inputs queued by earlier versions into the outbox.
+async fn migrate_pending() {
+    let pool = namespaces().pool();
+    let exists = sqlx::query(
+        "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'pending_embeddings'",
+    )
+    .fetch_optional(pool)
+    .await
+    .ok()
+    .flatten()
+    .is_some();
+    if !exists {
+        return;
+    }
+    let result = sqlx::query(
+        "INSERT INTO outbox_jobs (kind, namespace, payload)
+         SELECT 'vector', namespace,
+                json_object('kind', 'vector', 'id', item_id, 'text', text)
+         FROM pending_embeddings
+         UNION ALL
+         SELECT 'cluster', namespace,
+                json_object('kind', 'cluster', 'id', item_id, 'text', text)
+         FROM pending_embeddings WHERE cluster",
+    )
+    .execute(pool)
+    .await;
+    let result = match result {
+        Ok(_) => {
+            sqlx::query("DROP TABLE pending_embeddings")
+                .execute(pool)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::warn!("outbox: failed to migrate pending_embeddings: {}", e);
+    }
+}
+
+/// Queues `task` for `namespace` and wakes the worker.
+pub async fn enqueue(namespace: &str, task: &Task) -> Result<i64, String> {
+    let mut conn = namespaces()
+        .pool()
+        .acquire()
+        .await
+        .map_err(|e| e.to_string())?;
+    let id = enqueue_in(&mut conn, namespace, task).await?;
+    wake();
+    Ok(id)
+}
+
+/// Queues `task` on the caller's connection, so that inside a transaction
+/// the job is stored exactly when the caller's own writes are. Call
+/// [`wake`] once the transaction has committed.
+pub async fn enqueue_in(
+    conn: &mut SqliteConnection,
+    namespace: &str,
+    task: &Task,
+) -> Result<i64, String> {
+    let payload = serde_json::to_string(task).map_err(|e| e.to_string())?;
+    sqlx::query_scalar(
+        "INSERT INTO outbox_jobs (kind, namespace, payload) VALUES (?, ?, ?) RETURNING id",
+    )
+    .bind(task.kind())
+    .bind(namespace)
+    .bind(payload)
+    .fetch_one(conn)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Lets the worker pick up newly queued jobs.
+pub fn wake() {
+    WAKE.notify_one();
+}
+
+fn to_job(row: &SqliteRow) -> Job {
+    let payload: String = row.get("payload");
+    Job {
+        id: row.get("id"),
+        kind: row.get("kind"),
+        namespace: row.get("namespace"),
+        task: serde_json::from_str(&payload).unwrap_or(Value::String(payload)),
+        status: row.get("status"),
+        attempts: row.get("attempts"),
+        last_error: row.get("last_error"),
+        next_run_at: row.get("next_run_at"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+    }
+}
+
+/// Jobs matching the filters, oldest first, and how many match in all.
+pub async fn list(
+    status: Option<&str>,
+    kind: Option<&str>,
+    namespace: Option<&str>,
+    offset: usize,
+    limit: usize,
+) -> (Vec<Job>, usize) {
+    let filter = "WHERE (?1 IS NULL OR status = ?1)
+        AND (?2 IS NULL OR kind = ?2)
+        AND (?3 IS NULL OR namespace = ?3)";
+    let pool = namespaces().pool();
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM outbox_jobs {}", filter))
+        .bind(status)
+        .bind(kind)
+        .bind(namespace)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(0);
+    let jobs = sqlx::query(&format!(
+        "SELECT {} FROM outbox_jobs {} ORDER BY id LIMIT ?4 OFFSET ?5",
+        JOB_COLUMNS, filter
+    ))
+    .bind(status)
+    .bind(kind)
+    .bind(namespace)
+    .bind(limit as i64)
+    .bind(offset as i64)
+    .fetch_all(pool)
+    .await
+    .unwrap_or_default()
+    .iter()
+    .map(to_job)
+    .collect();
+    (jobs, total as usize)
+}
+
+pub async fn get(id: i64) -> Option<Job> {
+    sqlx::query(&format!(
+        "SELECT {} FROM outbox_jobs WHERE id = ?",
+        JOB_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(namespaces().pool())
+    .await
+    .ok()
+    .flatten()
+    .map(|row| to_job(&row))
+}
+
+pub async fn stats() -> QueueStats {
+    let rows: Vec<(String, i64)> =
+        sqlx::query_as("SELECT status, COUNT(*) FROM outbox_jobs GROUP BY status")
+            .fetch_all(namespaces().pool())
+            .await
+            .unwrap_or_default();
+    let mut stats = QueueStats::default();
+    for (status, count) in rows {
+        match status.as_str() {
+            "pending" => stats.pending = count,
+            "running" => stats.running = count,
+            "dead" => stats.dead = count,
+            _ => {}
+        }
+    }
+    stats
+}
+
+/// Runs a job again right away with a fresh attempt count.
+pub async fn replay(id: i64) -> Result<Job, JobError> {
+    let job = get(id).await.ok_or(JobError::NotFound)?;
+    if job.status == "running" {
+        return Err(JobError::Running);
+    }
+    sqlx::query(
+        "UPDATE outbox_jobs SET status = 'pending', attempts = 0, next_run_at = unixepoch(),
+             updated_at = CURRENT_TIMESTAMP
+         WHERE id = ?",
+    )
+    .bind(id)
+    .execute(namespaces().pool())
+    .await
+    .map_err(|e| JobError::Backend(e.to_string()))?;
+    WAKE.notify_one();
+    get(id).await.ok_or(JobError::NotFound)
+}
+
+/// Replays every dead job. Returns how many there were.
+pub async fn replay_dead() -> Result<u64, JobError> {
+    let replayed = sqlx::query(
+        "UPDATE outbox_jobs SET status = 'pending', attempts = 0, next_run_at = unixepoch(),
+             updated_at = CURRENT_TIMESTAMP
+         WHERE status = 'dead'",
+    )
+    .execute(namespaces().pool())
+    .await
+    .map_err(|e| JobError::Backend(e.to_string()))?
+    .rows_affected();
+    WAKE.notify_one();
+    Ok(replayed)
+}
+
+/// Drops a job that is not running.
+pub async fn delete(id: i64) -> Result<(), JobError> {
+    let job = get(id).await.ok_or(JobError::NotFound)?;
+    if job.status == "running" {
+        return Err(JobError::Running);
+    }
+    sqlx::query("DELETE FROM outbox_jobs WHERE id = ?")
+        .bind(id)
+        .execute(namespaces().pool())
+        .await
+        .map_err(|e| JobError::Backend(e.to_string()))?;
+    Ok(())
+}
+
+/// Time until the next pending job is due.
+async fn next_due() -> Option<Duration> {
+    let secs: Option<i64> = sqlx::query_scalar(
+        "SELECT MIN(next_run_at) - unixepoch() FROM outbox_jobs WHERE status = 'pending'",
+    )
+    .fetch_one(namespaces().pool())
+    .await
+    .ok()
+    .flatten();
+    secs.map(|s| Duration::from_secs(s.max(1) as u64))
+}
+
+/// Runs due jobs, oldest first. Returns how many succeeded.
+async fn drain(policy: RetryPolicy) -> usize {
+    let pool = namespaces().pool();
+    let mut done = 0;
+    loop {
+        let rows = sqlx::query(
+            "SELECT id, namespace, payload, attempts FROM outbox_jobs
+             WHERE status = 'pending' AND next_run_at <= unixepoch()
+             ORDER BY id LIMIT ?",
+        )
+        .bind(BATCH_SIZE)
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default();
+        if rows.is_empty() {
+            return done;
+        }
+        for row in rows {
+            let id: i64 = row.get("id");
+            let namespace: String = row.get("namespace");
+            let payload: String = row.get("payload");
+            let attempts: i64 = row.get::<i64, _>("attempts") + 1;
+            sqlx::query(
+                "UPDATE outbox_jobs SET status = 'running', updated_at = CURRENT_TIMESTAMP
+                 WHERE id = ?",
+            )
+            .bind(id)
+            .execute(pool)
+            .await
+            .ok();
+
+            let result = match serde_json::from_str::<Task>(&payload) {
+                Ok(task) => run(&namespace, task).await,
+                Err(e) => Err(format!("invalid task: {}", e)),
+            };
+            match result {
+                Ok(()) => {
+                    sqlx::query("DELETE FROM outbox_jobs WHERE id = ?")
+                        .bind(id)
+                        .execute(pool)
+                        .await
+                        .ok();
+                    done += 1;
+                }
+                Err(e) => {
+                    let dead = attempts >= policy.max_attempts;
+                    if dead {
+                        tracing::error!(
+                            "outbox: job {} dead after {} attempts: {}",
+                            id,
+                            attempts,
+                            e
+                        );
+                    } else {
+                        tracing::warn!("outbox: job {} failed (attempt {}): {}", id, attempts, e);
+                    }
+                    sqlx::query(
+                        "UPDATE outbox_jobs SET status = ?, attempts = ?, last_error = ?,
+                             next_run_at = unixepoch() + ?, updated_at = CURRENT_TIMESTAMP
+                         WHERE id = ?",
+                    )
+                    .bind(if dead { "dead" } else { "pending" })
+                    .bind(attempts)
+                    .bind(&e)
+                    .bind(policy.delay(attempts))
+                    .bind(id)
+                    .execute(pool)
+                    .await
+                    .ok();
+                }
+            }
+        }
+    }
+}
+
+async fn run(namespace: &str, task: Task) -> Result<(), String> {
+    let ctx = match namespaces().context(namespace).await {
+        Ok(ctx) => ctx,
+        // Deleted since; nothing left to index.
+        Err(NamespaceError::NotFound) => return Ok(()),
+        Err(e) => return Err(e.to_string()),
+    };
+    match task {
+        Task::Vector { id, text } => {
+            // A delete that ran while this job waited for a retry has
+            // already removed the vector; don't bring it back.
+            if let Some(key) = long_key(&id)
+                && ctx.mem_long.record(key).await.is_none()
+            {
+                return Ok(());
+            }
+            // The model is read before embedding; if a re-embedding
+            // switches the collection meanwhile, the write is refused and
+            // the retry embeds with the new model.
+            let model = ctx.embed_model().await;
+            let embedding = embed::embed_with(&model, &text)
+                .await
+                .map_err(|e| e.to_string())?;
+            ctx.embed_latent(&id, embedding, &text, &model).await
+        }
+        Task::Cluster { id, text } => {
+            // Tagged with the model it was embedded with, even if a
+            // re-embedding switches the namespace meanwhile.
+            let model = ctx.embed_model().await;
+            let embedding = embed::embed_with(&model, &text)
+                .await
+                .map_err(|e| e.to_string())?;
+            ctx.add_input_cluster(&id, embedding, model);
+            Ok(())
+        }
+        Task::Embedding { id, embedding } => {
+            let cluster = ObjectCluster {
+                name: id.clone(),
+                embedding,
+                tags: vec![],
+                affect: AffectScore::from_value(0.0),
+                known: false,
+                model: None,
+            };
+            let mut graph = ctx.mem_semantic.lock().map_err(|e| e.to_string())?;
+            graph.add_cluster(id, cluster);
+            Ok(())
+        }
+        Task::Delete { ids } => {
+            // Keys written again since keep the vector of the newer write.
+            let mut gone = Vec::with_capacity(ids.len());
+            for id in ids {
+                match long_key(&id) {
+                    Some(key) if ctx.mem_long.record(key).await.is_some() => {}
+                    _ => gone.push(id),
+                }
+            }
+            ctx.mem_latent.lock().await.delete(&gone).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            backoff_secs: 5,
+        };
+        assert_eq!(policy.delay(1), 5);
+        assert_eq!(policy.delay(2), 10);
+        assert_eq!(policy.delay(4), 40);
+        assert_eq!(policy.delay(40), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn tasks_are_tagged_by_kind() {
+        let task = Task::Cluster {
+            id: "chat_1".into(),
+            text: "hi".into(),
+        };
+        let json = serde_json::to_value(&task).unwrap();
+        assert_eq!(json["kind"], task.kind());
+        assert!(matches!(
+            serde_json::from_value(json).unwrap(),
+            Task::Cluster { .. }
+        ));
+
+        let Task::Vector { id, text } = Task::long_entry("profile", &serde_json::json!({"a": 1}))
+        else {
+            panic!("long entries are indexed as vectors");
+        };
+        assert_eq!(id, "long:profile");
+        assert_eq!(text, r#"{"a":1}"#);
+
+        let Task::Delete { ids } = Task::forget_long(&["profile".to_string()]) else {
+            panic!("deleted entries drop their vectors");
+        };
+        assert_eq!(ids, vec!["long:profile"]);
+    }
+}
@@ -0,0 +1,624 @@
+use crate::icore::embed;
+use crate::icore::namespace::{DEFAULT_NAMESPACE, NamespaceError, namespaces};
+use crate::memory::latent::{DEFAULT_COLLECTION, LatentMemory, VectorRecord, vector_metadata};
+use crate::memory::semantic::LatentGraph;
+use crate::memory::value;
+use axum::http::StatusCode;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{Row, sqlite::SqliteRow};
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// Vectors read from the source collection per step; progress is saved
+/// after each one.
+const PAGE_SIZE: usize = 100;
+/// Attempts per embedding before the job fails.
+const EMBED_ATTEMPTS: u32 = 3;
+
+/// Jobs with a worker in this process.
+static ACTIVE: Lazy<std::sync::Mutex<HashSet<i64>>> = Lazy::new(Default::default);
+
+const JOB_COLUMNS: &str = "id, namespace, model, source_collection, target_collection, status,
+    total, position, embedded, skipped, error,
+    strftime('%Y-%m-%dT%H:%M:%SZ', created_at) AS created_at,
+    strftime('%Y-%m-%dT%H:%M:%SZ', updated_at) AS updated_at";
+
+/// Rebuilds a namespace's collection with another embedding model. The
+/// source collection is read in pages and every vector's stored document
+/// is embedded again into a new collection. `position` is how far the
+/// source has been read, so a job that stopped carries on from there.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: i64,
+    pub namespace: String,
+    pub model: String,
+    pub source_collection: String,
+    pub target_collection: String,
+    /// `running`, `completed` or `failed`.
+    pub status: String,
+    pub total: i64,
+    pub position: i64,
+    pub embedded: i64,
+    /// Vectors without a stored document, which cannot be re-embedded.
+    pub skipped: i64,
+    pub progress: f64,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug)]
+pub enum ReembedError {
+    Namespace(NamespaceError),
+    NotFound,
+    /// The namespace already has a running job.
+    Busy(i64),
+    /// The job is running or already completed.
+    NotResumable(String),
+    Backend(String),
+}
+
+impl ReembedError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ReembedError::Namespace(e) => e.status(),
+            ReembedError::NotFound => StatusCode::NOT_FOUND,
+            ReembedError::Busy(_) | ReembedError::NotResumable(_) => StatusCode::CONFLICT,
+            ReembedError::Backend(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for ReembedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReembedError::Namespace(e) => e.fmt(f),
+            ReembedError::NotFound => write!(f, "re-embedding job not found"),
+            ReembedError::Busy(id) => {
+                write!(f, "job {} is already re-embedding this namespace", id)
+            }
+            ReembedError::NotResumable(status) => write!(f, "job is {}", status),
+            ReembedError::Backend(e) => write!(f, "re-embedding failed: {}", e),
+        }
+    }
+}
+
+fn backend(e: impl ToString) -> ReembedError {
+    ReembedError::Backend(e.to_string())
+}
+
+/// Creates the jobs table and resumes jobs that were running when the
+/// server stopped.
+pub async fn init() {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS reembed_jobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            namespace TEXT NOT NULL,
+            model TEXT NOT NULL,
+            source_collection TEXT NOT NULL,
+            target_collection TEXT NOT NULL DEFAULT '',
+            status TEXT NOT NULL DEFAULT 'running',
+            total INTEGER NOT NULL DEFAULT 0,
+            position INTEGER NOT NULL DEFAULT 0,
+            embedded INTEGER NOT NULL DEFAULT 0,
+            skipped INTEGER NOT NULL DEFAULT 0,
+            error TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        );",
+    )
+    .execute(namespaces().pool())
+    .await
+    .expect("Failed to create reembed_jobs table");
+
+    for job in list().await {
+        if job.status == "running" {
+            tracing::info!("resuming re-embedding job {}", job.id);
+            spawn(job.id);
+        }
+    }
+}
+
+fn to_job(row: &SqliteRow) -> Job {
+    let total: i64 = row.get("total");
+    let position: i64 = row.get("position");
+    Job {
+        id: row.get("id"),
+        namespace: row.get("namespace"),
+        model: row.get("model"),
+        source_collection: row.get("source_collection"),
+        target_collection: row.get("target_collection"),
+        status: row.get("status"),
+        total,
+        position,
+        embedded: row.get("embedded"),
+        skipped: row.get("skipped"),
+        progress: if total > 0 {
+            (position as f64 / total as f64).min(1.0)
+        } else {
+            0.0
+        },
+        error: row.get("error"),
+        created_at: row.get("created_at"),
+        updated_at: row.get("updated_at"),
+    }
+}
+
+pub async fn list() -> Vec<Job> {
+    sqlx::query(&format!(
+        "SELECT {} FROM reembed_jobs ORDER BY id DESC",
+        JOB_COLUMNS
+    ))
+    .fetch_all(namespaces().pool())
+    .await
+    .unwrap_or_default()
+    .iter()
+    .map(to_job)
+    .collect()
+}
+
+pub async fn get(id: i64) -> Option<Job> {
+    sqlx::query(&format!(
+        "SELECT {} FROM reembed_jobs WHERE id = ?",
+        JOB_COLUMNS
+    ))
+    .bind(id)
+    .fetch_optional(namespaces().pool())
+    .await
+    .ok()
+    .flatten()
+    .map(|row| to_job(&row))
+}
+
+/// Starts re-embedding `namespace` with `model` (the configured model when
+/// `None`) into a new collection.
+pub async fn start(namespace: &str, model: Option<String>) -> Result<Job, ReembedError> {
+    let ctx = namespaces()
+        .context(namespace)
+        .await
+        .map_err(ReembedError::Namespace)?;
+    if let Some(job) = list()
+        .await
+        .into_iter()
+        .find(|j| j.namespace == namespace && j.status == "running")
+    {
+        return Err(ReembedError::Busy(job.id));
+    }
+    let source = ctx.mem_latent.lock().await.clone();
+    let model = model
+        .filter(|m| !m.trim().is_empty())
+        .unwrap_or_else(embed::default_model);
+
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO reembed_jobs (namespace, model, source_collection) VALUES (?, ?, ?)
+         RETURNING id",
+    )
+    .bind(namespace)
+    .bind(&model)
+    .bind(&source.collection_name)
+    .fetch_one(namespaces().pool())
+    .await
+    .map_err(backend)?;
+    let base = if namespace == DEFAULT_NAMESPACE {
+        DEFAULT_COLLECTION.to_string()
+    } else {
+        LatentMemory::collection_for(namespace)
+    };
+    sqlx::query("UPDATE reembed_jobs SET target_collection = ? WHERE id = ?")
+        .bind(format!("{}_r{}", base, id))
+        .bind(id)
+        .execute(namespaces().pool())
+        .await
+        .map_err(backend)?;
+
+    tracing::info!(
+        "re-embedding job {}: namespace '{}' from {} ({}) to {}",
+        id,
+        namespace,
+        source.collection_name,
+        source.model,
+        model
+    );
+    spawn(id);
+    get(id).await.ok_or(ReembedError::NotFound)
+}
+
+/// Restarts a failed job, or one whose worker is gone, from where it
+/// stopped.
+pub async fn resume(id: i64) -> Result<Job, ReembedError> {
+    let job = get(id).await.ok_or(ReembedError::NotFound)?;
+    let active = ACTIVE.lock().map(|a| a.contains(&id)).unwrap_or(false);
+    if job.status == "completed" || active {
+        return Err(ReembedError::NotResumable(job.status));
+    }
+    set_status(id, "running", None).await;
+    spawn(id);
+    get(id).await.ok_or(ReembedError::NotFound)
+}
+
+fn spawn(id: i64) {
+    if let Ok(mut active) = ACTIVE.lock()
+        && !active.insert(id)
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        let result = run(id).await;
+        if let Ok(mut active) = ACTIVE.lock() {
+            active.remove(&id);
+        }
+        match result {
+            Ok(()) => tracing::info!("re-embedding job {} completed", id),
+            Err(e) => {
+                tracing::error!("re-embedding job {} failed: {}", id, e);
+                set_status(id, "failed", Some(&e)).await;
+            }
+        }
+    });
+}
+
+async fn set_status(id: i64, status: &str, error: Option<&str>) {
+    sqlx::query(
+        "UPDATE reembed_jobs SET status = ?, error = ?, updated_at = CURRENT_TIMESTAMP
+         WHERE id = ?",
+    )
+    .bind(status)
+    .bind(error)
+    .bind(id)
+    .execute(namespaces().pool())
+    .await
+    .ok();
+}
+
+async fn run(id: i64) -> Result<(), String> {
+    let mut job = get(id).await.ok_or("job disappeared")?;
+    let ctx = namespaces()
+        .context(&job.namespace)
+        .await
+        .map_err(|e| e.to_string())?;
+    let source = ctx.mem_latent.lock().await.clone();
+    if source.collection_name != job.source_collection {
+        return Err(format!(
+            "namespace now uses collection '{}'",
+            source.collection_name
+        ));
+    }
+    let target = source
+        .with_collection(&job.target_collection, &job.model)
+        .await?;
+    job.total = source.count().await? as i64;
+    save_progress(&job).await?;
+
+    let model = job.model.clone();
+    let embed = Model(&model);
+    while step(&mut job, &source, &target, &embed).await? == PAGE_SIZE {}
+
+    // Vectors may have been added meanwhile. Writers wait on the lock, so
+    // nothing is missed between the last page and the switch.
+    let mut latent = ctx.mem_latent.lock().await;
+    if latent.collection_name != job.source_collection {
+        return Err(format!(
+            "namespace now uses collection '{}'",
+            latent.collection_name
+        ));
+    }
+    while step(&mut job, &source, &target, &embed).await? > 0 {}
+
+    // Clusters are embedded with the model of the collection they were
+    // stored next to; take their new embeddings from the vectors with the
+    // same id before the graph starts being compared with the new model.
+    let stale = {
+        let graph = ctx.mem_semantic.lock().map_err(|e| e.to_string())?;
+        stale_clusters(&graph, &model)
+    };
+    let vectors = target.get_many(&stale).await?;
+    namespaces()
+        .switch_collection(&job.namespace, &target)
+        .await?;
+    *latent = target;
+    drop(latent);
+    let updated = {
+        let mut graph = ctx.mem_semantic.lock().map_err(|e| e.to_string())?;
+        update_clusters(&mut graph, &model, vectors)
+    };
+    if updated < stale.len() {
+        tracing::warn!(
+            "re-embedding job {}: {} clusters have no vector in {} and keep their old embedding",
+            id,
+            stale.len() - updated,
+            job.target_collection
+        );
+    }
+
+    set_status(id, "completed", None).await;
+    Ok(())
+}
+
+/// The collection calls a [`step`] makes.
+trait Collection {
+    fn page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<VectorRecord>, String>> + Send;
+    fn upsert(&self, records: &[VectorRecord]) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+impl Collection for LatentMemory {
+    fn page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<VectorRecord>, String>> + Send {
+        self.export_page(offset, limit)
+    }
+
+    fn upsert(&self, records: &[VectorRecord]) -> impl Future<Output = Result<(), String>> + Send {
+        self.upsert_many(records)
+    }
+}
+
+/// Embeds the documents a [`step`] reads.
+trait Embedder {
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Vec<f32>, String>> + Send;
+}
+
+/// The job's model, retried [`EMBED_ATTEMPTS`] times per document.
+struct Model<'a>(&'a str);
+
+impl Embedder for Model<'_> {
+    fn embed(&self, text: &str) -> impl Future<Output = Result<Vec<f32>, String>> + Send {
+        embed_retrying(self.0, text)
+    }
+}
+
+/// Re-embeds the next page of the source into the target and saves the
+/// job's progress. Returns how many source vectors it read.
+async fn step(
+    job: &mut Job,
+    source: &impl Collection,
+    target: &impl Collection,
+    embed: &impl Embedder,
+) -> Result<usize, String> {
+    let read = reembed_page(job, source, target, embed).await?;
+    save_progress(job).await?;
+    Ok(read)
+}
+
+/// Re-embeds the page at `job.position`. The job only moves on once the
+/// whole page is written, so a page that fails is redone on resume.
+async fn reembed_page(
+    job: &mut Job,
+    source: &impl Collection,
+    target: &impl Collection,
+    embed: &impl Embedder,
+) -> Result<usize, String> {
+    let page = source.page(job.position.max(0) as usize, PAGE_SIZE).await?;
+    let read = page.len();
+    let mut batch = Vec::with_capacity(read);
+    for vector in page {
+        let Some(document) = vector.document else {
+            continue;
+        };
+        let embedding = embed.embed(&document).await?;
+        let mut metadata = vector.metadata.filter(Value::is_object).unwrap_or_default();
+        value::merge_patch(&mut metadata, &vector_metadata(&job.model, embedding.len()));
+        batch.push(VectorRecord {
+            id: vector.id,
+            embedding,
+            metadata: Some(metadata),
+            document: Some(document),
+        });
+    }
+    target.upsert(&batch).await?;
+    job.embedded += batch.len() as i64;
+    job.skipped += (read - batch.len()) as i64;
+    job.position += read as i64;
+    job.total = job.total.max(job.position);
+    Ok(read)
+}
+
+/// Clusters embedded with a model other than `model`. Clusters without a
+/// model came with their embedding from a client and are left alone.
+fn stale_clusters(graph: &LatentGraph, model: &str) -> Vec<String> {
+    graph
+        .clusters
+        .iter()
+        .filter(|(_, c)| c.model.as_deref().is_some_and(|m| m != model))
+        .map(|(id, _)| id.clone())
+        .collect()
+}
+
+/// Gives each cluster the embedding of the vector with its id and marks it
+/// as embedded with `model`. Returns how many clusters were updated.
+fn update_clusters(graph: &mut LatentGraph, model: &str, vectors: Vec<VectorRecord>) -> usize {
+    let mut updated = 0;
+    for vector in vectors {
+        let Some(mut cluster) = graph.clusters.get(&vector.id).cloned() else {
+            continue;
+        };
+        cluster.embedding = vector.embedding;
+        cluster.model = Some(model.to_string());
+        graph.add_cluster(vector.id, cluster);
+        updated += 1;
+    }
+    updated
+}
+
+async fn embed_retrying(model: &str, text: &str) -> Result<Vec<f32>, String> {
+    let mut attempt = 1;
+    loop {
+        match embed::embed_with(model, text).await {
+            Ok(embedding) => return Ok(embedding),
+            Err(e) if attempt >= EMBED_ATTEMPTS => return Err(e.to_string()),
+            Err(e) => {
+                tracing::warn!(
+                    "embedding with {} failed (attempt {}): {}",
+                    model,
+                    attempt,
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn save_progress(job: &Job) -> Result<(), String> {
+    sqlx::query(
+        "UPDATE reembed_jobs SET total = ?, position = ?, embedded = ?, skipped = ?,
+             updated_at = CURRENT_TIMESTAMP
+         WHERE id = ?",
+    )
+    .bind(job.total)
+    .bind(job.position)
+    .bind(job.embedded)
+    .bind(job.skipped)
+    .bind(job.id)
+    .execute(namespaces().pool())
+    .await
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::semantic::object::{AffectScore, ObjectCluster};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Memory(Mutex<Vec<VectorRecord>>);
+
+    impl Collection for Memory {
+        async fn page(&self, offset: usize, limit: usize) -> Result<Vec<VectorRecord>, String> {
+            let vectors = self.0.lock().unwrap();
+            Ok(vectors.iter().skip(offset).take(limit).cloned().collect())
+        }
+
+        async fn upsert(&self, records: &[VectorRecord]) -> Result<(), String> {
+            let mut vectors = self.0.lock().unwrap();
+            for record in records {
+                vectors.retain(|v| v.id != record.id);
+                vectors.push(record.clone());
+            }
+            Ok(())
+        }
+    }
+
+    /// Embeds a text as its length.
+    struct Length;
+
+    impl Embedder for Length {
+        async fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+            Ok(vec![text.len() as f32])
+        }
+    }
+
+    struct Down;
+
+    impl Embedder for Down {
+        async fn embed(&self, _: &str) -> Result<Vec<f32>, String> {
+            Err("embedding service down".to_string())
+        }
+    }
+
+    fn vector(i: usize) -> VectorRecord {
+        VectorRecord {
+            id: format!("v{}", i),
+            embedding: vec![0.0],
+            metadata: None,
+            // Every tenth vector was stored without its text.
+            document: (!i.is_multiple_of(10)).then(|| format!("text {}", i)),
+        }
+    }
+
+    fn job() -> Job {
+        Job {
+            id: 1,
+            namespace: "default".into(),
+            model: "new".into(),
+            source_collection: "source".into(),
+            target_collection: "target".into(),
+            status: "running".into(),
+            total: 150,
+            position: 0,
+            embedded: 0,
+            skipped: 0,
+            progress: 0.0,
+            error: None,
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_page_is_redone_on_resume() {
+        let source = Memory(Mutex::new((0..150).map(vector).collect()));
+        let target = Memory::default();
+        let embed = Length;
+        let down = Down;
+
+        let mut job = job();
+        assert_eq!(
+            reembed_page(&mut job, &source, &target, &embed).await,
+            Ok(100)
+        );
+        assert!(
+            reembed_page(&mut job, &source, &target, &down)
+                .await
+                .is_err()
+        );
+        assert_eq!((job.position, job.embedded, job.skipped), (100, 90, 10));
+
+        // Resuming starts again at the saved position.
+        assert_eq!(
+            reembed_page(&mut job, &source, &target, &embed).await,
+            Ok(50)
+        );
+        assert_eq!(
+            reembed_page(&mut job, &source, &target, &embed).await,
+            Ok(0)
+        );
+        assert_eq!((job.position, job.embedded, job.skipped), (150, 135, 15));
+
+        let target = target.0.into_inner().unwrap();
+        assert_eq!(target.len(), 135);
+        let v1 = target.iter().find(|v| v.id == "v1").unwrap();
+        assert_eq!(v1.embedding, vec![6.0]);
+        assert_eq!(v1.metadata.as_ref().unwrap()["model"], "new");
+    }
+
+    #[test]
+    fn clusters_take_the_embedding_of_their_vector() {
+        let cluster = |model: Option<&str>| ObjectCluster {
+            name: String::new(),
+            embedding: vec![1.0, 0.0],
+            tags: vec![],
+            affect: AffectScore::from_value(0.0),
+            known: true,
+            model: model.map(str::to_string),
+        };
+        let mut graph = LatentGraph::new();
+        graph.add_cluster("chat".into(), cluster(Some("old")));
+        graph.add_cluster("current".into(), cluster(Some("new")));
+        graph.add_cluster("client".into(), cluster(None));
+        assert_eq!(stale_clusters(&graph, "new"), vec!["chat".to_string()]);
+
+        let vector = VectorRecord {
+            id: "chat".into(),
+            embedding: vec![0.0, 1.0],
+            metadata: None,
+            document: None,
+        };
+        assert_eq!(update_clusters(&mut graph, "new", vec![vector]), 1);
+        assert!(stale_clusters(&graph, "new").is_empty());
+        assert_eq!(graph.clusters["chat"].embedding, vec![0.0, 1.0]);
+        assert_eq!(graph.find_nearest(&[0.0, 1.0]).unwrap().0, "chat");
+    }
+}
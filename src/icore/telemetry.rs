@@ -0,0 +1,190 @@
+use crate::icore::namespace::namespaces;
+use crate::icore::outbox;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderMap, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Histogram buckets in seconds, from a cache hit to a slow completion.
+const BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+/// How often histogram samples are folded into their buckets between
+/// scrapes.
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Installs the Prometheus recorder. Without it the `metrics` macros are
+/// no-ops, as in tests.
+pub fn init() {
+    let handle = PrometheusBuilder::new()
+        .set_buckets(BUCKETS)
+        .expect("invalid histogram buckets")
+        .install_recorder()
+        .expect("failed to install the metrics recorder");
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+    if HANDLE.set(handle).is_err() {
+        panic!("metrics recorder was already installed");
+    }
+}
+
+/// `GET /metrics` in the Prometheus text format. Series are labelled
+/// with namespace names, so it is an admin endpoint.
+pub async fn render(headers: HeaderMap) -> Response {
+    if let Err(e) = namespaces().authorize_admin(&headers) {
+        return e.into_response();
+    }
+    let Some(handle) = HANDLE.get() else {
+        return (axum::http::StatusCode::NOT_FOUND, "metrics are not enabled").into_response();
+    };
+    record_sizes().await;
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        handle.render(),
+    )
+        .into_response()
+}
+
+/// Counts requests and their latency by method, route template and status.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16().to_string();
+    counter!(
+        "icore_http_requests_total",
+        "method" => method.clone(),
+        "route" => route.clone(),
+        "status" => status
+    )
+    .increment(1);
+    histogram!(
+        "icore_http_request_duration_seconds",
+        "method" => method,
+        "route" => route
+    )
+    .record(started.elapsed().as_secs_f64());
+    response
+}
+
+/// Records a call to `dependency` (`embedding`, `llm` or `chroma`) that
+/// began at `started`.
+pub fn observe_call(dependency: &'static str, operation: &'static str, started: Instant, ok: bool) {
+    histogram!(
+        "icore_dependency_request_duration_seconds",
+        "dependency" => dependency,
+        "operation" => operation
+    )
+    .record(started.elapsed().as_secs_f64());
+    if !ok {
+        counter!(
+            "icore_dependency_errors_total",
+            "dependency" => dependency,
+            "operation" => operation
+        )
+        .increment(1);
+    }
+}
+
+/// Locks an agent, recording how long the caller waited for it.
+pub async fn lock_agent<'a, T>(kind: &'static str, agent: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    let started = Instant::now();
+    let guard = agent.lock().await;
+    histogram!("icore_agent_lock_wait_seconds", "kind" => kind)
+        .record(started.elapsed().as_secs_f64());
+    guard
+}
+
+/// Records how long `agent` took to handle one input.
+pub fn observe_agent_eval(agent: &str, started: Instant) {
+    histogram!("icore_agent_eval_duration_seconds", "agent" => agent.to_string())
+        .record(started.elapsed().as_secs_f64());
+}
+
+/// Sizes of the memory layers of every opened namespace and the depth of
+/// the outbox, read at scrape time.
+async fn record_sizes() {
+    for ctx in namespaces().opened().await {
+        let namespace = ctx.namespace.clone();
+        let short = ctx.mem_short.all().map(|all| all.len()).unwrap_or(0);
+        let long = ctx.mem_long.count().await;
+        let (clusters, relations) = ctx
+            .mem_semantic
+            .lock()
+            .map(|graph| (graph.clusters.len(), graph.relations.len()))
+            .unwrap_or_default();
+        for (layer, size) in [
+            ("short_term_keys", short),
+            ("long_term_rows", long),
+            ("semantic_clusters", clusters),
+            ("semantic_relations", relations),
+        ] {
+            gauge!(
+                "icore_memory_size",
+                "namespace" => namespace.clone(),
+                "layer" => layer
+            )
+            .set(size as f64);
+        }
+    }
+    let jobs = outbox::stats().await;
+    for (status, depth) in [
+        ("pending", jobs.pending),
+        ("running", jobs.running),
+        ("dead", jobs.dead),
+    ] {
+        gauge!("icore_outbox_jobs", "status" => status).set(depth as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_lock_waits_are_recorded_by_kind() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(BUCKETS)
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        let agent = Mutex::new(());
+        metrics::with_local_recorder(&recorder, || {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    drop(lock_agent("sentience", &agent).await);
+                    drop(lock_agent("sentience", &agent).await);
+                    drop(lock_agent("react", &agent).await);
+                })
+        });
+        let text = handle.render();
+        assert!(
+            text.contains("icore_agent_lock_wait_seconds_count{kind=\"sentience\"} 2"),
+            "{}",
+            text
+        );
+        assert!(text.contains("icore_agent_lock_wait_seconds_count{kind=\"react\"} 1"));
+    }
+}
@@ -1,13 +1,27 @@
-use crate::agents::{AGENT, BaseAgent};
-use crate::api::handlers::{LATENT_MEM, LONG_MEM, SHORT_MEM};
+use crate::agents::{AGENTS, AgentDefaults, AgentRegistry};
+use crate::api::handlers::{DEGRADED_HEADER, EPISODIC_MEM, LATENT_MEM, LONG_MEM, SHORT_MEM};
 use crate::api::routes::routes;
+use crate::icore::embed_cache::{EMBED_CACHE, EmbedCache};
+use crate::icore::health::{self, Status};
+use crate::icore::namespace::{NAMESPACE_HEADER, NAMESPACES, Namespaces};
+use crate::icore::outbox::{self, RetryPolicy};
+use crate::icore::{backup, reembed, telemetry};
+use crate::memory::episodic::EpisodicMemory;
 use crate::memory::latent::LatentMemory;
 use crate::memory::long_term::LongTermMemory;
 use crate::memory::short_term::ShortTermMemory;
-use axum::{Router, http::Method, routing::get};
-use std::fs;
+use axum::{
+    Json, Router,
+    http::{Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde_json::{Value, json};
 use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tower_http::cors::{Any, CorsLayer};
@@ -24,49 +38,120 @@ async fn main() {
     dotenvy::dotenv().ok();
     fmt().with_env_filter(EnvFilter::from_default_env()).init();
 
-    let settings = config::settings::Settings::new();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [command, file] = args.as_slice() {
+        let client = config::settings::ClientSettings::new();
+        let token = client.token.as_deref();
+        let result = match command.as_str() {
+            "backup" => backup::download(&client.server_url, token, file).await,
+            "restore" => backup::upload(&client.server_url, token, file).await,
+            other => Err(format!("unknown command '{}'", other)),
+        };
+        match result {
+            Ok(message) => println!("{}", message),
+            Err(e) => {
+                eprintln!("{} failed: {}", command, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let settings = match config::settings::Settings::new() {
+        Ok(settings) => settings,
+        Err(e) => {
+            tracing::error!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
     tracing::info!("Starting ICORE server in {} mode", settings.env);
 
+    telemetry::init();
     SHORT_MEM.set(ShortTermMemory::new()).unwrap();
     LONG_MEM
         .set(LongTermMemory::new(&settings.database_url).await)
         .unwrap();
+    EPISODIC_MEM
+        .set(
+            EpisodicMemory::new(
+                &settings.database_url,
+                settings.episode_gap_secs,
+                settings.episode_topic_threshold,
+            )
+            .await,
+        )
+        .unwrap();
     LATENT_MEM
         .set(Arc::new(Mutex::new(
             LatentMemory::new(settings.chromadb_url.clone()).await,
         )))
         .unwrap();
+    if NAMESPACES
+        .set(Namespaces::new(&settings.database_url, settings.jwt_secret.clone()).await)
+        .is_err()
+    {
+        panic!("NAMESPACES was already set");
+    }
+    reembed::init().await;
+    outbox::init(RetryPolicy {
+        max_attempts: settings.outbox_max_attempts,
+        backoff_secs: settings.outbox_backoff_secs,
+    })
+    .await;
+    health::CACHE_TTL
+        .set(Duration::from_secs(settings.health_cache_secs))
+        .unwrap();
+    if settings.embed_cache_size > 0
+        && EMBED_CACHE
+            .set(
+                EmbedCache::new(
+                    settings.embed_cache_size,
+                    settings.embed_cache_db.as_deref(),
+                    settings.embed_cache_db_rows,
+                )
+                .await,
+            )
+            .is_err()
+    {
+        panic!("EMBED_CACHE was already set");
+    }
 
-    let mut base_agent = BaseAgent::new(
-        "Reflector".to_string(),
-        "Reflective memory agent".to_string(),
-    );
-    match fs::read_to_string("agent.sent") {
-        Ok(sent_code) => {
-            if let Err(e) = base_agent.load(&sent_code).await {
-                panic!("Sentience load failed: {}", e);
-            }
-            println!("Loaded Sentience DSL from agent.sent");
-        }
-        Err(_) => {
-            println!("Warning: 'agent.sent' not found, running w/o Sentience DSL");
+    let registry = match AgentRegistry::load_dir(
+        Path::new(&settings.agents_dir),
+        settings.default_agent.clone(),
+        AgentDefaults {
+            limits: settings.agent_limits,
+            tools: settings.agent_tools.clone(),
+        },
+    )
+    .await
+    {
+        Ok(registry) => registry,
+        Err(e) => {
+            tracing::error!("Sentience load failed:\n{}", e);
+            std::process::exit(1);
         }
+    };
+    tracing::info!(
+        "Agents: {:?} (default: {})",
+        registry.names(),
+        registry.default_name()
+    );
+    if AGENTS.set(registry).is_err() {
+        panic!("AGENTS was already set");
     }
-
-    let agent_arc = Arc::new(Mutex::new(base_agent));
-    if AGENT.set(agent_arc).is_err() {
-        panic!("AGENT was already set");
+    if settings.agent_watch_interval_secs > 0 {
+        agents::registry().spawn_watcher(Duration::from_secs(settings.agent_watch_interval_secs));
     }
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([axum::http::header::CONTENT_TYPE]);
-
     let app = Router::new()
         .route("/health", get(health_check))
+        .route("/health/live", get(liveness))
+        .route("/health/ready", get(readiness))
+        .route("/metrics", get(telemetry::render))
         .nest("/api", routes())
-        .layer(cors);
+        .layer(middleware::from_fn(telemetry::track_requests))
+        .layer(cors());
 
     let addr: SocketAddr = "0.0.0.0:8080".parse().unwrap();
     let listener = TcpListener::bind(addr).await.unwrap();
@@ -74,6 +159,61 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
-async fn health_check() -> &'static str {
-    "ICORE server is healthy."
+/// Browsers preflight every method but GET and POST: PUT, PATCH and
+/// DELETE back long-term writes, cluster tag/affect patches, cluster
+/// deletion and agent memory. `ETag` is exposed so browser clients can
+/// echo it back in `If-Match`.
+fn cors() -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_headers([
+            axum::http::header::CONTENT_TYPE,
+            axum::http::header::AUTHORIZATION,
+            axum::http::header::IF_MATCH,
+            axum::http::header::IF_NONE_MATCH,
+            axum::http::HeaderName::from_static(NAMESPACE_HEADER),
+        ])
+        .expose_headers([
+            axum::http::header::ETAG,
+            axum::http::HeaderName::from_static(DEGRADED_HEADER),
+        ])
+}
+
+/// `200` either way; the body says which memory layers are degraded.
+async fn health_check() -> String {
+    let mut degraded = Vec::new();
+    if !LATENT_MEM.get().unwrap().lock().await.is_connected() {
+        degraded.push("Chroma unavailable".to_string());
+    }
+    let jobs = outbox::stats().await;
+    if jobs.dead > 0 {
+        degraded.push(format!("{} dead indexing jobs", jobs.dead));
+    }
+    if degraded.is_empty() {
+        "ICORE server is healthy.".to_string()
+    } else {
+        format!("ICORE server is degraded: {}.", degraded.join(", "))
+    }
+}
+
+/// The process is up and serving requests.
+async fn liveness() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Per-dependency status; `503` when the server cannot work.
+async fn readiness() -> Response {
+    let report = health::ready().await;
+    let status = match report.status {
+        Status::Down => StatusCode::SERVICE_UNAVAILABLE,
+        Status::Ok | Status::Degraded => StatusCode::OK,
+    };
+    (status, Json(report)).into_response()
 }
@@ -0,0 +1,520 @@
+use crate::icore::namespace::DEFAULT_NAMESPACE;
+use crate::memory::semantic::hnsw::normalize;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite, sqlite::SqlitePoolOptions, sqlite::SqliteRow};
+
+/// How many of the newest episodes `recall` compares a query against.
+pub const RECALL_WINDOW: i64 = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default)]
+    pub episode_id: i64,
+    pub session: String,
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub participants: Vec<String>,
+    pub content: String,
+    #[serde(default)]
+    pub clusters: Vec<String>,
+    #[serde(default)]
+    pub affect: f32,
+    #[serde(default, skip_serializing)]
+    pub embedding: Option<Vec<f32>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Episode {
+    pub id: i64,
+    pub session: String,
+    pub started_at: i64,
+    pub ended_at: i64,
+    pub participants: Vec<String>,
+    pub affect: f32,
+    pub event_count: i64,
+    pub summary: Option<String>,
+    #[serde(skip)]
+    pub centroid: Vec<f32>,
+    #[serde(skip)]
+    embedded_count: i64,
+}
+
+/// Ordered event log segmented into episodes. A session's open episode is
+/// closed when the next event arrives more than `max_gap_ms` later or when
+/// its embedding drifts below `topic_threshold` similarity to the episode.
+///
+/// All namespaces share the tables; every query is scoped to `namespace`,
+/// see [`EpisodicMemory::scoped`].
+#[derive(Debug, Clone)]
+pub struct EpisodicMemory {
+    pool: Pool<Sqlite>,
+    namespace: String,
+    max_gap_ms: i64,
+    topic_threshold: f32,
+}
+
+impl EpisodicMemory {
+    pub async fn new(database_url: &str, max_gap_secs: i64, topic_threshold: f32) -> Self {
+        let options = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(database_url)
+            .create_if_missing(true);
+
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .expect("Failed to connect to SQLite");
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS episodes (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER NOT NULL,
+                participants TEXT NOT NULL DEFAULT '[]',
+                affect REAL NOT NULL DEFAULT 0,
+                event_count INTEGER NOT NULL DEFAULT 0,
+                centroid TEXT NOT NULL DEFAULT '[]',
+                embedded_count INTEGER NOT NULL DEFAULT 0,
+                summary TEXT
+            );",
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to create episodes table");
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS episode_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                episode_id INTEGER NOT NULL REFERENCES episodes(id) ON DELETE CASCADE,
+                session TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                participants TEXT NOT NULL DEFAULT '[]',
+                content TEXT NOT NULL,
+                clusters TEXT NOT NULL DEFAULT '[]',
+                affect REAL NOT NULL DEFAULT 0,
+                embedding TEXT
+            );",
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to create episode_events table");
+
+        // Episodes from before namespaces belong to the default one.
+        let added = sqlx::query(&format!(
+            "ALTER TABLE episodes ADD COLUMN namespace TEXT NOT NULL DEFAULT '{}'",
+            DEFAULT_NAMESPACE
+        ))
+        .execute(&pool)
+        .await;
+        match added {
+            Err(e) if !e.to_string().contains("duplicate column") => {
+                panic!("Failed to add the namespace column to episodes: {}", e)
+            }
+            _ => {}
+        }
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS episodes_namespace_session
+                ON episodes (namespace, session, id)",
+        )
+        .execute(&pool)
+        .await
+        .expect("Failed to index episodes");
+
+        Self {
+            pool,
+            namespace: DEFAULT_NAMESPACE.to_string(),
+            max_gap_ms: max_gap_secs * 1000,
+            topic_threshold,
+        }
+    }
+
+    /// The same log, seen from `namespace`.
+    pub fn scoped(&self, namespace: &str) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Deletes every episode and event of this namespace.
+    pub async fn clear(&self) -> Result<(), String> {
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        sqlx::query(
+            "DELETE FROM episode_events
+             WHERE episode_id IN (SELECT id FROM episodes WHERE namespace = ?)",
+        )
+        .bind(&self.namespace)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+        sqlx::query("DELETE FROM episodes WHERE namespace = ?")
+            .bind(&self.namespace)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        tx.commit().await.map_err(|e| e.to_string())
+    }
+
+    /// Appends an event, opening a new episode when the session's current one
+    /// is too old or off-topic. Returns the stored event.
+    pub async fn record(&self, mut event: Event) -> Result<Event, String> {
+        if event.timestamp == 0 {
+            event.timestamp = chrono::Utc::now().timestamp_millis();
+        }
+        let unit = event.embedding.as_deref().and_then(normalize);
+
+        let mut tx = self.pool.begin().await.map_err(|e| e.to_string())?;
+        // sqlx opens deferred transactions; a no-op write takes the write
+        // lock up front, as BEGIN IMMEDIATE would. Concurrent turns then
+        // wait on the busy timeout instead of failing to upgrade a read
+        // lock, and never update the running means from a stale row.
+        sqlx::query("UPDATE episodes SET id = id WHERE 0")
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        let open = sqlx::query(
+            "SELECT * FROM episodes WHERE namespace = ? AND session = ?
+             ORDER BY id DESC LIMIT 1",
+        )
+        .bind(&self.namespace)
+        .bind(&event.session)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .map(episode_from_row);
+
+        let mut episode = match open {
+            Some(ep) if !self.starts_new_episode(&ep, &event, unit.as_deref()) => ep,
+            _ => {
+                let id = sqlx::query(
+                    "INSERT INTO episodes (namespace, session, started_at, ended_at)
+                     VALUES (?, ?, ?, ?)",
+                )
+                .bind(&self.namespace)
+                .bind(&event.session)
+                .bind(event.timestamp)
+                .bind(event.timestamp)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?
+                .last_insert_rowid();
+                Episode {
+                    id,
+                    session: event.session.clone(),
+                    started_at: event.timestamp,
+                    ended_at: event.timestamp,
+                    participants: vec![],
+                    affect: 0.0,
+                    event_count: 0,
+                    summary: None,
+                    centroid: vec![],
+                    embedded_count: 0,
+                }
+            }
+        };
+
+        // Running means over the episode's events.
+        let n = episode.event_count as f32;
+        episode.affect = (episode.affect * n + event.affect) / (n + 1.0);
+        episode.event_count += 1;
+        episode.ended_at = episode.ended_at.max(event.timestamp);
+        for p in &event.participants {
+            if !episode.participants.contains(p) {
+                episode.participants.push(p.clone());
+            }
+        }
+        if let Some(v) = &unit {
+            let m = episode.embedded_count as f32;
+            if episode.centroid.len() != v.len() {
+                episode.centroid = v.clone();
+            } else {
+                for (c, x) in episode.centroid.iter_mut().zip(v) {
+                    *c = (*c * m + x) / (m + 1.0);
+                }
+            }
+            episode.embedded_count += 1;
+        }
+
+        sqlx::query(
+            "UPDATE episodes SET ended_at = ?, participants = ?, affect = ?, event_count = ?,
+                centroid = ?, embedded_count = ? WHERE id = ?",
+        )
+        .bind(episode.ended_at)
+        .bind(to_json(&episode.participants))
+        .bind(episode.affect)
+        .bind(episode.event_count)
+        .bind(to_json(&episode.centroid))
+        .bind(episode.embedded_count)
+        .bind(episode.id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+        event.episode_id = episode.id;
+        event.id = sqlx::query(
+            "INSERT INTO episode_events
+                (episode_id, session, timestamp, participants, content, clusters, affect, embedding)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(event.episode_id)
+        .bind(&event.session)
+        .bind(event.timestamp)
+        .bind(to_json(&event.participants))
+        .bind(&event.content)
+        .bind(to_json(&event.clusters))
+        .bind(event.affect)
+        .bind(event.embedding.as_ref().map(to_json))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?
+        .last_insert_rowid();
+
+        tx.commit().await.map_err(|e| e.to_string())?;
+        Ok(event)
+    }
+
+    fn starts_new_episode(&self, episode: &Episode, event: &Event, unit: Option<&[f32]>) -> bool {
+        if event.timestamp - episode.ended_at > self.max_gap_ms {
+            return true;
+        }
+        match unit {
+            Some(v) if episode.embedded_count > 0 && episode.centroid.len() == v.len() => {
+                cosine(&episode.centroid, v) < self.topic_threshold
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn episodes(&self, session: Option<&str>, limit: i64) -> Vec<Episode> {
+        let rows = match session {
+            Some(session) => {
+                sqlx::query(
+                    "SELECT * FROM episodes WHERE namespace = ? AND session = ?
+                     ORDER BY id DESC LIMIT ?",
+                )
+                .bind(&self.namespace)
+                .bind(session)
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await
+            }
+            None => {
+                sqlx::query("SELECT * FROM episodes WHERE namespace = ? ORDER BY id DESC LIMIT ?")
+                    .bind(&self.namespace)
+                    .bind(limit)
+                    .fetch_all(&self.pool)
+                    .await
+            }
+        };
+        rows.unwrap_or_default()
+            .into_iter()
+            .map(episode_from_row)
+            .collect()
+    }
+
+    pub async fn episode(&self, id: i64) -> Option<Episode> {
+        sqlx::query("SELECT * FROM episodes WHERE id = ? AND namespace = ?")
+            .bind(id)
+            .bind(&self.namespace)
+            .fetch_optional(&self.pool)
+            .await
+            .ok()?
+            .map(episode_from_row)
+    }
+
+    pub async fn events(&self, episode_id: i64) -> Vec<Event> {
+        sqlx::query(
+            "SELECT e.* FROM episode_events e JOIN episodes ep ON ep.id = e.episode_id
+             WHERE e.episode_id = ? AND ep.namespace = ? ORDER BY e.timestamp, e.id",
+        )
+        .bind(episode_id)
+        .bind(&self.namespace)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|row| Event {
+            id: row.get("id"),
+            episode_id: row.get("episode_id"),
+            session: row.get("session"),
+            timestamp: row.get("timestamp"),
+            participants: from_json(row.get("participants")),
+            content: row.get("content"),
+            clusters: from_json(row.get("clusters")),
+            affect: row.get("affect"),
+            embedding: row
+                .get::<Option<String>, _>("embedding")
+                .map(|s| from_json(&s)),
+        })
+        .collect()
+    }
+
+    pub async fn set_summary(&self, id: i64, summary: &str) -> Result<(), String> {
+        sqlx::query("UPDATE episodes SET summary = ? WHERE id = ? AND namespace = ?")
+            .bind(summary)
+            .bind(id)
+            .bind(&self.namespace)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Ranks the newest [`RECALL_WINDOW`] episodes by cosine similarity
+    /// between `embedding` and each episode's centroid, most similar first.
+    pub async fn recall(
+        &self,
+        embedding: &[f32],
+        session: Option<&str>,
+        k: usize,
+    ) -> Vec<(Episode, f32)> {
+        let Some(query) = normalize(embedding) else {
+            return vec![];
+        };
+        let mut scored = self
+            .episodes(session, RECALL_WINDOW)
+            .await
+            .into_iter()
+            .filter(|ep| ep.centroid.len() == query.len())
+            .map(|ep| {
+                let sim = cosine(&ep.centroid, &query);
+                (ep, sim)
+            })
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(k);
+        scored
+    }
+}
+
+/// Builds the LLM prompt used to summarize an episode.
+pub fn summary_prompt(events: &[Event]) -> String {
+    let mut prompt = String::from(
+        "Summarize the following conversation episode in two sentences. \
+         Mention who took part and what it was about.\n\n",
+    );
+    for event in events {
+        let speaker = event
+            .participants
+            .first()
+            .map(String::as_str)
+            .unwrap_or("unknown");
+        prompt.push_str(&format!("{}: {}\n", speaker, event.content));
+    }
+    prompt.push_str("\nSummary:");
+    prompt
+}
+
+fn episode_from_row(row: SqliteRow) -> Episode {
+    Episode {
+        id: row.get("id"),
+        session: row.get("session"),
+        started_at: row.get("started_at"),
+        ended_at: row.get("ended_at"),
+        participants: from_json(row.get("participants")),
+        affect: row.get("affect"),
+        event_count: row.get("event_count"),
+        summary: row.get("summary"),
+        centroid: from_json(row.get("centroid")),
+        embedded_count: row.get("embedded_count"),
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| "[]".into())
+}
+
+fn from_json<T: for<'de> Deserialize<'de> + Default>(raw: &str) -> T {
+    serde_json::from_str(raw).unwrap_or_default()
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: i64, embedding: Vec<f32>) -> Event {
+        Event {
+            id: 0,
+            episode_id: 0,
+            session: "s".into(),
+            timestamp,
+            participants: vec!["user".into()],
+            content: format!("at {}", timestamp),
+            clusters: vec![],
+            affect: 0.0,
+            embedding: Some(embedding),
+        }
+    }
+
+    #[tokio::test]
+    async fn segments_on_time_gap_and_topic_shift() {
+        let path = std::env::temp_dir().join(format!(
+            "icore_episodic_{}.db",
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let mem = EpisodicMemory::new(path.to_str().unwrap(), 60, 0.5).await;
+
+        let a = mem.record(event(1_000, vec![1.0, 0.0])).await.unwrap();
+        let b = mem.record(event(2_000, vec![0.9, 0.1])).await.unwrap();
+        let c = mem.record(event(3_000, vec![0.0, 1.0])).await.unwrap();
+        let d = mem.record(event(200_000, vec![0.0, 1.0])).await.unwrap();
+
+        assert_eq!(a.episode_id, b.episode_id);
+        assert_ne!(b.episode_id, c.episode_id);
+        assert_ne!(c.episode_id, d.episode_id);
+
+        let recalled = mem.recall(&[1.0, 0.05], None, 1).await;
+        assert_eq!(recalled[0].0.id, a.episode_id);
+        assert_eq!(mem.events(a.episode_id).await.len(), 2);
+
+        let other = mem.scoped("other");
+        assert!(other.episodes(None, 10).await.is_empty());
+        assert!(other.episode(a.episode_id).await.is_none());
+        assert!(other.events(a.episode_id).await.is_empty());
+        assert!(other.recall(&[1.0, 0.05], None, 1).await.is_empty());
+        let e = other.record(event(4_000, vec![1.0, 0.0])).await.unwrap();
+        assert_ne!(e.episode_id, d.episode_id);
+        other.clear().await.unwrap();
+        assert_eq!(mem.episodes(None, 10).await.len(), 3);
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn concurrent_turns_keep_every_event() {
+        let path = std::env::temp_dir().join(format!(
+            "icore_episodic_race_{}.db",
+            chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let mem = EpisodicMemory::new(path.to_str().unwrap(), 60, 0.0).await;
+
+        let turns = (0..16).map(|i| {
+            let mem = mem.clone();
+            tokio::spawn(async move { mem.record(event(1_000 + i, vec![1.0, 0.0])).await })
+        });
+        for turn in futures::future::join_all(turns).await {
+            turn.unwrap().unwrap();
+        }
+
+        let episodes = mem.episodes(None, 10).await;
+        assert_eq!(episodes.len(), 1);
+        assert_eq!(episodes[0].event_count, 16);
+        assert_eq!(mem.events(episodes[0].id).await.len(), 16);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
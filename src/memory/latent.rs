@@ -1,20 +1,45 @@
-use reqwest::Client;
-use serde::Deserialize;
+use crate::icore::{embed, telemetry};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct LatentMemory {
     pub chroma_url: String,
     pub collection_id: String,
+    pub collection_name: String,
+    /// Embedding model the vectors in this collection come from. Queries
+    /// must be embedded with it too.
+    pub model: String,
     client: Client,
 }
 
+pub const DEFAULT_COLLECTION: &str = "mem";
+const ID_FILE: &str = ".chroma";
+/// Model of collections created before models were recorded.
+pub const LEGACY_MODEL: &str = "tinyllama";
+/// Vectors fetched or added per Chroma request when copying collections.
+const PAGE_SIZE: usize = 500;
+
+/// One stored vector as read back from Chroma.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorRecord {
+    pub id: String,
+    pub embedding: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document: Option<String>,
+}
+
 impl LatentMemory {
     pub async fn new(chroma_url: String) -> Self {
         let client = Client::new();
-        let path = ".chroma";
+        let path = ID_FILE;
 
         let collection_id = if let Ok(env_id) = env::var("CHROMA_COLLECTION_ID") {
             env_id
@@ -24,67 +49,262 @@ impl LatentMemory {
                 .trim()
                 .to_string()
         } else {
-            let payload = serde_json::json!({
-                "name": "mem",
-                "embedding_function": null,
-                "dimension": 1536
-            });
-
-            let res = client
-                .post(format!(
-                    "{}/api/v2/tenants/default_tenant/databases/default_database/collections",
-                    chroma_url
-                ))
-                .json(&payload)
-                .send()
-                .await
-                .expect("failed to create collection");
-
-            if res.status().is_success() {
-                let text = res.text().await.expect("failed to get response text");
-                let value: serde_json::Value =
-                    serde_json::from_str(&text).expect("invalid json response");
-                let id = value["id"]
-                    .as_str()
-                    .expect("missing id field in response")
-                    .to_string();
-
-                fs::write(path, &id).expect("failed to write .chroma");
-                id
-            } else {
-                let err = res.text().await.unwrap_or_else(|_| "unknown error".into());
-                panic!("failed to create collection: {}", err);
+            match create_collection(&client, &chroma_url, DEFAULT_COLLECTION).await {
+                Ok(id) => {
+                    fs::write(path, &id).expect("failed to write .chroma");
+                    id
+                }
+                Err(e) => {
+                    // Boot without vectors; the collection is resolved
+                    // once Chroma answers.
+                    tracing::warn!("Chroma unavailable, latent memory disconnected: {}", e);
+                    String::new()
+                }
             }
         };
 
         Self {
             chroma_url,
             collection_id,
+            collection_name: DEFAULT_COLLECTION.to_string(),
+            model: embed::default_model(),
             client,
         }
     }
 
-    pub async fn embed(&self, id: &str, embedding: Vec<f32>) -> Result<(), String> {
-        let payload = serde_json::json!({
+    /// Name of the collection `namespace` starts out with.
+    pub fn collection_for(namespace: &str) -> String {
+        format!("{}_{}", DEFAULT_COLLECTION, namespace)
+    }
+
+    /// A handle on the collection `name` holding vectors of `model`,
+    /// created if needed.
+    pub async fn with_collection(&self, name: &str, model: &str) -> Result<Self, String> {
+        let collection_id = create_collection(&self.client, &self.chroma_url, name).await?;
+        Ok(Self {
+            chroma_url: self.chroma_url.clone(),
+            collection_id,
+            collection_name: name.to_string(),
+            model: model.to_string(),
+            client: self.client.clone(),
+        })
+    }
+
+    /// A handle on `name` that is not connected yet, for when Chroma cannot
+    /// be reached. [`LatentMemory::connect`] resolves it later.
+    pub fn detached(&self, name: &str, model: &str) -> Self {
+        Self {
+            chroma_url: self.chroma_url.clone(),
+            collection_id: String::new(),
+            collection_name: name.to_string(),
+            model: model.to_string(),
+            client: self.client.clone(),
+        }
+    }
+
+    /// Whether the collection was resolved in Chroma.
+    pub fn is_connected(&self) -> bool {
+        !self.collection_id.is_empty()
+    }
+
+    /// Resolves the collection, creating it if needed.
+    pub async fn connect(&mut self) -> Result<(), String> {
+        self.collection_id =
+            create_collection(&self.client, &self.chroma_url, &self.collection_name).await?;
+        Ok(())
+    }
+
+    /// Checks that Chroma answers and that the collection still exists.
+    pub async fn heartbeat(&self) -> Result<(), String> {
+        let (status, _) = send(
+            "heartbeat",
+            self.client
+                .get(format!("{}/api/v2/heartbeat", self.chroma_url)),
+        )
+        .await?;
+        if !status.is_success() {
+            return Err(format!("Chroma heartbeat error: {}", status));
+        }
+        self.count().await.map(|_| ())
+    }
+
+    fn collection_url(&self, endpoint: &str) -> Result<String, String> {
+        if !self.is_connected() {
+            return Err(format!(
+                "Chroma collection '{}' is not connected",
+                self.collection_name
+            ));
+        }
+        Ok(format!(
+            "{}/api/v2/tenants/default_tenant/databases/default_database/collections/{}/{}",
+            self.chroma_url, self.collection_id, endpoint
+        ))
+    }
+
+    /// Records this collection as the default namespace's in `.chroma`.
+    pub fn save_id(&self) -> Result<(), String> {
+        fs::write(ID_FILE, &self.collection_id).map_err(|e| format!("{}: {}", ID_FILE, e))
+    }
+
+    pub async fn count(&self) -> Result<usize, String> {
+        let url = self.collection_url("count")?;
+        let (status, text) = send("count", self.client.get(&url)).await?;
+        if !status.is_success() {
+            return Err(format!("Chroma count error: {} - {}", status, text));
+        }
+        text.trim()
+            .parse()
+            .map_err(|_| format!("unexpected count: {}", text))
+    }
+
+    pub async fn delete_collection(&self) -> Result<(), String> {
+        let url = format!(
+            "{}/api/v2/tenants/default_tenant/databases/default_database/collections/{}",
+            self.chroma_url, self.collection_name
+        );
+        let (status, text) = send("delete", self.client.delete(&url)).await?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("Chroma delete error: {} - {}", status, text))
+        }
+    }
+
+    /// Drops the collection and creates it again, empty, under the same
+    /// name. The returned handle has the new id.
+    pub async fn recreate(&self) -> Result<Self, String> {
+        if let Err(e) = self.delete_collection().await {
+            tracing::warn!("recreating '{}': {}", self.collection_name, e);
+        }
+        let collection_id =
+            create_collection(&self.client, &self.chroma_url, &self.collection_name).await?;
+        Ok(Self {
+            collection_id,
+            ..self.clone()
+        })
+    }
+
+    /// Every vector in the collection with its metadata and document.
+    pub async fn export_all(&self) -> Result<Vec<VectorRecord>, String> {
+        let mut records = Vec::new();
+        loop {
+            let page = self.export_page(records.len(), PAGE_SIZE).await?;
+            let done = page.len() < PAGE_SIZE;
+            records.extend(page);
+            if done {
+                return Ok(records);
+            }
+        }
+    }
+
+    /// Up to `limit` vectors starting at `offset`, in Chroma's order.
+    pub async fn export_page(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<VectorRecord>, String> {
+        self.fetch(serde_json::json!({
+            "include": ["embeddings", "metadatas", "documents"],
+            "limit": limit,
+            "offset": offset
+        }))
+        .await
+    }
+
+    /// The stored vectors among `ids`; ids that are not stored are left out.
+    pub async fn get_many(&self, ids: &[String]) -> Result<Vec<VectorRecord>, String> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.fetch(serde_json::json!({
+            "ids": ids,
+            "include": ["embeddings", "metadatas", "documents"]
+        }))
+        .await
+    }
+
+    async fn fetch(&self, payload: Value) -> Result<Vec<VectorRecord>, String> {
+        let url = self.collection_url("get")?;
+        let (status, text) = send("get", self.client.post(&url).json(&payload)).await?;
+        if !status.is_success() {
+            return Err(format!("Chroma get error: {} - {}", status, text));
+        }
+        let page: GetResponse = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let mut embeddings = page.embeddings.unwrap_or_default().into_iter();
+        let mut metadatas = page.metadatas.unwrap_or_default().into_iter();
+        let mut documents = page.documents.unwrap_or_default().into_iter();
+        Ok(page
+            .ids
+            .into_iter()
+            .map(|id| VectorRecord {
+                id,
+                embedding: embeddings.next().unwrap_or_default(),
+                metadata: metadatas.next().flatten(),
+                document: documents.next().flatten(),
+            })
+            .collect())
+    }
+
+    /// Adds `records` in batches, keeping their ids, metadata and documents.
+    pub async fn add_many(&self, records: &[VectorRecord]) -> Result<(), String> {
+        self.write_many("add", records).await
+    }
+
+    /// Like [`LatentMemory::add_many`], but replaces vectors whose id is
+    /// already stored.
+    pub async fn upsert_many(&self, records: &[VectorRecord]) -> Result<(), String> {
+        self.write_many("upsert", records).await
+    }
+
+    async fn write_many(&self, op: &'static str, records: &[VectorRecord]) -> Result<(), String> {
+        let url = self.collection_url(op)?;
+        for batch in records.chunks(PAGE_SIZE) {
+            let mut payload = serde_json::json!({
+                "ids": batch.iter().map(|r| &r.id).collect::<Vec<_>>(),
+                "embeddings": batch.iter().map(|r| &r.embedding).collect::<Vec<_>>(),
+                "metadatas": batch.iter().map(|r| &r.metadata).collect::<Vec<_>>(),
+            });
+            if batch.iter().any(|r| r.document.is_some()) {
+                payload["documents"] =
+                    serde_json::json!(batch.iter().map(|r| &r.document).collect::<Vec<_>>());
+            }
+            let (status, text) = send(op, self.client.post(&url).json(&payload)).await?;
+            if !status.is_success() {
+                return Err(format!("Chroma {} error: {} - {}", op, status, text));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores a vector produced by `model`, with the text it was computed
+    /// from so it can be re-embedded later. Replaces a vector stored under
+    /// the same id, so retries are harmless. Refused when `model` is not
+    /// this collection's model, e.g. after a re-embedding switched it.
+    pub async fn embed(
+        &self,
+        id: &str,
+        embedding: Vec<f32>,
+        document: Option<&str>,
+        model: &str,
+    ) -> Result<(), String> {
+        if model != self.model {
+            return Err(format!(
+                "vector '{}' was embedded with '{}' but the collection now uses '{}'",
+                id, model, self.model
+            ));
+        }
+        let mut payload = serde_json::json!({
             "ids": [id],
+            "metadatas": [vector_metadata(&self.model, embedding.len())],
             "embeddings": [embedding],
-            "metadatas": [{"source": "stub"}]
         });
+        if let Some(document) = document {
+            payload["documents"] = serde_json::json!([document]);
+        }
 
-        let url = format!(
-            "{}/api/v2/tenants/default_tenant/databases/default_database/collections/{}/add",
-            self.chroma_url, self.collection_id
-        );
+        let url = self.collection_url("upsert")?;
 
-        let resp = self
-            .client
-            .post(&url)
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| e.to_string())?;
-        let status = resp.status();
-        let text = resp.text().await.unwrap_or_else(|_| "unknown error".into());
+        let (status, text) = send("upsert", self.client.post(&url).json(&payload)).await?;
 
         if status.is_success() {
             Ok(())
@@ -93,26 +313,31 @@ impl LatentMemory {
         }
     }
 
+    /// Removes the vectors stored under `ids`; ids that are not stored are
+    /// ignored.
+    pub async fn delete(&self, ids: &[String]) -> Result<(), String> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let url = self.collection_url("delete")?;
+        let payload = serde_json::json!({ "ids": ids });
+        let (status, text) = send("delete", self.client.post(&url).json(&payload)).await?;
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(format!("Chroma delete error: {} - {}", status, text))
+        }
+    }
+
     pub async fn query(&self, embedding: Vec<f32>) -> Result<Vec<String>, String> {
         let payload = serde_json::json!({
             "query_embeddings": [embedding],
             "n_results": 3
         });
 
-        let url = format!(
-            "{}/api/v2/tenants/default_tenant/databases/default_database/collections/{}/query",
-            self.chroma_url, self.collection_id
-        );
+        let url = self.collection_url("query")?;
 
-        let resp = self
-            .client
-            .post(&url)
-            .json(&payload)
-            .send()
-            .await
-            .map_err(|e| e.to_string())?;
-        let status = resp.status();
-        let text = resp.text().await.unwrap_or_else(|_| "unknown error".into());
+        let (status, text) = send("query", self.client.post(&url).json(&payload)).await?;
 
         if status.is_success() {
             let parsed: QueryResponse = serde_json::from_str(&text).map_err(|e| e.to_string())?;
@@ -123,7 +348,97 @@ impl LatentMemory {
     }
 }
 
+/// Metadata stored with every vector.
+pub fn vector_metadata(model: &str, dimension: usize) -> Value {
+    serde_json::json!({ "model": model, "dimension": dimension })
+}
+
+/// Creates the collection `name`, or finds it if it exists, and returns
+/// its id.
+async fn create_collection(
+    client: &Client,
+    chroma_url: &str,
+    name: &str,
+) -> Result<String, String> {
+    let payload = serde_json::json!({
+        "name": name,
+        "embedding_function": null,
+        "dimension": 1536,
+        "get_or_create": true
+    });
+
+    let (status, text) = send(
+        "create_collection",
+        client
+            .post(format!(
+                "{}/api/v2/tenants/default_tenant/databases/default_database/collections",
+                chroma_url
+            ))
+            .json(&payload),
+    )
+    .await?;
+
+    if status.is_success() {
+        let value: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        value["id"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "missing id field in response".to_string())
+    } else {
+        Err(text)
+    }
+}
+
+/// Sends a request to Chroma and reads its body, recording the call's
+/// latency and outcome.
+async fn send(
+    operation: &'static str,
+    request: RequestBuilder,
+) -> Result<(StatusCode, String), String> {
+    let started = Instant::now();
+    let result = match request.send().await {
+        Ok(resp) => {
+            let status = resp.status();
+            let text = resp.text().await.unwrap_or_else(|_| "unknown error".into());
+            Ok((status, text))
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    let ok = matches!(&result, Ok((status, _)) if status.is_success());
+    telemetry::observe_call("chroma", operation, started, ok);
+    result
+}
+
+#[derive(Debug, Deserialize)]
+struct GetResponse {
+    ids: Vec<String>,
+    embeddings: Option<Vec<Vec<f32>>>,
+    metadatas: Option<Vec<Option<Value>>>,
+    documents: Option<Vec<Option<String>>>,
+}
+
 #[derive(Debug, Deserialize)]
 struct QueryResponse {
     ids: Vec<Vec<String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn vectors_of_another_model_are_refused() {
+        let latent = LatentMemory {
+            chroma_url: "http://127.0.0.1:9".to_string(),
+            collection_id: String::new(),
+            collection_name: "mem".to_string(),
+            model: "new-model".to_string(),
+            client: Client::new(),
+        };
+        let err = latent
+            .embed("long:a", vec![1.0], Some("a"), "old-model")
+            .await
+            .unwrap_err();
+        assert!(err.contains("now uses 'new-model'"), "{}", err);
+    }
+}
@@ -1,10 +1,58 @@
-use sqlx::{Pool, Row, Sqlite, sqlite::SqlitePoolOptions};
+use crate::memory::value;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{
+    Pool, Row, Sqlite, SqliteConnection, Transaction, sqlite::SqlitePoolOptions, sqlite::SqliteRow,
+};
 
 #[derive(Debug, Clone)]
 pub struct LongTermMemory {
     pool: Pool<Sqlite>,
+    /// `memories` for the default namespace, `memories_<name>` for others.
+    table: String,
 }
 
+/// A stored entry with its bookkeeping. `version` starts at 1 and grows by
+/// one on every write; `created_at` is RFC 3339 in UTC.
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    pub key: String,
+    pub value: Value,
+    pub content_type: String,
+    pub version: i64,
+    pub created_at: String,
+}
+
+/// Condition a write or delete must meet to go ahead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Precondition {
+    Exists,
+    Absent,
+    Version(i64),
+}
+
+/// Filters for [`LongTermMemory::list`]. Bounds are SQLite timestamps
+/// (`YYYY-MM-DD HH:MM:SS`, UTC); the lower one is inclusive.
+#[derive(Debug, Default)]
+pub struct ListFilter<'a> {
+    pub prefix: Option<&'a str>,
+    pub created_after: Option<String>,
+    pub created_before: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BulkEntry {
+    pub key: String,
+    pub value: Value,
+    /// Expected current version; `0` means the key must not exist yet.
+    pub version: Option<i64>,
+}
+
+pub const DEFAULT_TABLE: &str = "memories";
+
+const RECORD_COLUMNS: &str =
+    "key, value, content_type, version, strftime('%Y-%m-%dT%H:%M:%SZ', created_at) AS created_at";
+
 impl LongTermMemory {
     pub async fn new(database_url: &str) -> Self {
         let options = sqlx::sqlite::SqliteConnectOptions::new()
@@ -16,23 +64,89 @@ impl LongTermMemory {
             .await
             .expect("Failed to connect to SQLite");
 
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS memories (
+        let memory = Self {
+            pool,
+            table: DEFAULT_TABLE.to_string(),
+        };
+        memory.create_table().await.expect("Failed to create table");
+        memory
+    }
+
+    /// An empty memory on a private in-memory database.
+    #[cfg(test)]
+    pub async fn in_memory() -> Self {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let memory = Self {
+            pool,
+            table: DEFAULT_TABLE.to_string(),
+        };
+        memory.create_table().await.unwrap();
+        memory
+    }
+
+    /// The same database, scoped to the table of `namespace`, which must be
+    /// a validated namespace name. The table is created if needed.
+    pub async fn scoped(&self, namespace: &str) -> Result<Self, String> {
+        let memory = Self {
+            pool: self.pool.clone(),
+            table: format!("{}_{}", DEFAULT_TABLE, namespace),
+        };
+        memory.create_table().await.map_err(|e| e.to_string())?;
+        Ok(memory)
+    }
+
+    /// Removes the table with everything in it.
+    pub async fn drop_table(&self) -> Result<(), String> {
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", self.table))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn create_table(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
                 id TEXT PRIMARY KEY,
                 key TEXT NOT NULL,
                 value TEXT NOT NULL,
                 created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
             );",
-        )
-        .execute(&pool)
-        .await
-        .expect("Failed to create table");
+            self.table
+        ))
+        .execute(&self.pool)
+        .await?;
 
-        Self { pool }
+        // Added after the table first shipped.
+        self.add_column("version INTEGER NOT NULL DEFAULT 1")
+            .await?;
+        self.add_column("content_type TEXT NOT NULL DEFAULT 'text/plain'")
+            .await
+    }
+
+    /// Adds a column unless the table already has it.
+    async fn add_column(&self, definition: &str) -> Result<(), sqlx::Error> {
+        match sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {}",
+            self.table, definition
+        ))
+        .execute(&self.pool)
+        .await
+        {
+            Err(e) if !e.to_string().contains("duplicate column") => Err(e),
+            _ => Ok(()),
+        }
     }
 
+    /// Text form of the value; structured values come back as JSON.
     pub async fn get(&self, key: &str) -> Option<String> {
-        sqlx::query("SELECT value FROM memories WHERE key = ?")
+        sqlx::query(&format!("SELECT value FROM {} WHERE key = ?", self.table))
             .bind(key)
             .fetch_optional(&self.pool)
             .await
@@ -40,29 +154,254 @@ impl LongTermMemory {
             .map(|row| row.get("value"))
     }
 
-    pub async fn set(&self, key: &str, value: &str) {
-        sqlx::query(
-            "INSERT INTO memories (id, key, value) VALUES (?, ?, ?)
-             ON CONFLICT(id) DO UPDATE SET value = excluded.value",
+    /// Returns the new version, or `None` if the write failed.
+    pub async fn set(&self, key: &str, value: &str) -> Option<i64> {
+        upsert(
+            &self.pool,
+            &self.table,
+            key,
+            &Value::String(value.to_string()),
         )
+        .await
+    }
+
+    /// Like [`set`](Self::set), recording whether the value is text or JSON.
+    pub async fn set_value(&self, key: &str, value: &Value) -> Option<i64> {
+        upsert(&self.pool, &self.table, key, value).await
+    }
+
+    /// Starts a transaction on the memory's database. The `*_in` writes
+    /// take it, so they can commit together with rows of other tables on
+    /// the same database, such as outbox jobs.
+    pub async fn begin(&self) -> Result<Transaction<'static, Sqlite>, String> {
+        self.pool.begin().await.map_err(|e| e.to_string())
+    }
+
+    /// [`set_value`](Self::set_value) on `conn`.
+    pub async fn set_value_in(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &str,
+        value: &Value,
+    ) -> Option<i64> {
+        upsert(conn, &self.table, key, value).await
+    }
+
+    pub async fn record(&self, key: &str) -> Option<Record> {
+        sqlx::query(&format!(
+            "SELECT {} FROM {} WHERE key = ?",
+            RECORD_COLUMNS, self.table
+        ))
         .bind(key)
-        .bind(key)
-        .bind(value)
-        .execute(&self.pool)
+        .fetch_optional(&self.pool)
         .await
-        .ok();
+        .ok()?
+        .map(|row| to_record(&row))
+    }
+
+    /// Writes `value` if `condition` holds. Returns the new version, or the
+    /// current one (`None` when the key is missing) if it does not.
+    pub async fn set_if(
+        &self,
+        key: &str,
+        value: &Value,
+        condition: Precondition,
+    ) -> Result<i64, Option<i64>> {
+        let Ok(mut conn) = self.pool.acquire().await else {
+            return Err(None);
+        };
+        self.set_if_in(&mut conn, key, value, condition).await
+    }
+
+    /// [`set_if`](Self::set_if) on `conn`.
+    pub async fn set_if_in(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &str,
+        value: &Value,
+        condition: Precondition,
+    ) -> Result<i64, Option<i64>> {
+        write_if(conn, &self.table, key, value, condition).await
+    }
+
+    /// Deletes `key` if it is at `version`. Returns the current version
+    /// (`None` when the key is missing) if it is not.
+    pub async fn delete_if(&self, key: &str, version: i64) -> Result<(), Option<i64>> {
+        let Ok(mut conn) = self.pool.acquire().await else {
+            return Err(None);
+        };
+        self.delete_if_in(&mut conn, key, version).await
     }
 
-    pub async fn delete(&self, key: &str) {
-        sqlx::query("DELETE FROM memories WHERE key = ?")
+    /// [`delete_if`](Self::delete_if) on `conn`.
+    pub async fn delete_if_in(
+        &self,
+        conn: &mut SqliteConnection,
+        key: &str,
+        version: i64,
+    ) -> Result<(), Option<i64>> {
+        let deleted = sqlx::query(&format!(
+            "DELETE FROM {} WHERE key = ? AND version = ?",
+            self.table
+        ))
+        .bind(key)
+        .bind(version)
+        .execute(&mut *conn)
+        .await
+        .map(|r| r.rows_affected() > 0)
+        .unwrap_or(false);
+        if deleted {
+            return Ok(());
+        }
+        sqlx::query_scalar(&format!("SELECT version FROM {} WHERE key = ?", self.table))
             .bind(key)
-            .execute(&self.pool)
+            .fetch_optional(conn)
+            .await
+            .ok()
+            .flatten()
+            .map_or(Err(None), |version| Err(Some(version)))
+    }
+
+    /// Deletes `keys` on `conn`. Returns how many of them existed.
+    pub async fn delete_many_in(
+        &self,
+        conn: &mut SqliteConnection,
+        keys: &[String],
+    ) -> Result<u64, String> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut query =
+            sqlx::QueryBuilder::<Sqlite>::new(format!("DELETE FROM {} WHERE key IN (", self.table));
+        let mut list = query.separated(", ");
+        for key in keys {
+            list.push_bind(key);
+        }
+        query.push(")");
+        query
+            .build()
+            .execute(conn)
+            .await
+            .map(|r| r.rows_affected())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Writes all entries in one transaction. If any entry's expected
+    /// version does not match, nothing is written and the conflicting keys
+    /// are returned.
+    pub async fn upsert_many(&self, entries: &[BulkEntry]) -> Result<Vec<i64>, Vec<String>> {
+        let Ok(mut tx) = self.pool.begin().await else {
+            return Err(Vec::new());
+        };
+        match self.upsert_many_in(&mut tx, entries).await {
+            Ok(versions) => {
+                tx.commit().await.map_err(|_| Vec::new())?;
+                Ok(versions)
+            }
+            Err(conflicts) => {
+                tx.rollback().await.ok();
+                Err(conflicts)
+            }
+        }
+    }
+
+    /// [`upsert_many`](Self::upsert_many) inside the caller's transaction,
+    /// which the caller must roll back on error.
+    pub async fn upsert_many_in(
+        &self,
+        tx: &mut SqliteConnection,
+        entries: &[BulkEntry],
+    ) -> Result<Vec<i64>, Vec<String>> {
+        let mut versions = Vec::with_capacity(entries.len());
+        let mut conflicts = Vec::new();
+        for entry in entries {
+            let condition = match entry.version {
+                None => None,
+                Some(0) => Some(Precondition::Absent),
+                Some(v) => Some(Precondition::Version(v)),
+            };
+            let result = match condition {
+                Some(condition) => {
+                    write_if(tx, &self.table, &entry.key, &entry.value, condition).await
+                }
+                None => upsert(&mut *tx, &self.table, &entry.key, &entry.value)
+                    .await
+                    .ok_or(None),
+            };
+            match result {
+                Ok(version) => versions.push(version),
+                Err(_) => conflicts.push(entry.key.clone()),
+            }
+        }
+        if conflicts.is_empty() {
+            Ok(versions)
+        } else {
+            Err(conflicts)
+        }
+    }
+
+    /// Writes an entry read from an export, keeping its creation time
+    /// (RFC 3339 or SQLite format) when one is given. Returns the new
+    /// version.
+    pub async fn import(&self, key: &str, value: &Value, created_at: Option<&str>) -> Option<i64> {
+        sqlx::query_scalar(&format!(
+            "INSERT INTO {} (id, key, value, content_type, created_at)
+             VALUES (?1, ?1, ?2, ?3, COALESCE(datetime(?4), CURRENT_TIMESTAMP))
+             ON CONFLICT(id) DO UPDATE SET value = excluded.value,
+                 content_type = excluded.content_type, version = version + 1,
+                 created_at = COALESCE(datetime(?4), created_at)
+             RETURNING version",
+            self.table
+        ))
+        .bind(key)
+        .bind(value::to_text(value))
+        .bind(value::content_type(value))
+        .bind(created_at)
+        .fetch_one(&self.pool)
+        .await
+        .ok()
+    }
+
+    /// One page of entries ordered by key, plus the number of entries
+    /// matching `filter` in total.
+    pub async fn list(
+        &self,
+        filter: &ListFilter<'_>,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<Record>, usize) {
+        let mut count =
+            sqlx::QueryBuilder::<Sqlite>::new(format!("SELECT COUNT(*) FROM {}", self.table));
+        push_filter(&mut count, filter);
+        let total: i64 = count
+            .build_query_scalar()
+            .fetch_one(&self.pool)
+            .await
+            .unwrap_or(0);
+
+        let mut page = sqlx::QueryBuilder::<Sqlite>::new(format!(
+            "SELECT {} FROM {}",
+            RECORD_COLUMNS, self.table
+        ));
+        push_filter(&mut page, filter);
+        page.push(" ORDER BY key LIMIT ")
+            .push_bind(limit as i64)
+            .push(" OFFSET ")
+            .push_bind(offset as i64);
+        let rows = page.build().fetch_all(&self.pool).await.unwrap_or_default();
+        (rows.iter().map(to_record).collect(), total as usize)
+    }
+
+    /// Number of stored entries.
+    pub async fn count(&self) -> usize {
+        sqlx::query_scalar::<_, i64>(&format!("SELECT COUNT(*) FROM {}", self.table))
+            .fetch_one(&self.pool)
             .await
-            .ok();
+            .unwrap_or(0) as usize
     }
 
     pub async fn all(&self) -> Vec<(String, String)> {
-        let rows = sqlx::query("SELECT key, value FROM memories")
+        let rows = sqlx::query(&format!("SELECT key, value FROM {}", self.table))
             .fetch_all(&self.pool)
             .await
             .unwrap_or_default();
@@ -74,4 +413,278 @@ impl LongTermMemory {
             })
             .collect()
     }
+
+    /// Entries whose key or value contains `text`, newest first.
+    pub async fn search(&self, text: &str, limit: usize) -> Vec<(String, String)> {
+        let pattern = format!("%{}%", escape_like(text));
+        let rows = sqlx::query(&format!(
+            "SELECT key, value FROM {}
+             WHERE key LIKE ?1 ESCAPE '\\' OR value LIKE ?1 ESCAPE '\\'
+             ORDER BY created_at DESC LIMIT ?2",
+            self.table
+        ))
+        .bind(pattern)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .unwrap_or_default();
+        rows.into_iter()
+            .map(|row| (row.get("key"), row.get("value")))
+            .collect()
+    }
+}
+
+fn to_record(row: &SqliteRow) -> Record {
+    let content_type: String = row.get("content_type");
+    Record {
+        key: row.get("key"),
+        value: value::from_text(row.get("value"), &content_type),
+        content_type,
+        version: row.get("version"),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn push_filter<'a>(query: &mut sqlx::QueryBuilder<'a, Sqlite>, filter: &'a ListFilter<'_>) {
+    query
+        .push(" WHERE key LIKE ")
+        .push_bind(format!("{}%", escape_like(filter.prefix.unwrap_or(""))))
+        .push(r" ESCAPE '\'");
+    if let Some(after) = &filter.created_after {
+        query.push(" AND created_at >= ").push_bind(after.as_str());
+    }
+    if let Some(before) = &filter.created_before {
+        query.push(" AND created_at < ").push_bind(before.as_str());
+    }
+}
+
+async fn upsert<'e>(
+    executor: impl sqlx::Executor<'e, Database = Sqlite>,
+    table: &str,
+    key: &str,
+    value: &Value,
+) -> Option<i64> {
+    sqlx::query_scalar(&format!(
+        "INSERT INTO {} (id, key, value, content_type) VALUES (?1, ?1, ?2, ?3)
+         ON CONFLICT(id) DO UPDATE SET value = excluded.value,
+             content_type = excluded.content_type, version = version + 1
+         RETURNING version",
+        table
+    ))
+    .bind(key)
+    .bind(value::to_text(value))
+    .bind(value::content_type(value))
+    .fetch_one(executor)
+    .await
+    .ok()
+}
+
+/// Checks and writes in a single statement, so concurrent writers cannot
+/// both pass the same check.
+async fn write_if(
+    conn: &mut sqlx::SqliteConnection,
+    table: &str,
+    key: &str,
+    value: &Value,
+    condition: Precondition,
+) -> Result<i64, Option<i64>> {
+    let text = value::to_text(value);
+    let content_type = value::content_type(value);
+    let written: Option<i64> = match condition {
+        Precondition::Absent => {
+            sqlx::query_scalar(&format!(
+                "INSERT INTO {} (id, key, value, content_type) VALUES (?1, ?1, ?2, ?3)
+                 ON CONFLICT(id) DO NOTHING RETURNING version",
+                table
+            ))
+            .bind(key)
+            .bind(&text)
+            .bind(content_type)
+            .fetch_optional(&mut *conn)
+            .await
+        }
+        Precondition::Exists => {
+            sqlx::query_scalar(&format!(
+                "UPDATE {} SET value = ?2, content_type = ?3, version = version + 1
+                 WHERE key = ?1 RETURNING version",
+                table
+            ))
+            .bind(key)
+            .bind(&text)
+            .bind(content_type)
+            .fetch_optional(&mut *conn)
+            .await
+        }
+        Precondition::Version(expected) => {
+            sqlx::query_scalar(&format!(
+                "UPDATE {} SET value = ?2, content_type = ?3, version = version + 1
+                 WHERE key = ?1 AND version = ?4 RETURNING version",
+                table
+            ))
+            .bind(key)
+            .bind(&text)
+            .bind(content_type)
+            .bind(expected)
+            .fetch_optional(&mut *conn)
+            .await
+        }
+    }
+    .ok()
+    .flatten();
+    match written {
+        Some(version) => Ok(version),
+        None => Err(
+            sqlx::query_scalar(&format!("SELECT version FROM {} WHERE key = ?", table))
+                .bind(key)
+                .fetch_optional(&mut *conn)
+                .await
+                .ok()
+                .flatten(),
+        ),
+    }
+}
+
+/// Escapes `LIKE` wildcards for use with `ESCAPE '\'`.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', r"\\")
+        .replace('%', r"\%")
+        .replace('_', r"\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn conditional_writes_check_the_version() {
+        let mem = LongTermMemory::in_memory().await;
+        let v = json!("blue");
+        assert_eq!(
+            mem.set_if("color", &v, Precondition::Exists).await,
+            Err(None)
+        );
+        assert_eq!(mem.set_if("color", &v, Precondition::Absent).await, Ok(1));
+        assert_eq!(
+            mem.set_if("color", &v, Precondition::Absent).await,
+            Err(Some(1))
+        );
+        assert_eq!(
+            mem.set_if("color", &json!("red"), Precondition::Version(1))
+                .await,
+            Ok(2)
+        );
+        assert_eq!(
+            mem.set_if("color", &v, Precondition::Version(1)).await,
+            Err(Some(2))
+        );
+        assert_eq!(mem.get("color").await.as_deref(), Some("red"));
+
+        assert_eq!(mem.delete_if("color", 1).await, Err(Some(2)));
+        assert_eq!(mem.delete_if("color", 2).await, Ok(()));
+        assert_eq!(mem.delete_if("color", 2).await, Err(None));
+    }
+
+    #[tokio::test]
+    async fn bulk_writes_are_all_or_nothing() {
+        let mem = LongTermMemory::in_memory().await;
+        mem.set("taken", "x").await;
+        let entry = |key: &str, version| BulkEntry {
+            key: key.to_string(),
+            value: json!({ "key": key }),
+            version,
+        };
+
+        let conflicts = mem
+            .upsert_many(&[entry("fresh", None), entry("taken", Some(0))])
+            .await;
+        assert_eq!(conflicts, Err(vec!["taken".to_string()]));
+        assert_eq!(mem.get("fresh").await, None);
+        assert_eq!(mem.record("taken").await.unwrap().version, 1);
+
+        let versions = mem
+            .upsert_many(&[entry("fresh", Some(0)), entry("taken", Some(1))])
+            .await;
+        assert_eq!(versions, Ok(vec![1, 2]));
+        let record = mem.record("fresh").await.unwrap();
+        assert_eq!(record.content_type, "application/json");
+        assert_eq!(record.value, json!({ "key": "fresh" }));
+    }
+
+    #[tokio::test]
+    async fn deletes_roll_back_with_their_transaction() {
+        let mem = LongTermMemory::in_memory().await;
+        mem.set("a", "1").await;
+        mem.set("b", "2").await;
+        let keys = ["a".to_string(), "b".to_string(), "missing".to_string()];
+
+        let mut tx = mem.begin().await.unwrap();
+        assert_eq!(mem.delete_many_in(&mut tx, &keys).await, Ok(2));
+        drop(tx);
+        assert_eq!(mem.count().await, 2);
+
+        let mut tx = mem.begin().await.unwrap();
+        assert_eq!(mem.delete_if_in(&mut tx, "a", 2).await, Err(Some(1)));
+        assert_eq!(mem.delete_if_in(&mut tx, "a", 1).await, Ok(()));
+        assert_eq!(mem.delete_many_in(&mut tx, &keys).await, Ok(1));
+        tx.commit().await.unwrap();
+        assert_eq!(mem.count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn list_prefixes_match_wildcards_literally() {
+        let mem = LongTermMemory::in_memory().await;
+        for key in ["a_b", "axb", "a%c", "abc", "b"] {
+            mem.set(key, key).await;
+        }
+        let keys = |records: Vec<Record>| records.into_iter().map(|r| r.key).collect::<Vec<_>>();
+        let list = async |prefix, offset, limit| {
+            let filter = ListFilter {
+                prefix: Some(prefix),
+                ..Default::default()
+            };
+            mem.list(&filter, offset, limit).await
+        };
+
+        let (records, total) = list("a_", 0, 10).await;
+        assert_eq!((keys(records), total), (vec!["a_b".to_string()], 1));
+        let (records, total) = list("a%", 0, 10).await;
+        assert_eq!((keys(records), total), (vec!["a%c".to_string()], 1));
+
+        let (records, total) = list("a", 1, 2).await;
+        assert_eq!(total, 4);
+        assert_eq!(keys(records), vec!["a_b", "abc"]);
+    }
+
+    #[tokio::test]
+    async fn older_tables_gain_the_new_columns() {
+        let mem = LongTermMemory::in_memory().await;
+        mem.drop_table().await.unwrap();
+        sqlx::query(
+            "CREATE TABLE memories (id TEXT PRIMARY KEY, key TEXT NOT NULL,
+                 value TEXT NOT NULL, created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP)",
+        )
+        .execute(&mem.pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO memories (id, key, value) VALUES ('k', 'k', 'v')")
+            .execute(&mem.pool)
+            .await
+            .unwrap();
+
+        mem.create_table().await.unwrap();
+        mem.create_table().await.unwrap();
+        let record = mem.record("k").await.unwrap();
+        assert_eq!(
+            (record.version, record.content_type.as_str()),
+            (1, "text/plain")
+        );
+
+        // Anything other than an existing column is an error.
+        sqlx::query("PRAGMA query_only = ON")
+            .execute(&mem.pool)
+            .await
+            .unwrap();
+        assert!(mem.add_column("extra TEXT").await.is_err());
+    }
 }
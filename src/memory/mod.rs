@@ -1,4 +1,6 @@
+pub mod episodic;
 pub mod latent;
 pub mod long_term;
 pub mod semantic;
 pub mod short_term;
+pub mod value;
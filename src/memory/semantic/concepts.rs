@@ -0,0 +1,344 @@
+use crate::memory::semantic::hnsw::normalize;
+use crate::memory::semantic::latent_graph::LatentGraph;
+use crate::memory::semantic::object::{AffectScore, ObjectCluster};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const CONCEPT_TAG: &str = "concept";
+pub const IS_A: &str = "is_a";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConceptOptions {
+    /// Number of concepts to look for. Defaults to `sqrt(n / 2)`.
+    pub k: Option<usize>,
+    /// Groups smaller than this are not proposed.
+    pub min_size: usize,
+    /// Fraction of members a tag must appear on to count as shared.
+    pub min_tag_share: f32,
+    pub max_iterations: usize,
+    /// Seed for centroid initialization, so a dry run and the following
+    /// apply produce the same groups on an unchanged graph.
+    pub seed: u64,
+}
+
+impl Default for ConceptOptions {
+    fn default() -> Self {
+        Self {
+            k: None,
+            min_size: 2,
+            min_tag_share: 0.5,
+            max_iterations: 50,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConceptProposal {
+    pub name: String,
+    pub members: Vec<String>,
+    pub centroid: Vec<f32>,
+    pub shared_tags: Vec<String>,
+    pub affect: AffectScore,
+    /// Mean cosine similarity of the members to the centroid.
+    pub cohesion: f32,
+}
+
+/// The clusters [`propose`] groups: every cluster that is not a concept,
+/// copied so the graph need not stay locked while they are clustered.
+pub fn candidates(graph: &LatentGraph) -> HashMap<String, ObjectCluster> {
+    graph
+        .clusters
+        .iter()
+        .filter(|(_, c)| !c.tags.iter().any(|t| t == CONCEPT_TAG))
+        .map(|(id, c)| (id.clone(), c.clone()))
+        .collect()
+}
+
+/// Groups `clusters` with spherical k-means (k-means++ seeding, cosine
+/// similarity on normalized embeddings).
+pub fn propose(
+    clusters: &HashMap<String, ObjectCluster>,
+    options: &ConceptOptions,
+) -> Vec<ConceptProposal> {
+    let mut points = clusters
+        .iter()
+        .filter_map(|(id, c)| normalize(&c.embedding).map(|v| (id.clone(), v)))
+        .collect::<Vec<_>>();
+    points.sort_by(|a, b| a.0.cmp(&b.0));
+
+    if let Some(dim) = dominant_dimension(&points) {
+        points.retain(|(_, v)| v.len() == dim);
+    }
+    let n = points.len();
+    if n < options.min_size.max(2) {
+        return vec![];
+    }
+    let k = options
+        .k
+        .unwrap_or_else(|| ((n as f32 / 2.0).sqrt().round() as usize).max(1))
+        .clamp(1, n);
+
+    let vectors = points.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+    let (centroids, assignment) = kmeans(&vectors, k, options);
+
+    let mut proposals = Vec::new();
+    for (c, centroid) in centroids.into_iter().enumerate() {
+        let members = (0..n).filter(|&i| assignment[i] == c).collect::<Vec<_>>();
+        if members.len() < options.min_size {
+            continue;
+        }
+        let ids = members
+            .iter()
+            .map(|&i| points[i].0.clone())
+            .collect::<Vec<_>>();
+        let group = ids
+            .iter()
+            .filter_map(|id| clusters.get(id))
+            .collect::<Vec<_>>();
+
+        let shared_tags = shared_tags(&group, options.min_tag_share);
+        let affect = AffectScore::from_value(
+            group.iter().map(|c| c.affect.value).sum::<f32>() / group.len() as f32,
+        );
+        let cohesion = members
+            .iter()
+            .map(|&i| dot(&vectors[i], &centroid))
+            .sum::<f32>()
+            / members.len() as f32;
+
+        let index = proposals.len();
+        let name = shared_tags
+            .first()
+            .cloned()
+            .unwrap_or_else(|| format!("concept_{}", index));
+        proposals.push(ConceptProposal {
+            name,
+            members: ids,
+            centroid,
+            shared_tags,
+            affect,
+            cohesion,
+        });
+    }
+    proposals
+}
+
+/// Adds each proposal as a parent concept cluster with id `{prefix}_{n}`
+/// and links its members with `is_a` relations. Members removed since the
+/// proposal was made are skipped. Returns the ids of the new concepts.
+pub fn materialize(
+    graph: &mut LatentGraph,
+    proposals: &[ConceptProposal],
+    prefix: &str,
+) -> Vec<String> {
+    let mut created = Vec::new();
+    for proposal in proposals {
+        let members = proposal
+            .members
+            .iter()
+            .filter(|id| graph.clusters.contains_key(*id))
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            continue;
+        }
+
+        let mut tags = vec![CONCEPT_TAG.to_string()];
+        tags.extend(proposal.shared_tags.iter().cloned());
+        let mut concept =
+            ObjectCluster::new(proposal.name.clone(), proposal.centroid.clone(), tags);
+        concept.affect = proposal.affect.clone();
+        let id = format!("{}_{}", prefix, created.len());
+        graph.add_cluster(id.clone(), concept);
+
+        for member in members {
+            if !graph.has_relation(member, IS_A, &id) {
+                graph.add_relation(member, IS_A, &id);
+            }
+        }
+        created.push(id);
+    }
+    created
+}
+
+fn kmeans(vectors: &[Vec<f32>], k: usize, options: &ConceptOptions) -> (Vec<Vec<f32>>, Vec<usize>) {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let mut centroids = seed_centroids(vectors, k, &mut rng);
+    let mut assignment = vec![usize::MAX; vectors.len()];
+
+    for _ in 0..options.max_iterations.max(1) {
+        let mut changed = false;
+        for (i, v) in vectors.iter().enumerate() {
+            let best = nearest_centroid(v, &centroids);
+            if assignment[i] != best {
+                assignment[i] = best;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let dim = vectors[0].len();
+        let mut sums = vec![vec![0.0f32; dim]; centroids.len()];
+        for (i, v) in vectors.iter().enumerate() {
+            for (s, x) in sums[assignment[i]].iter_mut().zip(v) {
+                *s += x;
+            }
+        }
+        for (c, sum) in sums.into_iter().enumerate() {
+            // An emptied centroid keeps its previous position.
+            if let Some(unit) = normalize(&sum) {
+                centroids[c] = unit;
+            }
+        }
+    }
+    (centroids, assignment)
+}
+
+/// k-means++: each further centroid is drawn with probability proportional
+/// to its cosine distance from the nearest centroid chosen so far.
+fn seed_centroids(vectors: &[Vec<f32>], k: usize, rng: &mut StdRng) -> Vec<Vec<f32>> {
+    let mut centroids = vec![vectors[rng.gen_range(0..vectors.len())].clone()];
+    while centroids.len() < k {
+        let weights = vectors
+            .iter()
+            .map(|v| {
+                let d = 1.0 - dot(v, &centroids[nearest_centroid(v, &centroids)]);
+                d.max(0.0)
+            })
+            .collect::<Vec<_>>();
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+        let mut target = rng.r#gen::<f32>() * total;
+        let mut pick = vectors.len() - 1;
+        for (i, w) in weights.iter().enumerate() {
+            if target < *w {
+                pick = i;
+                break;
+            }
+            target -= w;
+        }
+        centroids.push(vectors[pick].clone());
+    }
+    centroids
+}
+
+fn nearest_centroid(v: &[f32], centroids: &[Vec<f32>]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .max_by(|a, b| dot(v, a.1).total_cmp(&dot(v, b.1)))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn shared_tags(clusters: &[&ObjectCluster], min_share: f32) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for cluster in clusters {
+        for tag in &cluster.tags {
+            *counts.entry(tag.as_str()).or_default() += 1;
+        }
+    }
+    let threshold = (min_share * clusters.len() as f32).ceil().max(1.0) as usize;
+    let mut shared = counts
+        .into_iter()
+        .filter(|(_, count)| *count >= threshold)
+        .collect::<Vec<_>>();
+    shared.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    shared.into_iter().map(|(tag, _)| tag.to_string()).collect()
+}
+
+/// Embeddings from different models can share a graph; clustering only makes
+/// sense within one vector space, so the most common dimension wins.
+fn dominant_dimension(points: &[(String, Vec<f32>)]) -> Option<usize> {
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    for (_, v) in points {
+        *counts.entry(v.len()).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(dim, _)| dim)
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_two_groups_and_links_members() {
+        let mut graph = LatentGraph::new();
+        for (i, (v, tag)) in [
+            (vec![1.0, 0.1, 0.0], "sharp"),
+            (vec![0.9, 0.0, 0.1], "sharp"),
+            (vec![1.0, 0.0, 0.05], "sharp"),
+            (vec![0.0, 0.1, 1.0], "soft"),
+            (vec![0.1, 0.0, 0.9], "soft"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            graph.add_cluster(
+                format!("x_{}", i),
+                ObjectCluster::new(format!("x_{}", i), v, vec![tag.to_string()]),
+            );
+        }
+
+        let options = ConceptOptions {
+            k: Some(2),
+            ..Default::default()
+        };
+        let mut proposals = propose(&candidates(&graph), &options);
+        proposals.sort_by_key(|p| p.members.len());
+
+        assert_eq!(proposals.len(), 2);
+        assert_eq!(proposals[0].members, vec!["x_3", "x_4"]);
+        assert_eq!(proposals[0].shared_tags, vec!["soft"]);
+        assert_eq!(proposals[1].name, "sharp");
+
+        let created = materialize(&mut graph, &proposals, "concept_test");
+        assert_eq!(created, vec!["concept_test_0", "concept_test_1"]);
+        assert!(graph.has_relation("x_0", IS_A, "concept_test_1"));
+        assert!(
+            propose(&candidates(&graph), &options)
+                .iter()
+                .all(|p| p.members.len() <= 3)
+        );
+    }
+
+    #[test]
+    fn materialize_skips_members_removed_after_the_snapshot() {
+        let mut graph = LatentGraph::new();
+        for (i, v) in [vec![1.0, 0.0], vec![0.9, 0.1], vec![1.0, 0.1]]
+            .into_iter()
+            .enumerate()
+        {
+            graph.add_cluster(
+                format!("x_{}", i),
+                ObjectCluster::new(format!("x_{}", i), v, vec![]),
+            );
+        }
+        let options = ConceptOptions {
+            k: Some(1),
+            ..Default::default()
+        };
+        let proposals = propose(&candidates(&graph), &options);
+        assert_eq!(proposals[0].members.len(), 3);
+
+        graph.remove_cluster("x_1");
+        let created = materialize(&mut graph, &proposals, "concept_test");
+        assert!(graph.has_relation("x_0", IS_A, &created[0]));
+        assert!(!graph.has_relation("x_1", IS_A, &created[0]));
+        assert_eq!(graph.relations.len(), 2);
+    }
+}
@@ -0,0 +1,433 @@
+use crate::memory::semantic::latent_graph::LatentGraph;
+use crate::memory::semantic::object::{AffectScore, ObjectCluster};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+const JSONLD_VOCAB: &str = "https://inception.icore/semantic#";
+const JSONLD_CLUSTER: &str = "https://inception.icore/cluster/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    GraphMl,
+    JsonLd,
+    Json,
+}
+
+impl GraphFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "dot" | "graphviz" => Some(Self::Dot),
+            "graphml" => Some(Self::GraphMl),
+            "jsonld" | "json-ld" => Some(Self::JsonLd),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Dot => "text/vnd.graphviz",
+            Self::GraphMl => "application/graphml+xml",
+            Self::JsonLd => "application/ld+json",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClusterRecord {
+    pub id: String,
+    #[serde(flatten)]
+    pub cluster: ObjectCluster,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RelationRecord {
+    pub source: String,
+    pub relation: String,
+    pub target: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct GraphSnapshot {
+    #[serde(default)]
+    pub clusters: Vec<ClusterRecord>,
+    #[serde(default)]
+    pub relations: Vec<RelationRecord>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportSummary {
+    pub clusters: usize,
+    pub relations: usize,
+}
+
+impl GraphSnapshot {
+    pub fn from_graph(graph: &LatentGraph) -> Self {
+        let mut clusters = graph
+            .clusters
+            .iter()
+            .map(|(id, cluster)| ClusterRecord {
+                id: id.clone(),
+                cluster: cluster.clone(),
+            })
+            .collect::<Vec<_>>();
+        clusters.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let relations = graph
+            .relations
+            .iter()
+            .map(|(source, relation, target)| RelationRecord {
+                source: source.clone(),
+                relation: relation.clone(),
+                target: target.clone(),
+            })
+            .collect();
+
+        Self {
+            clusters,
+            relations,
+        }
+    }
+
+    /// Merges the snapshot into `graph`. Clusters with an existing id are
+    /// overwritten, relations already present are skipped.
+    pub fn apply(self, graph: &mut LatentGraph) -> ImportSummary {
+        let mut summary = ImportSummary::default();
+        for record in self.clusters {
+            let mut cluster = record.cluster;
+            if cluster.name.is_empty() {
+                cluster.name = record.id.clone();
+            }
+            cluster.affect = AffectScore::from_value(cluster.affect.value);
+            graph.add_cluster(record.id, cluster);
+            summary.clusters += 1;
+        }
+        for rel in self.relations {
+            if !graph.has_relation(&rel.source, &rel.relation, &rel.target) {
+                graph.add_relation(&rel.source, &rel.relation, &rel.target);
+                summary.relations += 1;
+            }
+        }
+        summary
+    }
+}
+
+pub fn export(graph: &LatentGraph, format: GraphFormat) -> String {
+    let snapshot = GraphSnapshot::from_graph(graph);
+    match format {
+        GraphFormat::Dot => to_dot(&snapshot),
+        GraphFormat::GraphMl => to_graphml(&snapshot),
+        GraphFormat::JsonLd => to_jsonld(&snapshot).to_string(),
+        GraphFormat::Json => serde_json::to_string(&snapshot).unwrap_or_default(),
+    }
+}
+
+/// Parses `body` in the given format. Only the JSON based formats carry
+/// embeddings, so DOT and GraphML are export-only.
+pub fn import(format: GraphFormat, body: &str) -> Result<GraphSnapshot, String> {
+    match format {
+        GraphFormat::Json => serde_json::from_str(body).map_err(|e| e.to_string()),
+        GraphFormat::JsonLd => {
+            let value: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+            from_jsonld(&value)
+        }
+        GraphFormat::Dot | GraphFormat::GraphMl => {
+            Err("import supports only json and jsonld formats".to_string())
+        }
+    }
+}
+
+fn to_dot(snapshot: &GraphSnapshot) -> String {
+    let mut out = String::from("digraph semantic {\n");
+    for record in &snapshot.clusters {
+        let c = &record.cluster;
+        out.push_str(&format!(
+            "  \"{}\" [label=\"{}\", tags=\"{}\", affect=\"{:.3}\", affect_class=\"{:?}\", known=\"{}\"];\n",
+            dot_escape(&record.id),
+            dot_escape(&c.name),
+            dot_escape(&c.tags.join(",")),
+            c.affect.value,
+            c.affect.class,
+            c.known
+        ));
+    }
+    for rel in &snapshot.relations {
+        out.push_str(&format!(
+            "  \"{}\" -> \"{}\" [label=\"{}\"];\n",
+            dot_escape(&rel.source),
+            dot_escape(&rel.target),
+            dot_escape(&rel.relation)
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn to_graphml(snapshot: &GraphSnapshot) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n\
+         \x20 <key id=\"name\" for=\"node\" attr.name=\"name\" attr.type=\"string\"/>\n\
+         \x20 <key id=\"tags\" for=\"node\" attr.name=\"tags\" attr.type=\"string\"/>\n\
+         \x20 <key id=\"affect\" for=\"node\" attr.name=\"affect\" attr.type=\"double\"/>\n\
+         \x20 <key id=\"affect_class\" for=\"node\" attr.name=\"affect_class\" attr.type=\"string\"/>\n\
+         \x20 <key id=\"known\" for=\"node\" attr.name=\"known\" attr.type=\"boolean\"/>\n\
+         \x20 <key id=\"relation\" for=\"edge\" attr.name=\"relation\" attr.type=\"string\"/>\n\
+         \x20 <graph id=\"semantic\" edgedefault=\"directed\">\n",
+    );
+    for record in &snapshot.clusters {
+        let c = &record.cluster;
+        out.push_str(&format!(
+            "    <node id=\"{}\">\n\
+             \x20     <data key=\"name\">{}</data>\n\
+             \x20     <data key=\"tags\">{}</data>\n\
+             \x20     <data key=\"affect\">{}</data>\n\
+             \x20     <data key=\"affect_class\">{:?}</data>\n\
+             \x20     <data key=\"known\">{}</data>\n\
+             \x20   </node>\n",
+            xml_escape(&record.id),
+            xml_escape(&c.name),
+            xml_escape(&c.tags.join(",")),
+            c.affect.value,
+            c.affect.class,
+            c.known
+        ));
+    }
+
+    // GraphML requires every edge endpoint to be declared as a node, and
+    // relation targets such as "pain" are not necessarily clusters.
+    let mut dangling = snapshot
+        .relations
+        .iter()
+        .flat_map(|rel| [&rel.source, &rel.target])
+        .filter(|id| !snapshot.clusters.iter().any(|c| &c.id == *id))
+        .collect::<Vec<_>>();
+    dangling.sort();
+    dangling.dedup();
+    for id in dangling {
+        out.push_str(&format!("    <node id=\"{}\"/>\n", xml_escape(id)));
+    }
+
+    for (i, rel) in snapshot.relations.iter().enumerate() {
+        out.push_str(&format!(
+            "    <edge id=\"e{}\" source=\"{}\" target=\"{}\">\n\
+             \x20     <data key=\"relation\">{}</data>\n\
+             \x20   </edge>\n",
+            i,
+            xml_escape(&rel.source),
+            xml_escape(&rel.target),
+            xml_escape(&rel.relation)
+        ));
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+}
+
+fn to_jsonld(snapshot: &GraphSnapshot) -> Value {
+    let mut nodes: Vec<Map<String, Value>> = snapshot
+        .clusters
+        .iter()
+        .map(|record| {
+            let c = &record.cluster;
+            let mut node = Map::new();
+            node.insert("@id".into(), json!(format!("cluster:{}", record.id)));
+            node.insert("@type".into(), json!("ObjectCluster"));
+            node.insert("name".into(), json!(c.name));
+            node.insert("tags".into(), json!(c.tags));
+            node.insert("affect".into(), json!(c.affect.value));
+            node.insert("affectClass".into(), json!(format!("{:?}", c.affect.class)));
+            node.insert("known".into(), json!(c.known));
+            node.insert("embedding".into(), json!(c.embedding));
+            if let Some(model) = &c.model {
+                node.insert("model".into(), json!(model));
+            }
+            node
+        })
+        .collect();
+
+    for rel in &snapshot.relations {
+        let source_id = format!("cluster:{}", rel.source);
+        let pos = match nodes.iter().position(|n| n["@id"] == source_id) {
+            Some(pos) => pos,
+            None => {
+                let mut node = Map::new();
+                node.insert("@id".into(), json!(source_id));
+                nodes.push(node);
+                nodes.len() - 1
+            }
+        };
+        let target = json!({ "@id": format!("cluster:{}", rel.target) });
+        match nodes[pos]
+            .entry(rel.relation.clone())
+            .or_insert_with(|| json!([]))
+        {
+            Value::Array(targets) => targets.push(target),
+            other => *other = json!([other.clone(), target]),
+        }
+    }
+
+    json!({
+        "@context": {
+            "@vocab": JSONLD_VOCAB,
+            "cluster": JSONLD_CLUSTER,
+            "tags": { "@container": "@set" },
+            "embedding": { "@container": "@list" },
+        },
+        "@graph": nodes,
+    })
+}
+
+fn from_jsonld(value: &Value) -> Result<GraphSnapshot, String> {
+    let nodes = value["@graph"]
+        .as_array()
+        .ok_or_else(|| "missing @graph array".to_string())?;
+
+    let mut snapshot = GraphSnapshot::default();
+    for node in nodes {
+        let obj = node
+            .as_object()
+            .ok_or_else(|| "@graph entries must be objects".to_string())?;
+        let id = obj
+            .get("@id")
+            .and_then(Value::as_str)
+            .map(strip_cluster_prefix)
+            .ok_or_else(|| "node without @id".to_string())?;
+
+        if obj.get("@type").and_then(Value::as_str) == Some("ObjectCluster") {
+            let cluster = ObjectCluster {
+                name: obj
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .unwrap_or(&id)
+                    .to_string(),
+                embedding: serde_json::from_value(
+                    obj.get("embedding").cloned().unwrap_or_default(),
+                )
+                .unwrap_or_default(),
+                tags: serde_json::from_value(obj.get("tags").cloned().unwrap_or_default())
+                    .unwrap_or_default(),
+                affect: AffectScore::from_value(
+                    obj.get("affect").and_then(Value::as_f64).unwrap_or(0.0) as f32,
+                ),
+                known: obj.get("known").and_then(Value::as_bool).unwrap_or(false),
+                model: obj.get("model").and_then(Value::as_str).map(str::to_string),
+            };
+            snapshot.clusters.push(ClusterRecord {
+                id: id.clone(),
+                cluster,
+            });
+        }
+
+        for (key, val) in obj {
+            if key.starts_with('@') || is_cluster_property(key) {
+                continue;
+            }
+            let targets = match val {
+                Value::Array(items) => items.iter().collect::<Vec<_>>(),
+                other => vec![other],
+            };
+            for target in targets {
+                if let Some(target_id) = target.get("@id").and_then(Value::as_str) {
+                    snapshot.relations.push(RelationRecord {
+                        source: id.clone(),
+                        relation: key.clone(),
+                        target: strip_cluster_prefix(target_id),
+                    });
+                }
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+fn is_cluster_property(key: &str) -> bool {
+    matches!(
+        key,
+        "name" | "tags" | "affect" | "affectClass" | "known" | "embedding" | "model"
+    )
+}
+
+fn strip_cluster_prefix(id: &str) -> String {
+    id.strip_prefix("cluster:")
+        .or_else(|| id.strip_prefix(JSONLD_CLUSTER))
+        .unwrap_or(id)
+        .to_string()
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_graph() -> LatentGraph {
+        let mut graph = LatentGraph::new();
+        let mut knife = ObjectCluster::new(
+            "knife".into(),
+            vec![0.1, 0.2, 0.3],
+            vec!["sharp".into(), "metal".into()],
+        );
+        knife.update_affect(-0.85);
+        graph.add_cluster("x_17".into(), knife);
+        graph.add_relation("x_17", "caused", "pain");
+        graph
+    }
+
+    #[test]
+    fn json_roundtrip_preserves_graph() {
+        let graph = sample_graph();
+        let body = export(&graph, GraphFormat::Json);
+
+        let mut restored = LatentGraph::new();
+        let summary = import(GraphFormat::Json, &body)
+            .unwrap()
+            .apply(&mut restored);
+
+        assert_eq!(summary.clusters, 1);
+        assert_eq!(summary.relations, 1);
+        let cluster = &restored.clusters["x_17"];
+        assert_eq!(cluster.name, "knife");
+        assert_eq!(cluster.embedding, vec![0.1, 0.2, 0.3]);
+        assert!(restored.has_relation("x_17", "caused", "pain"));
+    }
+
+    #[test]
+    fn jsonld_roundtrip_preserves_graph() {
+        let graph = sample_graph();
+        let body = export(&graph, GraphFormat::JsonLd);
+
+        let mut restored = LatentGraph::new();
+        import(GraphFormat::JsonLd, &body)
+            .unwrap()
+            .apply(&mut restored);
+
+        let cluster = &restored.clusters["x_17"];
+        assert_eq!(cluster.tags, vec!["sharp", "metal"]);
+        assert!((cluster.affect.value + 0.85).abs() < 1e-6);
+        assert!(restored.has_relation("x_17", "caused", "pain"));
+        assert_eq!(restored.clusters.len(), 1);
+    }
+
+    #[test]
+    fn dot_and_graphml_escape_identifiers() {
+        let mut graph = LatentGraph::new();
+        graph.add_cluster(
+            "a\"b".into(),
+            ObjectCluster::new("<x>".into(), vec![], vec![]),
+        );
+        assert!(export(&graph, GraphFormat::Dot).contains("\"a\\\"b\""));
+        assert!(export(&graph, GraphFormat::GraphMl).contains("&lt;x&gt;"));
+    }
+}
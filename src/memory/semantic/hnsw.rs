@@ -0,0 +1,538 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+const M: usize = 16;
+const M0: usize = 2 * M;
+const EF_CONSTRUCTION: usize = 100;
+const EF_SEARCH: usize = 128;
+/// Live nodes moved into the replacement index per insert or remove while
+/// a compaction is running, which bounds the work done under the caller's
+/// lock.
+const COMPACTION_STEP: usize = 32;
+
+/// Approximate nearest-neighbour index (HNSW) over cosine similarity.
+///
+/// Vectors are normalized on insert so similarity is a plain dot product.
+/// Removal tombstones a node. Once more than half of the nodes are dead the
+/// index starts building a compacted replacement, a few nodes per insert
+/// or remove, and swaps it in when every live node has been moved.
+#[derive(Clone)]
+pub struct HnswIndex {
+    nodes: Vec<Node>,
+    ids: HashMap<String, usize>,
+    entry: Option<usize>,
+    max_level: usize,
+    deleted: usize,
+    rng: StdRng,
+    compaction: Option<Box<Compaction>>,
+}
+
+/// A replacement index under construction. Changes made meanwhile go to
+/// both indexes; `pending` are the live nodes still to be moved.
+#[derive(Clone)]
+struct Compaction {
+    index: HnswIndex,
+    pending: Vec<usize>,
+}
+
+#[derive(Clone)]
+struct Node {
+    id: String,
+    vector: Vec<f32>,
+    neighbors: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate {
+    dist: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist
+            .total_cmp(&other.dist)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Default for HnswIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HnswIndex {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            max_level: 0,
+            deleted: 0,
+            rng: StdRng::seed_from_u64(0x1c0e),
+            compaction: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Inserts or replaces the vector stored under `id`. Vectors that cannot
+    /// be normalized (zero norm, NaN or infinite components) are not indexed.
+    pub fn insert(&mut self, id: &str, embedding: &[f32]) {
+        self.remove(id);
+        let Some(vector) = normalize(embedding) else {
+            return;
+        };
+        if let Some(compaction) = &mut self.compaction {
+            compaction.index.insert(id, &vector);
+        }
+        self.add(id, vector);
+        self.compact_step();
+    }
+
+    /// Links a normalized vector into the graph under an id that is not
+    /// indexed.
+    fn add(&mut self, id: &str, vector: Vec<f32>) {
+        let level = self.random_level();
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            id: id.to_string(),
+            vector,
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id.to_string(), node);
+
+        let Some(mut entry) = self.entry else {
+            self.entry = Some(node);
+            self.max_level = level;
+            return;
+        };
+
+        let query = self.nodes[node].vector.clone();
+        for layer in (level + 1..=self.max_level).rev() {
+            entry = self.greedy_closest(&query, entry, layer);
+        }
+
+        let mut entries = vec![entry];
+        for layer in (0..=level.min(self.max_level)).rev() {
+            let found = self.search_layer(&query, &entries, EF_CONSTRUCTION, layer);
+            let max = if layer == 0 { M0 } else { M };
+            let selected = self.select_neighbors(&found, M);
+            self.nodes[node].neighbors[layer] = selected.clone();
+
+            for &peer in &selected {
+                self.nodes[peer].neighbors[layer].push(node);
+                if self.nodes[peer].neighbors[layer].len() > max {
+                    self.prune(peer, layer, max);
+                }
+            }
+            entries = found.iter().map(|c| c.node).collect();
+        }
+
+        if level > self.max_level {
+            self.max_level = level;
+            self.entry = Some(node);
+        }
+    }
+
+    pub fn remove(&mut self, id: &str) -> bool {
+        let Some(node) = self.ids.remove(id) else {
+            return false;
+        };
+        self.nodes[node].deleted = true;
+        self.deleted += 1;
+        if let Some(compaction) = &mut self.compaction {
+            compaction.index.remove(id);
+        } else if self.deleted > 64 && self.deleted * 2 > self.nodes.len() {
+            let mut index = Self::new();
+            index.rng = self.rng.clone();
+            self.compaction = Some(Box::new(Compaction {
+                index,
+                pending: (0..self.nodes.len())
+                    .filter(|&n| !self.nodes[n].deleted)
+                    .collect(),
+            }));
+        }
+        self.compact_step();
+        true
+    }
+
+    /// Returns up to `k` `(id, cosine similarity)` pairs, most similar first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(String, f32)> {
+        let (Some(mut entry), Some(query)) = (self.entry, normalize(query)) else {
+            return vec![];
+        };
+        if k == 0 {
+            return vec![];
+        }
+
+        for layer in (1..=self.max_level).rev() {
+            entry = self.greedy_closest(&query, entry, layer);
+        }
+        let ef = EF_SEARCH.max(k) + self.deleted.min(EF_SEARCH);
+        self.search_layer(&query, &[entry], ef, 0)
+            .into_iter()
+            .filter(|c| !self.nodes[c.node].deleted)
+            .take(k)
+            .map(|c| (self.nodes[c.node].id.clone(), 1.0 - c.dist))
+            .collect()
+    }
+
+    /// Moves up to [`COMPACTION_STEP`] live nodes into the replacement
+    /// index, and swaps it in once none are left. Nodes deleted or replaced
+    /// since the compaction started are skipped; their changes already
+    /// reached the replacement.
+    fn compact_step(&mut self) {
+        let Some(mut compaction) = self.compaction.take() else {
+            return;
+        };
+        let mut moved = 0;
+        while moved < COMPACTION_STEP
+            && let Some(node) = compaction.pending.pop()
+        {
+            let node = &self.nodes[node];
+            if !node.deleted {
+                compaction.index.add(&node.id, node.vector.clone());
+                moved += 1;
+            }
+        }
+        if compaction.pending.is_empty() {
+            *self = compaction.index;
+        } else {
+            self.compaction = Some(compaction);
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        let ml = 1.0 / (M as f64).ln();
+        let r: f64 = self.rng.r#gen::<f64>().max(f64::MIN_POSITIVE);
+        ((-r.ln()) * ml).floor() as usize
+    }
+
+    fn distance(&self, query: &[f32], node: usize) -> f32 {
+        1.0 - dot(query, &self.nodes[node].vector)
+    }
+
+    fn greedy_closest(&self, query: &[f32], mut current: usize, layer: usize) -> usize {
+        let mut best = self.distance(query, current);
+        loop {
+            let mut changed = false;
+            for &peer in &self.nodes[current].neighbors[layer] {
+                let dist = self.distance(query, peer);
+                if dist.total_cmp(&best) == Ordering::Less {
+                    best = dist;
+                    current = peer;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return current;
+            }
+        }
+    }
+
+    /// Best-first search on one layer; returns candidates sorted by distance.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entries: &[usize],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        // A set rather than a flag per node keeps a query proportional to
+        // the nodes it visits instead of the size of the index.
+        let mut visited = entries.iter().copied().collect::<HashSet<_>>();
+        let mut frontier = BinaryHeap::new();
+        let mut found = BinaryHeap::new();
+        for &node in entries {
+            let c = Candidate {
+                dist: self.distance(query, node),
+                node,
+            };
+            frontier.push(Reverse(c));
+            found.push(c);
+        }
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(current)) = frontier.pop() {
+            if let Some(worst) = found.peek()
+                && current.dist > worst.dist
+                && found.len() >= ef
+            {
+                break;
+            }
+            let Some(neighbors) = self.nodes[current.node].neighbors.get(layer) else {
+                continue;
+            };
+            for &peer in neighbors {
+                if !visited.insert(peer) {
+                    continue;
+                }
+                let c = Candidate {
+                    dist: self.distance(query, peer),
+                    node: peer,
+                };
+                let admit = found.len() < ef || found.peek().is_some_and(|w| c.dist < w.dist);
+                if admit {
+                    frontier.push(Reverse(c));
+                    found.push(c);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    /// Neighbour selection heuristic from the HNSW paper: prefer candidates
+    /// closer to the new node than to any already selected neighbour, then
+    /// top up with the closest pruned ones to keep the graph connected.
+    fn select_neighbors(&self, candidates: &[Candidate], m: usize) -> Vec<usize> {
+        let mut selected: Vec<usize> = Vec::with_capacity(m);
+        let mut pruned = Vec::new();
+        for c in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let vector = &self.nodes[c.node].vector;
+            let diverse = selected
+                .iter()
+                .all(|&s| self.distance(vector, s).total_cmp(&c.dist) == Ordering::Greater);
+            if diverse {
+                selected.push(c.node);
+            } else {
+                pruned.push(c.node);
+            }
+        }
+        for node in pruned {
+            if selected.len() >= m {
+                break;
+            }
+            selected.push(node);
+        }
+        selected
+    }
+
+    /// Keeps the `max` closest neighbours, which is much cheaper than
+    /// re-running the selection heuristic on every overflow.
+    fn prune(&mut self, node: usize, layer: usize, max: usize) {
+        let vector = self.nodes[node].vector.clone();
+        let mut candidates = self.nodes[node].neighbors[layer]
+            .iter()
+            .map(|&peer| Candidate {
+                dist: self.distance(&vector, peer),
+                node: peer,
+            })
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.truncate(max);
+        self.nodes[node].neighbors[layer] = candidates.iter().map(|c| c.node).collect();
+    }
+}
+
+/// Returns the unit-length copy of `v`, or `None` when it has no direction.
+pub fn normalize(v: &[f32]) -> Option<Vec<f32>> {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if !norm.is_finite() || norm == 0.0 {
+        return None;
+    }
+    Some(v.iter().map(|x| x / norm).collect())
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn random_vectors(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n)
+            .map(|_| (0..dim).map(|_| rng.r#gen::<f32>() - 0.5).collect())
+            .collect()
+    }
+
+    /// Exact top-k over pre-normalized vectors.
+    fn brute_force(normed: &[Vec<f32>], query: &[f32], k: usize) -> Vec<String> {
+        let q = normalize(query).unwrap();
+        let mut scored = normed
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (i.to_string(), dot(&q, v)))
+            .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().take(k).map(|(id, _)| id).collect()
+    }
+
+    fn build(vectors: &[Vec<f32>]) -> HnswIndex {
+        let mut index = HnswIndex::new();
+        for (i, v) in vectors.iter().enumerate() {
+            index.insert(&i.to_string(), v);
+        }
+        index
+    }
+
+    #[test]
+    fn recall_matches_brute_force() {
+        let vectors = random_vectors(2000, 32, 7);
+        let index = build(&vectors);
+        let normed = vectors
+            .iter()
+            .map(|v| normalize(v).unwrap())
+            .collect::<Vec<_>>();
+        let queries = random_vectors(50, 32, 11);
+
+        let mut hits = 0;
+        for q in &queries {
+            let expected = brute_force(&normed, q, 10);
+            let got = index.search(q, 10);
+            hits += got.iter().filter(|(id, _)| expected.contains(id)).count();
+        }
+        let recall = hits as f32 / (queries.len() * 10) as f32;
+        assert!(recall > 0.9, "recall@10 too low: {}", recall);
+    }
+
+    #[test]
+    fn removed_and_degenerate_vectors_are_never_returned() {
+        let vectors = random_vectors(300, 8, 3);
+        let mut index = build(&vectors);
+        for i in 0..200 {
+            index.remove(&i.to_string());
+        }
+        index.insert("zero", &[0.0; 8]);
+        index.insert("nan", &[f32::NAN; 8]);
+
+        assert_eq!(index.len(), 100);
+        for (id, sim) in index.search(&vectors[0], 20) {
+            let n: usize = id.parse().unwrap();
+            assert!(n >= 200);
+            assert!(sim.is_finite());
+        }
+        assert!(index.search(&[0.0; 8], 5).is_empty());
+    }
+
+    #[test]
+    fn compaction_moves_a_bounded_step_per_change() {
+        let vectors = random_vectors(1000, 16, 5);
+        let mut index = build(&vectors);
+        for i in 0..501 {
+            index.remove(&i.to_string());
+        }
+        assert!(index.compaction.is_some());
+        assert_eq!(index.nodes.len(), 1000);
+
+        // Changes made while the replacement is built reach it too.
+        index.insert("999", &vectors[0]);
+        index.remove("998");
+        index.insert("new", &vectors[1]);
+        let mut changes = 0;
+        while index.compaction.is_some() && changes < 100 {
+            index.insert("churn", &vectors[changes]);
+            changes += 1;
+        }
+        assert!(changes <= 499 / COMPACTION_STEP, "{} changes", changes);
+
+        assert_eq!(index.len(), 500);
+        // The 500 tombstones are gone; only replaced churn nodes remain.
+        assert_eq!(index.nodes.len() - index.len(), index.deleted);
+        assert!(index.deleted < changes + 2, "{} tombstones", index.deleted);
+        assert!(
+            index
+                .search(&vectors[998], 5)
+                .iter()
+                .all(|(id, _)| id != "998")
+        );
+        assert_eq!(index.search(&vectors[0], 1)[0].0, "999");
+        assert_eq!(index.search(&vectors[1], 1)[0].0, "new");
+    }
+
+    /// Embeddings are clustered rather than uniform, so the benchmark draws
+    /// points around 1000 random topic centres.
+    fn clustered_vectors(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let centres = random_vectors(1000, dim, 0);
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n)
+            .map(|_| {
+                let centre = &centres[rng.gen_range(0..centres.len())];
+                centre
+                    .iter()
+                    .map(|x| x + 0.15 * (rng.r#gen::<f32>() - 0.5))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// `cargo test --release -- --ignored bench_100k`
+    #[test]
+    #[ignore]
+    fn bench_100k() {
+        let n = 100_000;
+        let vectors = clustered_vectors(n, 128, 1);
+        let queries = clustered_vectors(200, 128, 2);
+        let index = build(&vectors);
+        let normed = vectors
+            .iter()
+            .map(|v| normalize(v).unwrap())
+            .collect::<Vec<_>>();
+
+        let start = Instant::now();
+        for q in &queries {
+            index.search(q, 10);
+        }
+        let ann = start.elapsed() / queries.len() as u32;
+
+        let start = Instant::now();
+        for q in queries.iter().take(20) {
+            brute_force(&normed, q, 10);
+        }
+        let linear = start.elapsed() / 20;
+        assert!(
+            ann < linear,
+            "ann {:?}/query, linear {:?}/query",
+            ann,
+            linear
+        );
+
+        let hits: usize = queries
+            .iter()
+            .take(20)
+            .map(|q| {
+                let expected = brute_force(&normed, q, 10);
+                index
+                    .search(q, 10)
+                    .iter()
+                    .filter(|(id, _)| expected.contains(id))
+                    .count()
+            })
+            .sum();
+        // Points of one topic sit close together, so the exact top 10 has
+        // many near ties and recall is a little lower than on uniform data.
+        let recall = hits as f32 / 200.0;
+        assert!(recall > 0.85, "recall@10 too low: {}", recall);
+    }
+}
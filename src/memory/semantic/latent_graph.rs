@@ -1,14 +1,19 @@
+use crate::memory::semantic::hnsw::HnswIndex;
 use crate::memory::semantic::object::ObjectCluster;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-pub static SEMANTIC_GRAPH: Lazy<Mutex<LatentGraph>> = Lazy::new(|| Mutex::new(LatentGraph::new()));
+/// Graph of the default namespace; other namespaces get their own through
+/// `icore::namespace`.
+pub static SEMANTIC_GRAPH: Lazy<Arc<Mutex<LatentGraph>>> =
+    Lazy::new(|| Arc::new(Mutex::new(LatentGraph::new())));
 
 #[derive(Clone)]
 pub struct LatentGraph {
     pub clusters: HashMap<String, ObjectCluster>,
     pub relations: Vec<(String, String, String)>, // (source, relation, target)
+    index: HnswIndex,
 }
 
 impl LatentGraph {
@@ -16,26 +21,76 @@ impl LatentGraph {
         Self {
             clusters: HashMap::new(),
             relations: vec![],
+            index: HnswIndex::new(),
         }
     }
 
     pub fn add_cluster(&mut self, id: String, cluster: ObjectCluster) {
+        self.index.insert(&id, &cluster.embedding);
         self.clusters.insert(id, cluster);
     }
 
+    /// Removes a cluster together with every relation that touches it.
+    pub fn remove_cluster(&mut self, id: &str) -> Option<ObjectCluster> {
+        let removed = self.clusters.remove(id)?;
+        self.index.remove(id);
+        self.relations.retain(|(s, _, t)| s != id && t != id);
+        Some(removed)
+    }
+
+    /// Merges `source` into `target`, re-pointing relations of the removed
+    /// cluster at the surviving one.
+    pub fn merge_clusters(&mut self, target: &str, source: &str) -> Result<(), String> {
+        if target == source {
+            return Err("cannot merge a cluster into itself".to_string());
+        }
+        let other = self
+            .clusters
+            .get(source)
+            .cloned()
+            .ok_or_else(|| format!("cluster not found: {}", source))?;
+        let cluster = self
+            .clusters
+            .get_mut(target)
+            .ok_or_else(|| format!("cluster not found: {}", target))?;
+        cluster.absorb(&other)?;
+        let embedding = cluster.embedding.clone();
+        self.index.insert(target, &embedding);
+        self.clusters.remove(source);
+        self.index.remove(source);
+
+        let mut relations = Vec::with_capacity(self.relations.len());
+        for (s, r, t) in self.relations.drain(..) {
+            let s = if s == source { target.to_string() } else { s };
+            let t = if t == source { target.to_string() } else { t };
+            if s == t || relations.contains(&(s.clone(), r.clone(), t.clone())) {
+                continue;
+            }
+            relations.push((s, r, t));
+        }
+        self.relations = relations;
+        Ok(())
+    }
+
     pub fn add_relation(&mut self, source: &str, relation: &str, target: &str) {
         self.relations
             .push((source.to_string(), relation.to_string(), target.to_string()));
     }
 
-    pub fn find_nearest(&self, embedding: &[f32]) -> Option<(String, f32)> {
-        self.clusters
+    pub fn has_relation(&self, source: &str, relation: &str, target: &str) -> bool {
+        self.relations
             .iter()
-            .filter_map(|(id, cluster)| {
-                let sim = cosine_similarity(&cluster.embedding, embedding);
-                Some((id.clone(), sim))
-            })
-            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .any(|(s, r, t)| s == source && r == relation && t == target)
+    }
+
+    pub fn find_nearest(&self, embedding: &[f32]) -> Option<(String, f32)> {
+        self.nearest(embedding, 1).into_iter().next()
+    }
+
+    /// Returns up to `k` `(id, cosine similarity)` pairs from the ANN index,
+    /// most similar first.
+    pub fn nearest(&self, embedding: &[f32], k: usize) -> Vec<(String, f32)> {
+        self.index.search(embedding, k)
     }
 }
 
@@ -45,13 +100,75 @@ impl Default for LatentGraph {
     }
 }
 
-fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
-    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
-    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
-    if norm_a == 0.0 || norm_b == 0.0 {
-        0.0
-    } else {
-        dot / (norm_a * norm_b)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> LatentGraph {
+        let mut graph = LatentGraph::new();
+        for (id, embedding, tag) in [
+            ("knife", vec![1.0, 0.0], "sharp"),
+            ("blade", vec![0.8, 0.2], "metal"),
+            ("kitchen", vec![0.0, 1.0], "room"),
+        ] {
+            graph.add_cluster(
+                id.to_string(),
+                ObjectCluster::new(id.to_string(), embedding, vec![tag.to_string()]),
+            );
+        }
+        graph.add_relation("knife", "in", "kitchen");
+        graph.add_relation("blade", "in", "kitchen");
+        graph.add_relation("knife", "like", "blade");
+        graph.add_relation("kitchen", "holds", "blade");
+        graph
+    }
+
+    #[test]
+    fn merge_rewires_and_deduplicates_relations() {
+        let mut graph = graph();
+        graph.merge_clusters("knife", "blade").unwrap();
+
+        assert!(!graph.clusters.contains_key("blade"));
+        assert_eq!(graph.clusters["knife"].tags, vec!["sharp", "metal"]);
+        assert_eq!(graph.clusters["knife"].embedding, vec![0.9, 0.1]);
+        // `blade in kitchen` duplicates `knife in kitchen` and
+        // `knife like blade` would point at itself; both are dropped.
+        assert_eq!(
+            graph.relations,
+            vec![
+                ("knife".to_string(), "in".to_string(), "kitchen".to_string()),
+                (
+                    "kitchen".to_string(),
+                    "holds".to_string(),
+                    "knife".to_string()
+                ),
+            ]
+        );
+        assert_eq!(graph.find_nearest(&[0.8, 0.2]).unwrap().0, "knife");
+    }
+
+    #[test]
+    fn merge_rejects_missing_or_identical_clusters() {
+        let mut graph = graph();
+        assert!(graph.merge_clusters("knife", "knife").is_err());
+        assert!(graph.merge_clusters("knife", "spoon").is_err());
+        assert!(graph.merge_clusters("spoon", "knife").is_err());
+        assert_eq!(graph.clusters.len(), 3);
+        assert_eq!(graph.relations.len(), 4);
+    }
+
+    #[test]
+    fn remove_drops_the_cluster_and_its_relations() {
+        let mut graph = graph();
+        assert_eq!(graph.remove_cluster("kitchen").unwrap().name, "kitchen");
+        assert!(graph.remove_cluster("kitchen").is_none());
+        assert!(graph.has_relation("knife", "like", "blade"));
+        assert_eq!(graph.relations.len(), 1);
+        assert!(
+            graph
+                .nearest(&[0.0, 1.0], 3)
+                .iter()
+                .all(|(id, _)| id != "kitchen")
+        );
     }
 }
@@ -1,4 +1,7 @@
+pub mod concepts;
 pub mod evaluator;
+pub mod export;
+pub mod hnsw;
 pub mod latent_graph;
 pub mod object;
 pub mod reflect;
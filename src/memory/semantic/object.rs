@@ -1,18 +1,32 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub enum Affect {
     Pleasant,
     Unpleasant,
+    #[default]
     Unknown,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AffectScore {
     pub value: f32,
+    #[serde(default)]
     pub class: Affect,
 }
 
+impl Affect {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "pleasant" => Some(Affect::Pleasant),
+            "unpleasant" => Some(Affect::Unpleasant),
+            "unknown" => Some(Affect::Unknown),
+            _ => None,
+        }
+    }
+}
+
 impl AffectScore {
     pub fn from_value(val: f32) -> Self {
         let class = if val > 0.3 {
@@ -26,13 +40,21 @@ impl AffectScore {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ObjectCluster {
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub embedding: Vec<f32>,
+    #[serde(default)]
     pub tags: Vec<String>,
+    #[serde(default)]
     pub affect: AffectScore,
+    #[serde(default)]
     pub known: bool,
+    /// Embedding model `embedding` comes from, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
 }
 
 impl ObjectCluster {
@@ -43,6 +65,7 @@ impl ObjectCluster {
             tags,
             affect: AffectScore::from_value(0.0),
             known: false,
+            model: None,
         }
     }
 
@@ -52,10 +75,39 @@ impl ObjectCluster {
         }
     }
 
+    pub fn remove_tag(&mut self, tag: &str) {
+        self.tags.retain(|t| t != tag);
+    }
+
     pub fn update_affect(&mut self, delta: f32) {
         self.affect.value += delta;
         self.affect = AffectScore::from_value(self.affect.value);
     }
+
+    /// Folds `other` into this cluster: tags are unioned, the embedding and
+    /// affect are averaged and the result is known if either side was.
+    pub fn absorb(&mut self, other: &ObjectCluster) -> Result<(), String> {
+        if self.embedding.is_empty() {
+            self.embedding = other.embedding.clone();
+        } else if !other.embedding.is_empty() {
+            if self.embedding.len() != other.embedding.len() {
+                return Err(format!(
+                    "embedding dimensions differ: {} vs {}",
+                    self.embedding.len(),
+                    other.embedding.len()
+                ));
+            }
+            for (a, b) in self.embedding.iter_mut().zip(other.embedding.iter()) {
+                *a = (*a + b) / 2.0;
+            }
+        }
+        for tag in &other.tags {
+            self.add_tag(tag);
+        }
+        self.affect = AffectScore::from_value((self.affect.value + other.affect.value) / 2.0);
+        self.known |= other.known;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -85,7 +137,7 @@ impl LatentGraph {
         self.clusters.values().min_by(|a, b| {
             let da = cosine_distance(&a.embedding, embedding);
             let db = cosine_distance(&b.embedding, embedding);
-            da.partial_cmp(&db).unwrap()
+            da.total_cmp(&db)
         })
     }
 }
@@ -94,5 +146,42 @@ fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
     let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
     let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
     let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
-    1.0 - (dot / (norm_a * norm_b + 1e-6))
+    let sim = dot / (norm_a * norm_b + 1e-6);
+    if sim.is_finite() { 1.0 - sim } else { 1.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn absorb_averages_and_unions() {
+        let mut knife = ObjectCluster::new("knife".into(), vec![1.0, 0.0], vec!["sharp".into()]);
+        knife.update_affect(-0.8);
+        let mut blade = ObjectCluster::new(
+            "blade".into(),
+            vec![0.0, 1.0],
+            vec!["metal".into(), "sharp".into()],
+        );
+        blade.known = true;
+
+        knife.absorb(&blade).unwrap();
+        assert_eq!(knife.embedding, vec![0.5, 0.5]);
+        assert_eq!(knife.tags, vec!["sharp", "metal"]);
+        assert_eq!(knife.affect.value, -0.4);
+        assert_eq!(knife.affect.class, Affect::Unpleasant);
+        assert!(knife.known);
+    }
+
+    #[test]
+    fn absorb_adopts_a_missing_embedding_and_rejects_other_dimensions() {
+        let mut empty = ObjectCluster::new("empty".into(), vec![], vec![]);
+        let other = ObjectCluster::new("other".into(), vec![0.3, 0.4], vec![]);
+        empty.absorb(&other).unwrap();
+        assert_eq!(empty.embedding, vec![0.3, 0.4]);
+
+        let wide = ObjectCluster::new("wide".into(), vec![1.0, 0.0, 0.0], vec!["x".into()]);
+        assert!(empty.absorb(&wide).is_err());
+        assert!(empty.tags.is_empty());
+    }
 }
@@ -1,9 +1,11 @@
+use crate::memory::value;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
 #[derive(Debug, Clone)]
 pub struct ShortTermMemory {
-    inner: Arc<RwLock<HashMap<String, String>>>,
+    inner: Arc<RwLock<HashMap<String, Value>>>,
 }
 
 impl ShortTermMemory {
@@ -13,16 +15,34 @@ impl ShortTermMemory {
         }
     }
 
+    /// Text form of the value; structured values come back as JSON.
     pub fn get(&self, key: &str) -> Option<String> {
+        self.get_value(key).map(|v| value::to_text(&v))
+    }
+
+    pub fn get_value(&self, key: &str) -> Option<Value> {
         self.inner.read().ok()?.get(key).cloned()
     }
 
     pub fn set(&self, key: String, value: String) {
+        self.set_value(key, Value::String(value));
+    }
+
+    pub fn set_value(&self, key: String, value: Value) {
         if let Ok(mut map) = self.inner.write() {
             map.insert(key, value);
         }
     }
 
+    /// Applies `f` to the value of an existing key under the write lock and
+    /// returns the result, or `None` if the key is missing.
+    pub fn update(&self, key: &str, f: impl FnOnce(&mut Value)) -> Option<Value> {
+        let mut map = self.inner.write().ok()?;
+        let value = map.get_mut(key)?;
+        f(value);
+        Some(value.clone())
+    }
+
     pub fn delete(&self, key: &str) {
         if let Ok(mut map) = self.inner.write() {
             map.remove(key);
@@ -36,6 +56,14 @@ impl ShortTermMemory {
     }
 
     pub fn all(&self) -> Option<HashMap<String, String>> {
+        self.inner.read().ok().map(|map| {
+            map.iter()
+                .map(|(k, v)| (k.clone(), value::to_text(v)))
+                .collect()
+        })
+    }
+
+    pub fn all_values(&self) -> Option<HashMap<String, Value>> {
         self.inner.read().ok().map(|map| map.clone())
     }
 }
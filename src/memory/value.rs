@@ -0,0 +1,174 @@
+use serde_json::Value;
+
+/// Content types recorded next to stored values.
+pub const TEXT: &str = "text/plain";
+pub const JSON: &str = "application/json";
+
+/// Strings are stored as plain text, anything else as JSON.
+pub fn content_type(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => TEXT,
+        _ => JSON,
+    }
+}
+
+/// Text form of a value: strings as-is, everything else serialized.
+pub fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Reverses [`to_text`]. Text that no longer parses as JSON is kept as a
+/// string rather than dropped.
+pub fn from_text(text: String, content_type: &str) -> Value {
+    if content_type == JSON {
+        serde_json::from_str(&text).unwrap_or(Value::String(text))
+    } else {
+        Value::String(text)
+    }
+}
+
+/// Reads text written by agent code: JSON objects and arrays become
+/// structured values, anything else stays a string.
+pub fn parse_structured(text: &str) -> Value {
+    let trimmed = text.trim_start();
+    if trimmed.starts_with(['{', '['])
+        && let Ok(value) = serde_json::from_str(text)
+    {
+        return value;
+    }
+    Value::String(text.to_string())
+}
+
+/// Applies a JSON Merge Patch (RFC 7396) to `target`.
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    let Value::Object(fields) = target else {
+        unreachable!("target was just made an object");
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            fields.remove(key);
+        } else {
+            merge_patch(fields.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Looks up a path such as `$.user.name`, `$.items[0]` or `$['a key']`.
+/// Only member and index steps are supported; `Ok(None)` means the path
+/// is valid but nothing is there.
+pub fn select<'a>(value: &'a Value, path: &str) -> Result<Option<&'a Value>, String> {
+    let mut rest = path
+        .trim()
+        .strip_prefix('$')
+        .ok_or_else(|| format!("path must start with '$': {}", path))?;
+    let mut current = value;
+    while !rest.is_empty() {
+        let (step, tail) = if let Some(tail) = rest.strip_prefix('.') {
+            let end = tail.find(['.', '[']).unwrap_or(tail.len());
+            if end == 0 {
+                return Err(format!("empty member name in {}", path));
+            }
+            (Step::Member(&tail[..end]), &tail[end..])
+        } else if let Some(tail) = rest.strip_prefix('[') {
+            let end = tail
+                .find(']')
+                .ok_or_else(|| format!("unclosed '[' in {}", path))?;
+            let inner = tail[..end].trim();
+            let step = match inner
+                .strip_prefix('\'')
+                .and_then(|s| s.strip_suffix('\''))
+                .or_else(|| inner.strip_prefix('"').and_then(|s| s.strip_suffix('"')))
+            {
+                Some(name) => Step::Member(name),
+                None => Step::Index(
+                    inner
+                        .parse()
+                        .map_err(|_| format!("invalid index '{}' in {}", inner, path))?,
+                ),
+            };
+            (step, &tail[end + 1..])
+        } else {
+            return Err(format!("unexpected '{}' in {}", rest, path));
+        };
+        let next = match step {
+            Step::Member(name) => current.get(name),
+            Step::Index(index) => current.get(index),
+        };
+        match next {
+            Some(next) => current = next,
+            None => return Ok(None),
+        }
+        rest = tail;
+    }
+    Ok(Some(current))
+}
+
+enum Step<'a> {
+    Member(&'a str),
+    Index(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut doc =
+            json!({ "title": "Hi", "author": { "name": "A", "email": "a@x" }, "tags": ["x"] });
+        merge_patch(
+            &mut doc,
+            &json!({ "title": "Hello", "author": { "email": null }, "tags": ["y"], "n": 1 }),
+        );
+        assert_eq!(
+            doc,
+            json!({ "title": "Hello", "author": { "name": "A" }, "tags": ["y"], "n": 1 })
+        );
+
+        let mut text = json!("plain");
+        merge_patch(&mut text, &json!({ "a": { "b": null, "c": 2 } }));
+        assert_eq!(text, json!({ "a": { "c": 2 } }));
+    }
+
+    #[test]
+    fn selects_members_and_indices() {
+        let doc = json!({ "user": { "name": "Ada", "tags": ["a", "b"], "full name": "Ada L" } });
+        assert_eq!(select(&doc, "$.user.name"), Ok(Some(&json!("Ada"))));
+        assert_eq!(select(&doc, "$.user.tags[1]"), Ok(Some(&json!("b"))));
+        assert_eq!(
+            select(&doc, "$['user']['full name']"),
+            Ok(Some(&json!("Ada L")))
+        );
+        assert_eq!(select(&doc, "$"), Ok(Some(&doc)));
+        assert_eq!(select(&doc, "$.user.age"), Ok(None));
+        assert!(select(&doc, "user.name").is_err());
+        assert!(select(&doc, "$.user.tags[x]").is_err());
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        for value in [
+            json!("plain"),
+            json!({ "a": [1, 2] }),
+            json!(3.5),
+            json!(null),
+        ] {
+            let text = to_text(&value);
+            assert_eq!(from_text(text, content_type(&value)), value);
+        }
+        assert_eq!(parse_structured(r#"{"a": 1}"#), json!({ "a": 1 }));
+        assert_eq!(parse_structured("42"), json!("42"));
+        assert_eq!(parse_structured("{not json"), json!("{not json"));
+    }
+}